@@ -0,0 +1,450 @@
+// The events logged by the contract. Every event carries an "event" name and
+// the schema version, which is bumped whenever the shape of an event changes.
+
+use serde::{Deserialize, Serialize};
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    MatchCreated(MatchCreated),
+    MatchJoined(MatchJoined),
+    MatchResolved(Box<MatchResolved>),
+    MatchCancelled(MatchCancelled),
+    BalanceQueried(BalanceQueried),
+    NashEquilibriumApproached(NashEquilibriumApproached),
+    GlobalStatsQueried(GlobalStatsQueried),
+    PlayerStatsReset(PlayerStatsReset),
+    ConfigChanged(ConfigChanged),
+    ContractShutdown(ContractShutdown),
+    PotTooSmall(PotTooSmall),
+    CommitmentExpired(CommitmentExpired),
+    MatchForfeited(MatchForfeited),
+    MatchExpired(MatchExpired),
+    PotDecayed(PotDecayed),
+    BatchPlayed(BatchPlayed),
+    VoteCommitted(VoteCommitted),
+    MatchToppedUp(MatchToppedUp),
+    SideBetPlaced(SideBetPlaced),
+    MultisigRequested(MultisigRequested),
+    MultisigApproved(MultisigApproved),
+    RandomMatchingChanged(RandomMatchingChanged),
+    MatchTransferred(MatchTransferred),
+    AliasSet(AliasSet),
+    AutoCashoutChanged(AutoCashoutChanged),
+    DelegateSet(DelegateSet),
+    DelegateRevoked(DelegateRevoked),
+    PauseChanged(PauseChanged),
+    OwnershipProposed(OwnershipProposed),
+    OwnershipAccepted(OwnershipAccepted),
+    StateImported(StateImported),
+    TiersChanged(TiersChanged),
+    CharityChanged(CharityChanged),
+    SurplusDistributed(SurplusDistributed),
+}
+
+// An event as it is logged, numbered so that gaps and reordering can be detected.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchCreated {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub stake: u64,
+    pub creator: String,
+    // Only set when the player paid to be matched first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority_fee: Option<u64>,
+}
+
+// Logged when the second player joins, before the match is paid out.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchJoined {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub player_2: String,
+    // Set when the match is only resolved once player 1 revealed a blinded vote.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub awaiting_reveal: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchResolved {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub player_1: PlayerResult,
+    pub player_2: PlayerResult,
+    pub outcome: String,
+    // Set by the result queries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    // Archived matches only keep the players, their payouts and the outcome.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archived: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_round: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_round: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_round_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pot_change: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pot_after: Option<u64>,
+    // Only known when the match is settled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub times_played_together: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrow_released: Option<(u64, u64)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jackpot: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub charity: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defector_bonus: Option<DefectorBonus>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub side_bets: Vec<SettledSideBet>,
+}
+
+// One side of a resolved match. Archived matches have no transaction id,
+// vote or strategy, and the bonuses are only reported when they were paid.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PlayerResult {
+    pub sender: String,
+    pub payout: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vote: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strategy_id: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    // What a conditional vote was before it resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_vote: Option<u8>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paid_out: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streak_bonus: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insurance_premium: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insurance_refund: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defection_penalty: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loyalty_bonus: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coop_jackpot: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub milestone: Option<MilestoneReached>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referral: Option<ReferralPaid>,
+}
+
+// The pot reward of a defector who betrayed a cooperator, before and after the cap.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DefectorBonus {
+    pub uncapped: u64,
+    pub capped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MilestoneReached {
+    pub matches_played: u64,
+    pub bonus: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReferralPaid {
+    pub referrer: String,
+    pub reward: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SettledSideBet {
+    pub bettor: String,
+    pub predicted_outcome: u8,
+    pub stake: u64,
+    pub payout: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchCancelled {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub cancelled: bool,
+    pub refund: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceQueried {
+    pub schema_version: u32,
+    pub balance: u64,
+}
+
+// Logged when the recent cooperation rate got close to the Nash equilibrium
+// of the game, in which everyone defects.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NashEquilibriumApproached {
+    pub schema_version: u32,
+    pub coop_rate: u32,
+    pub rounds_observed: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GlobalStatsQueried {
+    pub schema_version: u32,
+    pub matches_resolved: u64,
+    pub cc_count: u64,
+    pub cd_count: u64,
+    pub dc_count: u64,
+    pub dd_count: u64,
+    pub coop_rate_pct: u64,
+    pub pot_inflow: u64,
+    pub pot_outflow: u64,
+    pub avg_wait_rounds: f64,
+    pub total_staked: u64,
+    pub total_cashed_out: u64,
+    pub pot: u64,
+    pub waiting: u64,
+    pub threshold: u32,
+}
+
+// Logged for every player whose stats and badges the owner wiped.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlayerStatsReset {
+    pub schema_version: u32,
+    pub address: String,
+}
+
+// Logged for every parameter the owner changed through `set_params`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigChanged {
+    pub schema_version: u32,
+    pub name: String,
+    pub old: u64,
+    pub new: u64,
+}
+
+// Logged when the owner shuts the contract down, with what was unwound.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContractShutdown {
+    pub schema_version: u32,
+    pub matches_unwound: u64,
+    pub refunded: u64,
+    pub pot_swept: u64,
+    pub pot_burned: u64,
+}
+
+// Logged when a match paid no pot rewards because the pot was below the minimum.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PotTooSmall {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub pot: u64,
+    pub min_pot_for_rewards: u64,
+}
+
+// Logged for every commitment whose stake was sent back because it was not
+// revealed in time.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitmentExpired {
+    pub schema_version: u32,
+    pub sender: String,
+    pub refund: u64,
+}
+
+// Logged when player 1 did not reveal a blinded vote in time, and player 2
+// got both stakes.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchForfeited {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub payout: u64,
+}
+
+// Logged for every waiting match the sweep refunded.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchExpired {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub refund: u64,
+}
+
+// Logged when a part of the pot was shared among the recent players.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PotDecayed {
+    pub schema_version: u32,
+    pub round: u64,
+    pub recipients: Vec<String>,
+    pub amount: u64,
+    pub pot: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchPlayed {
+    pub schema_version: u32,
+    pub count: u8,
+    pub stake: u64,
+    // The transaction ids of the matches that were entered, in order.
+    pub played: Vec<String>,
+    pub refund: u64,
+    // Only set when a match of the batch could not be entered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed: Option<BatchFailure>,
+}
+
+// The first match of a batch that could not be entered, and why.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchFailure {
+    pub index: usize,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoteCommitted {
+    pub schema_version: u32,
+    pub commitment: String,
+    pub stake: u64,
+    pub expires_after_round: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchToppedUp {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub stake: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SideBetPlaced {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub predicted_outcome: u8,
+    pub stake: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MultisigRequested {
+    pub schema_version: u32,
+    pub nonce: u64,
+    pub approver: String,
+    pub amount: u64,
+    pub expires_after_round: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MultisigApproved {
+    pub schema_version: u32,
+    pub nonce: u64,
+    pub requester: String,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RandomMatchingChanged {
+    pub schema_version: u32,
+    pub random_matching: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatchTransferred {
+    pub schema_version: u32,
+    pub match_id: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AliasSet {
+    pub schema_version: u32,
+    pub sender: String,
+    pub alias: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AutoCashoutChanged {
+    pub schema_version: u32,
+    pub sender: String,
+    pub auto_cashout: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DelegateSet {
+    pub schema_version: u32,
+    pub sender: String,
+    pub delegate: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DelegateRevoked {
+    pub schema_version: u32,
+    pub sender: String,
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PauseChanged {
+    pub schema_version: u32,
+    pub paused: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OwnershipProposed {
+    pub schema_version: u32,
+    pub owner: String,
+    // None when the proposal was withdrawn.
+    pub pending_owner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OwnershipAccepted {
+    pub schema_version: u32,
+    pub previous_owner: String,
+    pub owner: String,
+}
+
+// Logged when the owner imported a state, with what it holds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateImported {
+    pub schema_version: u32,
+    pub balances: u64,
+    pub pot: u64,
+    pub waiting: u64,
+    pub history: u64,
+}
+
+// Logged when the owner replaced a schedule of (threshold, bonus in basis
+// points) pairs, such as the streak bonuses or the loyalty tiers.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TiersChanged {
+    pub schema_version: u32,
+    pub name: String,
+    pub tiers: Vec<(u64, u64)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CharityChanged {
+    pub schema_version: u32,
+    // None when the charity was removed.
+    pub charity: Option<String>,
+    pub charity_share_bps: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SurplusDistributed {
+    pub schema_version: u32,
+    pub recipients: Vec<String>,
+    pub amount: u64,
+    pub pot: u64,
+}
@@ -0,0 +1,77 @@
+// The entry point of the fuzzer, which plays the calls encoded in its input
+// against a seeded contract. See fuzz/README.md.
+
+use crate::game::ContractError;
+use crate::host::{parameters, LOGS, TRANSFERS};
+use crate::PrisonerDilemma;
+
+// Inputs are cut into at most this many calls, so that the deposits of a
+// whole input add up without overflowing.
+const MAX_CALLS: usize = 256;
+
+// The bytes of a call before its payload: the sender, the transaction id,
+// the amount, the round and the length of the payload.
+const CALL_HEADER_SIZE: usize = 1 + 8 + 8 + 8 + 1;
+
+// A contract with its invariant checks on, a pot, and a match waiting in
+// every stake tier.
+fn seeded() -> PrisonerDilemma {
+    let mut state = PrisonerDilemma::init(&mut parameters([9; 32], [0; 32], 0, 0, &[1]));
+    state.pot = 1_000_000;
+    state.total_deposits = 1_000_000;
+    state.stake_tiers = vec![(0, 1000), (1001, 2000), (2001, 3000)];
+
+    for sender in 1..=3u8 {
+        let stake = sender as u64 * 1000;
+        state.play(&mut parameters([sender; 32], [sender; 32], stake, 1, &[1, 0])).unwrap();
+    }
+
+    state
+}
+
+// Takes `n` bytes, padded with zeros once the input runs out.
+fn take(data: &mut &[u8], n: usize) -> Vec<u8> {
+    let (taken, rest) = data.split_at(n.min(data.len()));
+    *data = rest;
+
+    let mut bytes = taken.to_vec();
+    bytes.resize(n, 0);
+    bytes
+}
+
+fn take_u64(data: &mut &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&take(data, 8));
+
+    u64::from_le_bytes(bytes)
+}
+
+// Plays every call in the input. Whatever the parameters are, `play` has to
+// return, either fine or with a `ContractError`, and keep its accounting.
+pub fn play(mut data: &[u8]) {
+    let mut state = seeded();
+
+    for _ in 0..MAX_CALLS {
+        if data.len() < CALL_HEADER_SIZE {
+            break;
+        }
+
+        // The senders are few, so that they meet their own matches
+        let sender = [take(&mut data, 1)[0] % 8; 32];
+        let mut tx_id = [0; 32];
+        tx_id[..8].copy_from_slice(&take(&mut data, 8));
+        let amount = take_u64(&mut data) >> 8;
+        let round_idx = take_u64(&mut data);
+        let len = take(&mut data, 1)[0] as usize;
+        let payload = take(&mut data, len);
+
+        if let Err(err) = state.play(&mut parameters(sender, tx_id, amount, round_idx, &payload)) {
+            assert!(err.downcast_ref::<ContractError>().is_some(), "untyped error: {}", err);
+        }
+
+        assert!(state.verify_conservation().is_ok());
+    }
+
+    LOGS.with(|logs| logs.borrow_mut().clear());
+    TRANSFERS.with(|transfers| transfers.borrow_mut().clear());
+}
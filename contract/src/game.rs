@@ -0,0 +1,5845 @@
+// The game logic of the contract. Nothing in here depends on the smart contract
+// runtime, so it can also be run natively.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use rand::{Rng, SeedableRng};
+
+use crate::events::{
+    BatchFailure, BatchPlayed, CommitmentExpired, ConfigChanged, ContractShutdown, DefectorBonus, Envelope, Event, MatchCancelled, MatchCreated, MatchExpired,
+    MatchForfeited, MatchJoined, MatchResolved, MatchToppedUp, MilestoneReached, MultisigApproved, NashEquilibriumApproached, PlayerResult, PotDecayed, PotTooSmall,
+    RandomMatchingChanged, ReferralPaid, SettledSideBet, SideBetPlaced, VoteCommitted, SCHEMA_VERSION,
+};
+
+// Insured players pay a premium of their stake and get part of their stake
+// back when they cooperate against a defector.
+pub const INSURANCE_PREMIUM_PCT: u64 = 10;
+pub const INSURANCE_COVER_PCT: u64 = 50;
+
+pub const INITIAL_REPUTATION: u64 = 100;
+
+// Players who defected in at least REPEAT_DEFECTIONS of their last
+// RECENT_VOTES_WINDOW matches are repeat defectors.
+pub const RECENT_VOTES_WINDOW: usize = 5;
+pub const REPEAT_DEFECTIONS: usize = 3;
+
+// Players whose last COOP_JACKPOT_STREAK matches were all mutual
+// cooperations win the cooperation jackpot.
+pub const COOP_JACKPOT_STREAK: usize = 3;
+pub const OUTCOME_MUTUAL_COOPERATION: u8 = 1;
+pub const OUTCOME_OTHER: u8 = 0;
+
+// The match counts at which players get a milestone bonus.
+pub const MATCH_MILESTONES: [u64; 4] = [10, 50, 100, 500];
+
+pub const BADGE_FIRST_MATCH: u64 = 1 << 0;
+pub const BADGE_FIRST_COOPERATION: u64 = 1 << 1;
+pub const BADGE_FIRST_DEFECTION: u64 = 1 << 2;
+pub const BADGE_10_COOPERATIONS: u64 = 1 << 3;
+pub const BADGE_10_WINS: u64 = 1 << 4;
+pub const BADGE_JACKPOT_WIN: u64 = 1 << 5;
+
+pub const BADGE_NAMES: [(u64, &str); 6] = [
+    (BADGE_FIRST_MATCH, "first_match"),
+    (BADGE_FIRST_COOPERATION, "first_cooperation"),
+    (BADGE_FIRST_DEFECTION, "first_defection"),
+    (BADGE_10_COOPERATIONS, "10_cooperations"),
+    (BADGE_10_WINS, "10_wins"),
+    (BADGE_JACKPOT_WIN, "jackpot_win"),
+];
+
+pub const DEFAULT_HISTORY_CAPACITY: usize = 100;
+pub const MAX_ARCHIVE_CAPACITY: usize = 10_000;
+pub const MAX_PLAY_MANY_COUNT: u8 = 5;
+
+// The number of most recent votes the cooperation rate is computed over.
+pub const COOP_WINDOW_SIZE: usize = 50;
+
+// The cooperation rate of the Nash equilibrium, and how close to it the rate in
+// the cooperation window has to get to be reported. It is checked every
+// `NASH_CHECK_INTERVAL` resolved matches.
+pub const NASH_EQUILIBRIUM_COOP_RATE: u8 = 0;
+pub const NASH_TOLERANCE_PCT: u32 = 5;
+pub const NASH_CHECK_INTERVAL: u64 = 10;
+
+#[derive(Debug)]
+pub enum ContractError {
+    InvalidVote,
+    StakeTooLow(u64),
+    InvalidReferrer,
+    SelfReferral,
+    MatchNotFound,
+    InsufficientBalance,
+    WaitingPoolFull,
+    CommitmentMismatch,
+    DuplicateTransaction,
+    MissingParameter,
+    MalformedParameter,
+    InvalidAddress,
+    Unauthorized,
+    Paused,
+    ZeroStake,
+    MatchStillWaiting,
+    MatchAlreadyPlayed,
+    NotYourMatch,
+    OwnMatch,
+    CooldownActive,
+    CommitmentNotFound,
+    InvalidBatchSize,
+    InvalidOutcome,
+    NotDelegate,
+    MultisigNotFound,
+    NotApprover,
+    NotPendingOwner,
+    ShutDown,
+    NoSurplus,
+    NoRecentPlayers,
+    Overflow,
+    PayoutExceedsPot,
+    // A configuration or query parameter out of its range, with what is wrong with it.
+    InvalidParameter(String),
+}
+
+impl ContractError {
+    // A stable code for clients to match on, the message may change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ContractError::InvalidVote => "INVALID_VOTE",
+            ContractError::StakeTooLow(_) => "STAKE_TOO_LOW",
+            ContractError::InvalidReferrer => "INVALID_REFERRER",
+            ContractError::SelfReferral => "SELF_REFERRAL",
+            ContractError::MatchNotFound => "MATCH_NOT_FOUND",
+            ContractError::InsufficientBalance => "INSUFFICIENT_BALANCE",
+            ContractError::WaitingPoolFull => "POOL_FULL",
+            ContractError::CommitmentMismatch => "COMMITMENT_MISMATCH",
+            ContractError::DuplicateTransaction => "DUPLICATE_TRANSACTION",
+            ContractError::MissingParameter => "MISSING_PARAMETER",
+            ContractError::MalformedParameter => "MALFORMED_PARAMETER",
+            ContractError::InvalidAddress => "INVALID_ADDRESS",
+            ContractError::Unauthorized => "UNAUTHORIZED",
+            ContractError::Paused => "PAUSED",
+            ContractError::ZeroStake => "ZERO_STAKE",
+            ContractError::MatchStillWaiting => "MATCH_STILL_WAITING",
+            ContractError::MatchAlreadyPlayed => "MATCH_ALREADY_PLAYED",
+            ContractError::NotYourMatch => "NOT_YOUR_MATCH",
+            ContractError::OwnMatch => "OWN_MATCH",
+            ContractError::CooldownActive => "COOLDOWN_ACTIVE",
+            ContractError::CommitmentNotFound => "COMMITMENT_NOT_FOUND",
+            ContractError::InvalidBatchSize => "INVALID_BATCH_SIZE",
+            ContractError::InvalidOutcome => "INVALID_OUTCOME",
+            ContractError::NotDelegate => "NOT_DELEGATE",
+            ContractError::MultisigNotFound => "MULTISIG_NOT_FOUND",
+            ContractError::NotApprover => "NOT_APPROVER",
+            ContractError::NotPendingOwner => "NOT_PENDING_OWNER",
+            ContractError::ShutDown => "SHUT_DOWN",
+            ContractError::NoSurplus => "NO_SURPLUS",
+            ContractError::NoRecentPlayers => "NO_RECENT_PLAYERS",
+            ContractError::Overflow => "OVERFLOW",
+            ContractError::PayoutExceedsPot => "PAYOUT_EXCEEDS_POT",
+            ContractError::InvalidParameter(_) => "INVALID_PARAMETER",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ContractError::InvalidVote => "Vote must be either 1 (cooperate), 2 (defect), 3 (abstain), 4 (conditional) or 5 (blinded).".to_string(),
+            ContractError::StakeTooLow(min_stake) => format!("The stake must be at least {} PERLs.", min_stake),
+            ContractError::InvalidReferrer => "The referrer address must be 64 hex characters, optionally prefixed with 0x.".to_string(),
+            ContractError::SelfReferral => "Players can not refer themselves.".to_string(),
+            ContractError::MatchNotFound => "The match does not exist.".to_string(),
+            ContractError::InsufficientBalance => "Sender has no PERLS".to_string(),
+            ContractError::WaitingPoolFull => "The waiting pool is full, try again later.".to_string(),
+            ContractError::CommitmentMismatch => "The vote and nonce do not match the commitment.".to_string(),
+            ContractError::DuplicateTransaction => "The transaction has already been played.".to_string(),
+            ContractError::MissingParameter => "A parameter is missing from the payload.".to_string(),
+            ContractError::MalformedParameter => "A parameter in the payload is malformed.".to_string(),
+            ContractError::InvalidAddress => "The address must be 64 hex characters, optionally prefixed with 0x.".to_string(),
+            ContractError::Unauthorized => "Only the contract owner can do this.".to_string(),
+            ContractError::Paused => "The contract is paused, only exits are allowed.".to_string(),
+            ContractError::ZeroStake => "The call needs an amount.".to_string(),
+            ContractError::MatchStillWaiting => "The match is still waiting for other player.".to_string(),
+            ContractError::MatchAlreadyPlayed => "The match has already been played.".to_string(),
+            ContractError::NotYourMatch => "The match does not belong to the sender.".to_string(),
+            ContractError::OwnMatch => "Players can not challenge or bet on their own match.".to_string(),
+            ContractError::CooldownActive => "The sender already has a vote waiting to be revealed.".to_string(),
+            ContractError::CommitmentNotFound => "The sender has no vote to reveal, or the commitment has expired.".to_string(),
+            ContractError::InvalidBatchSize => format!("Between 1 and {} matches can be entered at once.", MAX_PLAY_MANY_COUNT),
+            ContractError::InvalidOutcome => "The predicted outcome must be 0 (both cooperate), 1 (both defect), 2 (player 1 defects) or 3 (player 2 defects).".to_string(),
+            ContractError::NotDelegate => "Only the player or their delegate can cash out the balance.".to_string(),
+            ContractError::MultisigNotFound => "The multisig request does not exist or has expired.".to_string(),
+            ContractError::NotApprover => "Only the approver can approve the multisig request.".to_string(),
+            ContractError::NotPendingOwner => "Only the proposed owner can accept the ownership.".to_string(),
+            ContractError::ShutDown => "The contract has been shut down for good.".to_string(),
+            ContractError::NoSurplus => "The pot has no surplus to distribute.".to_string(),
+            ContractError::NoRecentPlayers => "There are no recent players to distribute the surplus to.".to_string(),
+            ContractError::Overflow => "The amount does not fit into 64 bits.".to_string(),
+            ContractError::PayoutExceedsPot => "The match would pay out more than the pot holds.".to_string(),
+            ContractError::InvalidParameter(message) => message.clone(),
+        }
+    }
+}
+
+// Errors are displayed as JSON, e.g. {"code":"MATCH_NOT_FOUND","message":"The match does not exist."}
+impl std::fmt::Display for ContractError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let error = json!({
+            "code": self.code(),
+            "message": self.message(),
+        });
+        write!(f, "{}", error)
+    }
+}
+
+impl Error for ContractError {}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+// Writes the lowercase hex of the bytes into the buffer, without allocating.
+pub fn write_hex<'a>(bytes: &[u8; 32], buf: &'a mut [u8; 64]) -> &'a str {
+    for (i, b) in bytes.iter().enumerate() {
+        buf[i * 2] = HEX_DIGITS[(b >> 4) as usize];
+        buf[i * 2 + 1] = HEX_DIGITS[(b & 0x0f) as usize];
+    }
+
+    // Only ASCII hex digits were written.
+    std::str::from_utf8(buf).unwrap()
+}
+
+pub fn to_hex_string(bytes: [u8; 32]) -> String {
+    let mut buf = [0u8; 64];
+    write_hex(&bytes, &mut buf).to_owned()
+}
+
+// Parses 64 hex characters of either case, optionally prefixed with "0x".
+pub fn from_hex_string(hex: &str) -> Result<[u8; 32], ContractError> {
+    let hex = hex.strip_prefix("0x").or_else(|| hex.strip_prefix("0X")).unwrap_or(hex);
+
+    // `from_str_radix` alone would also accept a sign.
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ContractError::InvalidAddress);
+    }
+
+    let mut bytes = [0u8; 32];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| ContractError::InvalidAddress)?;
+    }
+
+    Ok(bytes)
+}
+
+// A pseudonym of the address, which only stays the same within a round.
+pub fn anonymize_address(sha256: fn(&[u8]) -> [u8; 32], addr: [u8; 32], round_id: [u8; 8]) -> String {
+    let mut data = [0u8; 40];
+    data[..32].copy_from_slice(&addr);
+    data[32..].copy_from_slice(&round_id);
+
+    to_hex_string(sha256(&data))
+}
+
+// The commitment to a vote, which is revealed along with the nonce.
+pub fn vote_commitment(sha256: fn(&[u8]) -> [u8; 32], vote: u8, nonce: [u8; 32]) -> [u8; 32] {
+    let mut data = [0u8; 33];
+    data[0] = vote;
+    data[1..].copy_from_slice(&nonce);
+
+    sha256(&data)
+}
+
+// JSON object keys must be strings, so maps keyed by an address are
+// (de)serialized with the address hex encoded.
+mod hex_key_map {
+    use std::collections::HashMap;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(map: &HashMap<[u8; 32], T>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(k, v)| (super::to_hex_string(*k), v)))
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<[u8; 32], T>, D::Error> {
+        let raw: HashMap<String, T> = HashMap::deserialize(deserializer)?;
+
+        let mut map = HashMap::new();
+        for (k, v) in raw {
+            let key = super::from_hex_string(&k).map_err(|_| D::Error::custom(format!("invalid address {}", k)))?;
+            map.insert(key, v);
+        }
+
+        Ok(map)
+    }
+}
+
+// Serializes addresses, transaction ids and hashes as hex strings.
+mod hex_bytes {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::to_hex_string(*bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        super::from_hex_string(&hex).map_err(|_| D::Error::custom(format!("invalid hex bytes {}", hex)))
+    }
+}
+
+mod hex_option {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error> {
+        bytes.map(super::to_hex_string).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(hex) => super::from_hex_string(&hex).map(Some).map_err(|_| D::Error::custom(format!("invalid hex bytes {}", hex))),
+            None => Ok(None),
+        }
+    }
+}
+
+mod hex_set {
+    use std::collections::HashSet;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(set: &HashSet<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(set.iter().map(|b| super::to_hex_string(*b)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashSet<[u8; 32]>, D::Error> {
+        let raw: Vec<String> = Vec::deserialize(deserializer)?;
+
+        let mut set = HashSet::new();
+        for hex in raw {
+            let bytes = super::from_hex_string(&hex).map_err(|_| D::Error::custom(format!("invalid hex bytes {}", hex)))?;
+            set.insert(bytes);
+        }
+
+        Ok(set)
+    }
+}
+
+// Maps with keys that can not be JSON object keys are (de)serialized as a
+// list of entries.
+mod entries_map {
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K: Serialize, V: Serialize, S: Serializer>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let entries: Vec<(K, V)> = Vec::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+// The sender's alias, if they have one and aliases are shown.
+pub fn alias_of(aliases: Option<&HashMap<[u8; 32], String>>, sender: [u8; 32]) -> Option<String> {
+    aliases.and_then(|aliases| aliases.get(&sender)).cloned()
+}
+
+// The largest number `random` returns.
+const RANDOM_MAX: u32 = 99;
+
+// A number in 0..=RANDOM_MAX, which only depends on the round id, the
+// transaction id, the sender and the amount. The bytes of the ids and the
+// sender are folded into the 16 byte seed with wrapping additions, so no
+// input can overflow it, and the amount is XORed into its first 8 bytes.
+pub fn random(env: &Env) -> u32 {
+    let mut seed: [u8; 16] = [0; 16];
+
+    let bytes = env.round_id.iter().chain(env.tx_id.iter()).chain(env.sender.iter());
+    for (i, b) in bytes.enumerate() {
+        seed[i % 16] = seed[i % 16].wrapping_add(*b);
+    }
+
+    for (s, b) in seed.iter_mut().zip(env.amount.to_le_bytes().iter()) {
+        *s ^= *b;
+    }
+
+    random_from(seed)
+}
+
+// A number in 0..=RANDOM_MAX drawn from the seed.
+pub fn random_from(seed: [u8; 16]) -> u32 {
+    let mut rng = rand::rngs::SmallRng::from_seed(seed);
+
+    rng.gen_range(0, RANDOM_MAX + 1)
+}
+
+// The seed of random draws made when a match is resolved. It mixes the
+// transaction ids of both players with the round id of the resolution, so the
+// player joining the match can't pick the draw by choosing when to join. The
+// joining player is the second player of the match, or else the sender of the
+// call resolving it. The random matching strategy draws the match to join
+// with it.
+pub fn entropy_for_match(m: &Match, env: &Env) -> [u8; 16] {
+    let joiner_tx_id = m.p2.as_ref().map_or(env.tx_id, |p2| p2.tx_id);
+
+    let mut seed: [u8; 16] = [0; 16];
+
+    let bytes = m.p1.tx_id.iter().chain(joiner_tx_id.iter()).chain(env.round_id.iter());
+    for (i, b) in bytes.enumerate() {
+        seed[i % 16] = seed[i % 16].wrapping_add(*b);
+    }
+
+    seed
+}
+
+// Classifies a match by its votes, player 1's vote first.
+pub fn outcome(p1_vote: u8, p2_vote: u8) -> &'static str {
+    match (p1_vote, p2_vote) {
+        (1, 1) => "cc",
+        (1, 2) => "cd",
+        (2, 1) => "dc",
+        (2, 2) => "dd",
+        _ => "abstain",
+    }
+}
+
+// Pairs of players are keyed in address order, whoever created the match.
+pub fn pair_key(a: [u8; 32], b: [u8; 32]) -> ([u8; 32], [u8; 32]) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+// The share of the amount in basis points. Like every percentage of the
+// contract, it is rounded down.
+pub fn bps_of(amount: u64, bps: u64) -> u64 {
+    (amount as u128 * bps as u128 / 10_000).min(u64::MAX as u128) as u64
+}
+
+// Splits the amount into equal parts, returning the part and the dust left
+// over by the division. The dust always goes back into the pot.
+pub fn split_amount(amount: u64, parts: u64) -> (u64, u64) {
+    if parts == 0 {
+        return (0, amount);
+    }
+
+    (amount / parts, amount % parts)
+}
+
+// Converts a widened intermediate back, failing if it does not fit.
+pub fn narrow_u64(value: u128) -> Result<u64, Box<dyn Error>> {
+    if value > u64::MAX as u128 {
+        return Err(ContractError::Overflow.into());
+    }
+
+    Ok(value as u64)
+}
+
+pub fn narrow_i64(value: i128) -> Result<i64, Box<dyn Error>> {
+    if value > i64::MAX as i128 || value < i64::MIN as i128 {
+        return Err(ContractError::Overflow.into());
+    }
+
+    Ok(value as i64)
+}
+
+// The pot once a match paid into or out of it. A match can never take more
+// than the pot holds.
+pub fn pot_after_payout(pot: u64, pot_payout: i64) -> Result<u64, Box<dyn Error>> {
+    let pot = pot as i128 + pot_payout as i128;
+    if pot < 0 {
+        return Err(ContractError::PayoutExceedsPot.into());
+    }
+
+    narrow_u64(pot as u128)
+}
+
+pub fn update_balance(balances: &mut HashMap<[u8; 32], u64>, sender: [u8; 32], amount: i128) {
+    let recipient_balance = match balances.get(&sender) {
+        Some(balance) => *balance,
+        None => 0,
+    };
+
+    let mut updated = recipient_balance as i128 + amount;
+    if updated.is_negative() {
+        // This should never happen.
+
+        updated = 0
+    }
+
+    balances.insert(sender, updated.min(u64::MAX as i128) as u64);
+}
+
+pub fn update_stats(
+    stats: &mut HashMap<[u8; 32], PlayerStats>,
+    earnings: &mut BTreeMap<u64, Vec<[u8; 32]>>,
+    player: &Player,
+    opponent_vote: u8,
+    payout: u64,
+) {
+    let s = stats.entry(player.sender).or_default();
+
+    s.matches_played += 1;
+
+    s.recent_votes.push_back(player.vote);
+    if s.recent_votes.len() > RECENT_VOTES_WINDOW {
+        s.recent_votes.pop_front();
+    }
+
+    let outcome = if player.vote == 1 && opponent_vote == 1 { OUTCOME_MUTUAL_COOPERATION } else { OUTCOME_OTHER };
+    s.recent_outcomes.push_back(outcome);
+    if s.recent_outcomes.len() > COOP_JACKPOT_STREAK {
+        s.recent_outcomes.pop_front();
+    }
+
+    let earned_before = s.lifetime_earned;
+    s.lifetime_earned = s.lifetime_earned.saturating_add(payout);
+    reindex_earnings(earnings, player.sender, earned_before, s.lifetime_earned);
+
+    if player.vote == 1 {
+        s.cooperations += 1;
+    } else if player.vote == 2 {
+        s.defections += 1;
+    }
+
+    // Only mutual cooperation extends the streak, a defection or a loss resets it.
+    if player.vote == 1 && opponent_vote == 1 {
+        s.cooperation_streak += 1;
+    } else {
+        s.cooperation_streak = 0;
+    }
+
+    // A win is any match that paid back more than the player staked.
+    if payout > player.stake {
+        s.wins += 1;
+        s.total_won += payout - player.stake;
+    }
+
+    s.reputation = s.compute_reputation();
+}
+
+// Moves the player to their new lifetime earnings in the index. Players who
+// never earned anything are left out, they all share the last rank.
+pub fn reindex_earnings(earnings: &mut BTreeMap<u64, Vec<[u8; 32]>>, sender: [u8; 32], before: u64, after: u64) {
+    if before == after {
+        return;
+    }
+
+    if let Some(senders) = earnings.get_mut(&before) {
+        senders.retain(|s| *s != sender);
+        if senders.is_empty() {
+            earnings.remove(&before);
+        }
+    }
+
+    if after > 0 {
+        earnings.entry(after).or_default().push(sender);
+    }
+}
+
+// Builds the lifetime earnings index from the players' stats.
+pub fn index_earnings(stats: &HashMap<[u8; 32], PlayerStats>) -> BTreeMap<u64, Vec<[u8; 32]>> {
+    let mut earnings = BTreeMap::new();
+
+    for (sender, s) in stats {
+        reindex_earnings(&mut earnings, *sender, 0, s.lifetime_earned);
+    }
+
+    earnings
+}
+
+// The 1-indexed rank of a player with the given lifetime earnings. Players
+// who earned the same share the better rank.
+pub fn earnings_rank(earnings: &BTreeMap<u64, Vec<[u8; 32]>>, earned: u64) -> usize {
+    let higher: usize = earnings.range((Bound::Excluded(earned), Bound::Unbounded))
+        .map(|(_, senders)| senders.len())
+        .sum();
+
+    higher + 1
+}
+
+pub fn reputation_of(stats: &HashMap<[u8; 32], PlayerStats>, sender: &[u8; 32]) -> u64 {
+    match stats.get(sender) {
+        Some(s) => s.reputation,
+        None => INITIAL_REPUTATION,
+    }
+}
+
+// Pays the sender a bonus from the pot if their cooperation streak just
+// reached one of the milestones of the schedule. Returns the bonus paid.
+pub fn pay_streak_bonus(
+    schedule: &[(u64, u64)],
+    pot: &mut u64,
+    balances: &mut HashMap<[u8; 32], u64>,
+    stats: &HashMap<[u8; 32], PlayerStats>,
+    sender: [u8; 32],
+) -> u64 {
+    let streak = match stats.get(&sender) {
+        Some(s) => s.cooperation_streak,
+        None => 0,
+    };
+
+    let bps = match schedule.iter().find(|(milestone, _)| *milestone == streak) {
+        Some((_, bps)) => *bps,
+        None => return 0,
+    };
+
+    pay_pot_bonus(bps, pot, balances, sender)
+}
+
+// Pays the player a share of the pot if their last matches were all mutual
+// cooperations, and starts their streak over. An empty pot pays nothing and
+// the streak carries on. Returns the jackpot paid.
+pub fn pay_coop_jackpot(
+    bps: u64,
+    pot: &mut u64,
+    balances: &mut HashMap<[u8; 32], u64>,
+    stats: &mut HashMap<[u8; 32], PlayerStats>,
+    sender: [u8; 32],
+) -> u64 {
+    let s = match stats.get_mut(&sender) {
+        Some(s) => s,
+        None => return 0,
+    };
+
+    if bps == 0 || s.recent_outcomes.len() < COOP_JACKPOT_STREAK {
+        return 0;
+    }
+    if s.recent_outcomes.iter().any(|outcome| *outcome != OUTCOME_MUTUAL_COOPERATION) {
+        return 0;
+    }
+
+    let jackpot = bps_of(*pot, bps).min(*pot);
+    if jackpot == 0 {
+        return 0;
+    }
+
+    *pot -= jackpot;
+    update_balance(balances, sender, jackpot as i128);
+    s.recent_outcomes.clear();
+
+    jackpot
+}
+
+// Pays the player a bonus of their stake from the pot if their match count
+// just reached one of the milestones. The milestone is recorded even when the
+// pot can't cover the bonus. Returns the milestone and the bonus paid.
+pub fn check_milestones(
+    bonus_bps: u64,
+    pot: &mut u64,
+    balances: &mut HashMap<[u8; 32], u64>,
+    stats: &mut HashMap<[u8; 32], PlayerStats>,
+    player: &Player,
+) -> Option<(u64, u64)> {
+    let s = stats.get_mut(&player.sender)?;
+
+    let milestone = *MATCH_MILESTONES.iter().find(|milestone| **milestone == s.matches_played)?;
+    s.last_milestone = milestone;
+
+    let bonus = bps_of(player.stake, bonus_bps);
+    if bonus == 0 || bonus > *pot {
+        return Some((milestone, 0));
+    }
+
+    *pot -= bonus;
+    update_balance(balances, player.sender, bonus as i128);
+
+    Some((milestone, bonus))
+}
+
+// Refunds part of the stake of an insured player who cooperated against a
+// defector, capped by the pot. Returns the refund paid.
+pub fn pay_insurance(pot: &mut u64, balances: &mut HashMap<[u8; 32], u64>, player: &Player, opponent_vote: u8) -> u64 {
+    if !player.insured || player.vote != 1 || opponent_vote != 2 {
+        return 0;
+    }
+
+    let refund = bps_of(player.stake, INSURANCE_COVER_PCT * 100).min(*pot);
+    if refund == 0 {
+        return 0;
+    }
+
+    *pot -= refund;
+    update_balance(balances, player.sender, refund as i128);
+
+    refund
+}
+
+// Charges a repeat defector who lost a mutual defection the part of the
+// penalty multiplier above 10000 of their stake, on top of the stake they
+// already lost. It is taken from their balance into the pot, capped by the
+// balance. Returns the penalty charged.
+pub fn charge_defection_penalty(
+    multiplier: u64,
+    pot: &mut u64,
+    balances: &mut HashMap<[u8; 32], u64>,
+    player: &Player,
+    opponent_vote: u8,
+    repeat_defector: bool,
+) -> u64 {
+    if !repeat_defector || player.vote != 2 || opponent_vote != 2 {
+        return 0;
+    }
+
+    let balance = balances.get(&player.sender).copied().unwrap_or(0);
+    let penalty = bps_of(player.stake, multiplier.saturating_sub(10_000)).min(balance);
+    if penalty == 0 {
+        return 0;
+    }
+
+    *pot += penalty;
+    update_balance(balances, player.sender, -(penalty as i128));
+
+    penalty
+}
+
+// Pays the sender a share of the pot, capped by whatever is left in the pot.
+// Returns the bonus paid.
+pub fn pay_pot_bonus(bps: u64, pot: &mut u64, balances: &mut HashMap<[u8; 32], u64>, sender: [u8; 32]) -> u64 {
+    let bonus = bps_of(*pot, bps).min(*pot);
+    if bonus == 0 {
+        return 0;
+    }
+
+    *pot -= bonus;
+    update_balance(balances, sender, bonus as i128);
+
+    bonus
+}
+
+// The loyalty tier (0 being the baseline) and its extra pot reward in basis
+// points for a player with the given number of matches played.
+pub fn loyalty_tier(tiers: &[(u64, u64)], matches_played: u64) -> (usize, u64) {
+    let mut tier = (0, 0);
+
+    for (i, (min_matches, bps)) in tiers.iter().enumerate() {
+        if matches_played >= *min_matches {
+            tier = (i + 1, *bps);
+        }
+    }
+
+    tier
+}
+
+// How the matching threshold moves after each play. A player who is not matched
+// raises it, so that the next player is more likely to be, and a matched player
+// lowers it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ThresholdMode {
+    // Moves by 1.
+    Linear,
+    // Closes a quarter of the distance to 100, or drops by a quarter.
+    Exponential,
+    // Never moves.
+    Fixed,
+}
+
+impl ThresholdMode {
+    pub fn from_u8(mode: u8) -> Option<ThresholdMode> {
+        match mode {
+            0 => Some(ThresholdMode::Linear),
+            1 => Some(ThresholdMode::Exponential),
+            2 => Some(ThresholdMode::Fixed),
+            _ => None,
+        }
+    }
+
+    pub fn raise(self, threshold: u32) -> u32 {
+        match self {
+            ThresholdMode::Linear => threshold + 1,
+            ThresholdMode::Exponential => threshold + 100u32.saturating_sub(threshold) / 4,
+            ThresholdMode::Fixed => threshold,
+        }
+    }
+
+    pub fn lower(self, threshold: u32) -> u32 {
+        match self {
+            ThresholdMode::Linear => threshold.saturating_sub(1),
+            ThresholdMode::Exponential => threshold * 3 / 4,
+            ThresholdMode::Fixed => threshold,
+        }
+    }
+}
+
+// Which of the eligible waiting matches a player gets matched with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MatchingStrategy {
+    // The first one in the waiting pool.
+    #[serde(rename = "FIFO")]
+    Fifo,
+    // A random one.
+    Random,
+    // The one whose stake is closest to the player's.
+    StakeWeighted,
+}
+
+impl MatchingStrategy {
+    pub fn from_u8(strategy: u8) -> Option<MatchingStrategy> {
+        match strategy {
+            0 => Some(MatchingStrategy::Fifo),
+            1 => Some(MatchingStrategy::Random),
+            2 => Some(MatchingStrategy::StakeWeighted),
+            _ => None,
+        }
+    }
+
+    // Picks one of the eligible matches, given by their indexes in the pool and
+    // their stakes, in pool order.
+    pub fn select(self, eligible: &[(usize, u64)], stake: u64, random: u32) -> Option<usize> {
+        if eligible.is_empty() {
+            return None;
+        }
+
+        match self {
+            MatchingStrategy::Fifo => Some(eligible[0].0),
+            MatchingStrategy::Random => Some(eligible[random as usize % eligible.len()].0),
+            MatchingStrategy::StakeWeighted => eligible.iter()
+                .min_by_key(|(_, s)| (*s).abs_diff(stake))
+                .map(|(index, _)| *index),
+        }
+    }
+}
+
+// The stake tier the stake falls into, if any. Tiers are in ascending order,
+// so a stake on the boundary of two tiers falls into the lower one.
+pub fn stake_tier(tiers: &[(u64, u64)], stake: u64) -> Option<usize> {
+    tiers.iter().position(|(lower, upper)| stake >= *lower && stake <= *upper)
+}
+
+// Pays the referrer of a player whose first ever match just resolved a
+// share of the pot. Returns the referrer and the reward paid.
+pub fn pay_referral(
+    referral_bps: u64,
+    max_referral_reward: u64,
+    pot: &mut u64,
+    balances: &mut HashMap<[u8; 32], u64>,
+    referred: &mut HashSet<[u8; 32]>,
+    stats: &HashMap<[u8; 32], PlayerStats>,
+    player: &Player,
+) -> Option<([u8; 32], u64)> {
+    let referrer = player.referrer?;
+
+    let first_match = match stats.get(&player.sender) {
+        Some(s) => s.matches_played == 1,
+        None => false,
+    };
+
+    if !first_match || referred.contains(&player.sender) {
+        return None;
+    }
+    referred.insert(player.sender);
+
+    let reward = bps_of(*pot, referral_bps).min(max_referral_reward).min(*pot);
+    if reward == 0 {
+        return None;
+    }
+
+    *pot -= reward;
+    update_balance(balances, referrer, reward as i128);
+
+    Some((referrer, reward))
+}
+
+// Awards the badges the sender has earned with their latest match.
+pub fn update_achievements(
+    achievements: &mut HashMap<[u8; 32], u64>,
+    stats: &HashMap<[u8; 32], PlayerStats>,
+    sender: [u8; 32],
+    won_jackpot: bool,
+) {
+    let s = match stats.get(&sender) {
+        Some(s) => s,
+        None => return,
+    };
+
+    let mut earned = 0;
+    if s.matches_played >= 1 {
+        earned |= BADGE_FIRST_MATCH;
+    }
+    if s.cooperations >= 1 {
+        earned |= BADGE_FIRST_COOPERATION;
+    }
+    if s.defections >= 1 {
+        earned |= BADGE_FIRST_DEFECTION;
+    }
+    if s.cooperations >= 10 {
+        earned |= BADGE_10_COOPERATIONS;
+    }
+    if s.wins >= 10 {
+        earned |= BADGE_10_WINS;
+    }
+    if won_jackpot {
+        earned |= BADGE_JACKPOT_WIN;
+    }
+
+    *achievements.entry(sender).or_insert(0) |= earned;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub matches_played: u64,
+    pub wins: u64,
+    // Total profit made from won matches.
+    pub total_won: u64,
+    pub cooperations: u64,
+    pub defections: u64,
+    // Number of consecutive mutual cooperations.
+    pub cooperation_streak: u64,
+    // Waiting matches the player cancelled instead of seeing them through.
+    pub cancellations: u64,
+    // 0-100 score from the cooperation rate and the share of completed matches.
+    pub reputation: u64,
+    // Everything the player ever staked, and got paid out by resolved matches.
+    pub lifetime_staked: u64,
+    pub lifetime_earned: u64,
+    // The strategy of the player's latest match, 0 for humans.
+    pub strategy_id: u8,
+    // The highest match count milestone the player reached, 0 for none.
+    pub last_milestone: u64,
+    // The votes of the player's last RECENT_VOTES_WINDOW matches, oldest first.
+    pub recent_votes: VecDeque<u8>,
+    // The outcomes of the player's last COOP_JACKPOT_STREAK matches since the
+    // last cooperation jackpot, oldest first.
+    pub recent_outcomes: VecDeque<u8>,
+}
+
+impl PlayerStats {
+    pub fn is_repeat_defector(&self) -> bool {
+        self.recent_votes.iter().filter(|vote| **vote == 2).count() >= REPEAT_DEFECTIONS
+    }
+
+    pub fn compute_reputation(&self) -> u64 {
+        // Without any matches there is nothing held against the player
+        let cooperation = if self.matches_played == 0 { INITIAL_REPUTATION } else { self.cooperation_rate() };
+
+        let total = self.matches_played + self.cancellations;
+        let completion = (self.matches_played * 100).checked_div(total).unwrap_or(INITIAL_REPUTATION);
+
+        (cooperation + completion) / 2
+    }
+
+    // Percentage of matches in which the player cooperated, rounded down.
+    pub fn cooperation_rate(&self) -> u64 {
+        if self.matches_played == 0 {
+            return 0;
+        }
+
+        self.cooperations * 100 / self.matches_played
+    }
+
+    // Percentage returned on everything staked, 0 for a player who never staked.
+    pub fn roi_pct(&self) -> i64 {
+        if self.lifetime_staked == 0 {
+            return 0;
+        }
+
+        let roi = (self.lifetime_earned as i128 - self.lifetime_staked as i128) * 100 / self.lifetime_staked as i128;
+        roi.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+}
+
+// A cash-out that is only executed once the approver signs off on it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultisigRequest {
+    #[serde(with = "hex_bytes")]
+    pub requester: [u8; 32],
+    #[serde(with = "hex_bytes")]
+    pub approver: [u8; 32],
+    pub amount: u64,
+    pub nonce: u64,
+    pub created_round: u64,
+}
+
+// A vote that was committed to by its hash, and still has to be revealed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingCommit {
+    #[serde(with = "hex_bytes")]
+    pub sender: [u8; 32],
+    #[serde(with = "hex_bytes")]
+    pub tx_id: [u8; 32],
+    #[serde(with = "hex_bytes")]
+    pub commitment: [u8; 32],
+    pub stake: u64,
+    pub committed_round: u64,
+}
+
+// A spectator's bet on the outcome of a waiting match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SideBet {
+    pub match_id: String,
+    #[serde(with = "hex_bytes")]
+    pub bettor: [u8; 32],
+    // 0 = both cooperate, 1 = both defect, 2 = player 1 defects, 3 = player 2 defects.
+    pub predicted_outcome: u8,
+    pub stake: u64,
+}
+
+// The side bet outcome of a match, if the match has one.
+pub fn side_bet_outcome(p1_vote: u8, p2_vote: u8) -> Option<u8> {
+    match outcome(p1_vote, p2_vote) {
+        "cc" => Some(0),
+        "dd" => Some(1),
+        "dc" => Some(2),
+        "cd" => Some(3),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PairRecord {
+    pub times_played: u32,
+    pub last_seen_round: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(not(test), derive(Clone))]
+pub struct Player {
+    #[serde(with = "hex_bytes")]
+    pub sender: [u8; 32],
+    #[serde(with = "hex_bytes")]
+    pub tx_id: [u8; 32],
+    pub stake: u64,
+    // The vote that counts. For a conditional vote it is only known once the
+    // match resolves.
+    pub vote: u8,
+    // The vote as submitted, and for a conditional vote the opponent stake
+    // needed to cooperate.
+    pub original_vote: u8,
+    pub min_opponent_stake: u64,
+    // The player who referred this player to the contract.
+    #[serde(with = "hex_option")]
+    pub referrer: Option<[u8; 32]>,
+    pub insured: bool,
+    // The premium already taken from the player's stake for the insurance.
+    pub insurance_premium: u64,
+    // The commitment to a blinded vote, which is revealed once an opponent joined.
+    #[serde(with = "hex_option")]
+    pub blinded_vote: Option<[u8; 32]>,
+    // The strategy a bot plays, 0 for humans.
+    pub strategy_id: u8,
+}
+
+// Test builds count the player clones, which also counts the clones of the
+// matches they are in.
+#[cfg(test)]
+thread_local! {
+    pub static PLAYER_CLONES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+impl Clone for Player {
+    fn clone(&self) -> Self {
+        PLAYER_CLONES.with(|clones| clones.set(clones.get() + 1));
+        Player { ..*self }
+    }
+}
+
+impl Player {
+    // A conditional vote cooperates if the opponent staked enough, and defects otherwise.
+    pub fn resolved_vote(&self, opponent_stake: u64) -> u8 {
+        if self.original_vote != 4 {
+            return self.vote;
+        }
+
+        if opponent_stake >= self.min_opponent_stake { 1 } else { 2 }
+    }
+}
+
+// Pot reward paid per player, depending on how large the pot is.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RewardSchedule {
+    // Below the low-water mark a flat minimum reward is paid.
+    pub low_water: u64,
+    // Above the high-water mark the reward is capped.
+    pub high_water: u64,
+    pub min_reward: u64,
+    pub max_reward: u64,
+    // Percentages paid in between, in basis points.
+    pub cooperate_bps: u64,
+    pub defect_bps: u64,
+}
+
+impl RewardSchedule {
+    // The most basis points of the pot each reward can be, so that rewards
+    // never pay out more than the pot. A mutual cooperation pays the
+    // cooperate reward to both players.
+    pub const MAX_COOPERATE_BPS: u64 = 5_000;
+    pub const MAX_DEFECT_BPS: u64 = 10_000;
+
+    pub fn check_bps(cooperate_bps: u64, defect_bps: u64) -> Result<(), ContractError> {
+        if cooperate_bps > Self::MAX_COOPERATE_BPS || defect_bps > Self::MAX_DEFECT_BPS {
+            return Err(ContractError::InvalidParameter("Rewards must not pay out more than the pot.".to_string()));
+        }
+
+        Ok(())
+    }
+
+    // The reward for each of the recipients sharing the pot.
+    pub fn reward(&self, pot: u64, bps: u64, recipients: u64) -> u64 {
+        if pot < self.low_water {
+            return self.min_reward.min(pot / recipients);
+        }
+
+        let reward = bps_of(pot, bps);
+        if pot > self.high_water {
+            return reward.min(self.max_reward);
+        }
+
+        reward
+    }
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        RewardSchedule {
+            low_water: 100,
+            high_water: 100_000_000,
+            min_reward: 1,
+            max_reward: 1_500_000,
+            cooperate_bps: 100,
+            defect_bps: 150,
+        }
+    }
+}
+
+// The configurable parts of the payout calculation of a match.
+#[derive(Debug)]
+pub struct PayoutRules {
+    pub schedule: RewardSchedule,
+    pub abstain_fee_bps: u64,
+    // When set, a mutual cooperation splits this share of the pot instead of
+    // paying the usual rewards.
+    pub jackpot_share_bps: Option<u64>,
+    // Share of the stakes forfeited by a mutual defection that goes to the charity.
+    pub charity_share_bps: u64,
+    // The pot reward of a defector who betrayed a cooperator is capped at this
+    // multiple of the smaller stake.
+    pub defector_bonus_cap_multiplier: u64,
+    // The pot reward of a repeat defector is divided by this multiplier, in
+    // basis points, 10000 leaving it as it is.
+    pub defection_penalty_multiplier: u64,
+    // Whether player 1 and player 2 are repeat defectors.
+    pub repeat_defectors: (bool, bool),
+    // Below this pot no pot rewards are paid, only the stakes.
+    pub min_pot_for_rewards: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Match {
+    pub id: String,
+    pub p1: Player,
+    pub p2: Option<Player>,
+
+    // The amout goes into Player 1 balance.
+    pub p1_payout: u64,
+    // The amout goes into Player 2 balance.
+    pub p2_payout: u64,
+    // The amout goes into pot or minus the pot.
+    pub pot_payout: i64,
+    // The part of the players' payouts that came from the jackpot.
+    pub jackpot_payout: u64,
+    // The amount of the forfeited stakes that goes to the charity.
+    pub charity_payout: u64,
+    // The pot reward of a defector who betrayed a cooperator, before and after the cap.
+    pub defector_bonus_uncapped: u64,
+    pub defector_bonus: u64,
+    // The pot once the match and its bonuses were paid out, and how much it
+    // grew or shrank because of the match.
+    pub pot_after: u64,
+    pub pot_change: i64,
+
+    // The rounds in which the match was created and resolved.
+    pub created_round: u64,
+    pub resolved_round: u64,
+    // The id of the round the match was resolved in.
+    #[serde(with = "hex_bytes")]
+    pub resolved_round_id: [u8; 32],
+
+    // Priority matches are offered to new players first.
+    pub priority: bool,
+    // The round in which the match entered the waiting pool. Older matches
+    // are offered first.
+    pub queued_at: u64,
+    // The round in which player 2 joined a match whose vote was still blinded.
+    pub joined_round: u64,
+}
+
+impl Match {
+    pub fn new(id: String, player: Player, round: u64) -> Match {
+        Match {
+            id,
+            p1: player,
+            p2: None,
+            p1_payout: 0,
+            p2_payout: 0,
+            pot_payout: 0,
+            jackpot_payout: 0,
+            charity_payout: 0,
+            defector_bonus_uncapped: 0,
+            defector_bonus: 0,
+            pot_after: 0,
+            pot_change: 0,
+            created_round: round,
+            resolved_round: 0,
+            resolved_round_id: [0; 32],
+            priority: false,
+            queued_at: round,
+            joined_round: 0,
+        }
+    }
+
+    // Computes the payouts of the match. The amounts are widened to u128 while
+    // they are computed, so a failure to narrow them back leaves the match untouched.
+    pub fn play(&mut self, mut p2: Player, pot: u64, rules: &PayoutRules) -> Result<(), Box<dyn Error>> {
+        let abstain_fee_bps = rules.abstain_fee_bps as u128;
+
+        // Resolve the conditional votes against the opponent's stake
+        let p1_vote = self.p1.resolved_vote(p2.stake);
+        let p2_vote = p2.resolved_vote(self.p1.stake);
+
+        let p1_stake = self.p1.stake as u128;
+        let p2_stake = p2.stake as u128;
+
+        let mut p1_payout: u128 = 0;
+        let mut p2_payout: u128 = 0;
+        let mut pot_payout: i128 = 0;
+        let mut jackpot_payout: u128 = 0;
+        let mut charity_payout: u128 = 0;
+        let mut defector_bonus_uncapped: u64 = 0;
+        let mut defector_bonus: u64 = 0;
+
+        let pot_rewards = pot >= rules.min_pot_for_rewards;
+
+        if p1_vote == 3 || p2_vote == 3 {
+            // Someone abstained. Both players get back their stakes minus the
+            // abstain fee, whatever the other player voted. The fees go to the pot
+
+            let p1_fee = (p1_stake * abstain_fee_bps / 10_000).min(p1_stake);
+            p1_payout = p1_stake - p1_fee;
+
+            let p2_fee = (p2_stake * abstain_fee_bps / 10_000).min(p2_stake);
+            p2_payout = p2_stake - p2_fee;
+
+            pot_payout = (p1_fee + p2_fee) as i128;
+        } else if p1_vote == 2 && p2_vote == 2 {
+            // Both players lose the stakes. The stakes go to the pot, minus
+            // the charity share
+
+            let forfeited = p1_stake + p2_stake;
+            charity_payout = forfeited * rules.charity_share_bps as u128 / 10_000;
+
+            pot_payout = (forfeited - charity_payout) as i128;
+        } else if p1_vote == 1 && p2_vote == 1 && !pot_rewards {
+            // Both players get back their stakes, the pot is too small for rewards
+
+            p1_payout = p1_stake;
+            p2_payout = p2_stake;
+        } else if p1_vote == 1 && p2_vote == 1 && rules.jackpot_share_bps.is_some() {
+            // Both players get back their stakes and split the jackpot
+
+            let jackpot = bps_of(pot, rules.jackpot_share_bps.unwrap());
+            let (share, dust) = split_amount(jackpot, 2);
+            let share = share as u128;
+
+            p1_payout = p1_stake + share;
+            p2_payout = p2_stake + share;
+            jackpot_payout = share * 2;
+
+            // The dust of the split stays in the pot
+            pot_payout = -((jackpot - dust) as i128);
+        } else if p1_vote == 1 && p2_vote == 1 {
+            // Both players get back their stakes plus pot rewards
+
+            let p1_pot_payout = rules.schedule.reward(pot, rules.schedule.cooperate_bps, 2) as u128;
+            p1_payout = p1_stake + p1_pot_payout;
+
+            let p2_pot_payout = rules.schedule.reward(pot, rules.schedule.cooperate_bps, 2) as u128;
+            p2_payout = p2_stake + p2_pot_payout;
+
+            pot_payout = -((p1_pot_payout + p2_pot_payout) as i128);
+        } else if (p1_vote == 1 && p2_vote == 2) || (p1_vote == 2 && p2_vote == 1) {
+            // The cooperator loses the stake. The defector gets back the stake,
+            // plus the cooperator's stake and a capped pot reward
+
+            if pot_rewards {
+                defector_bonus_uncapped = rules.schedule.reward(pot, rules.schedule.defect_bps, 1);
+            }
+            defector_bonus = self.cap_defector_bonus(defector_bonus_uncapped, &p2, rules);
+
+            // Repeat defectors get less of the pot, the rest stays in it
+            let repeat_defector = if p1_vote == 2 { rules.repeat_defectors.0 } else { rules.repeat_defectors.1 };
+            if repeat_defector {
+                let multiplier = rules.defection_penalty_multiplier.max(10_000);
+                defector_bonus = (defector_bonus as u128 * 10_000 / multiplier as u128) as u64;
+            }
+
+            let defector_payout = p1_stake + p2_stake + defector_bonus as u128;
+            if p1_vote == 2 {
+                p1_payout = defector_payout;
+            } else {
+                p2_payout = defector_payout;
+            }
+
+            pot_payout = -(defector_bonus as i128);
+        }
+
+        // Narrow everything back before touching the match
+        let p1_payout = narrow_u64(p1_payout)?;
+        let p2_payout = narrow_u64(p2_payout)?;
+        let pot_payout = narrow_i64(pot_payout)?;
+        pot_after_payout(pot, pot_payout)?;
+        let jackpot_payout = narrow_u64(jackpot_payout)?;
+        let charity_payout = narrow_u64(charity_payout)?;
+
+        self.p1.vote = p1_vote;
+        p2.vote = p2_vote;
+
+        self.p1_payout = p1_payout;
+        self.p2_payout = p2_payout;
+        self.pot_payout = pot_payout;
+        self.jackpot_payout = jackpot_payout;
+        self.charity_payout = charity_payout;
+        self.defector_bonus_uncapped = defector_bonus_uncapped;
+        self.defector_bonus = defector_bonus;
+
+        self.p2 = Some(p2);
+
+        Ok(())
+    }
+
+    // Caps the pot reward of a defector who betrayed a cooperator at a multiple
+    // of the smaller stake, so that big pots do not dominate the incentives.
+    fn cap_defector_bonus(&self, uncapped: u64, p2: &Player, rules: &PayoutRules) -> u64 {
+        let cap = self.p1.stake.min(p2.stake).saturating_mul(rules.defector_bonus_cap_multiplier);
+
+        uncapped.min(cap)
+    }
+
+    // The result of a resolved match, as reported by the result queries.
+    // The addresses of the players are formatted by `address`.
+    pub fn archive(&self) -> ArchivedMatch {
+        ArchivedMatch {
+            p1_sender: self.p1.sender,
+            p2_sender: self.p2.as_ref().unwrap().sender,
+            p1_payout: self.p1_payout,
+            p2_payout: self.p2_payout,
+            outcome: self.outcome().to_string(),
+        }
+    }
+
+    // The outcome of a resolved match, classified from the recorded votes.
+    pub fn outcome(&self) -> &'static str {
+        outcome(self.p1.vote, self.p2.as_ref().unwrap().vote)
+    }
+
+    pub fn resolution<F: Fn([u8; 32]) -> String>(&self, aliases: Option<&HashMap<[u8; 32], String>>, address: F) -> MatchResolved {
+        let p2 = self.p2.as_ref().unwrap();
+
+        let player = |player: &Player, payout: u64| PlayerResult {
+            sender: address(player.sender),
+            payout,
+            tx_id: Some(to_hex_string(player.tx_id)),
+            vote: Some(player.vote),
+            strategy_id: Some(player.strategy_id),
+            alias: alias_of(aliases, player.sender),
+            // Conditional votes show what they resolved to
+            original_vote: Some(player.original_vote).filter(|vote| *vote != player.vote),
+            ..PlayerResult::default()
+        };
+
+        let defector_bonus = match self.outcome() {
+            "cd" | "dc" => Some(DefectorBonus {
+                uncapped: self.defector_bonus_uncapped,
+                capped: self.defector_bonus,
+            }),
+            _ => None,
+        };
+
+        MatchResolved {
+            schema_version: SCHEMA_VERSION,
+            match_id: self.id.clone(),
+            player_1: player(&self.p1, self.p1_payout),
+            player_2: player(p2, self.p2_payout),
+            outcome: self.outcome().to_string(),
+            status: None,
+            archived: false,
+            created_round: Some(self.created_round),
+            resolved_round: Some(self.resolved_round),
+            resolved_round_id: Some(to_hex_string(self.resolved_round_id)),
+            pot_change: Some(self.pot_change),
+            pot_after: Some(self.pot_after),
+            wait_duration: None,
+            times_played_together: None,
+            escrow_released: None,
+            jackpot: Some(self.jackpot_payout).filter(|payout| *payout > 0),
+            charity: Some(self.charity_payout).filter(|payout| *payout > 0),
+            defector_bonus,
+            side_bets: Vec::new(),
+        }
+    }
+
+    // A match that is still waiting only has the first player, whose vote stays hidden.
+    pub fn waiting_json(&self) -> serde_json::Value {
+        json!({
+            "match_id": self.id,
+            "status": "waiting",
+            "created_round": self.created_round,
+            "stake": self.p1.stake,
+        })
+    }
+
+    pub fn wait_duration(&self) -> u64 {
+        self.resolved_round.saturating_sub(self.created_round)
+    }
+}
+
+// What is kept of a match once it was pruned from the history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedMatch {
+    #[serde(with = "hex_bytes")]
+    pub p1_sender: [u8; 32],
+    #[serde(with = "hex_bytes")]
+    pub p2_sender: [u8; 32],
+    pub p1_payout: u64,
+    pub p2_payout: u64,
+    pub outcome: String,
+}
+
+// The state changes of a player joining a waiting match.
+pub struct Resolution {
+    // The payouts credited to the balances.
+    pub credits: Vec<([u8; 32], u64)>,
+    pub pot: u64,
+}
+
+// Resolves a waiting match against the joining player. Only the match itself is
+// updated, and only on success; the caller applies the returned changes.
+pub fn resolve_match(m: &mut Match, player: Player, pot: u64, rules: &PayoutRules, charity: Option<[u8; 32]>, round: u64) -> Result<Resolution, Box<dyn Error>> {
+    m.play(player, pot, rules)?;
+    m.resolved_round = round;
+
+    let p2 = m.p2.as_ref().unwrap();
+    let mut credits = vec![(p2.sender, m.p2_payout), (m.p1.sender, m.p1_payout)];
+
+    if let Some(charity) = charity {
+        if m.charity_payout > 0 {
+            credits.push((charity, m.charity_payout));
+        }
+    }
+
+    let pot = pot_after_payout(pot, m.pot_payout)?;
+
+    Ok(Resolution {
+        credits,
+        pot,
+    })
+}
+
+pub const WAIT_ROUNDS_EMA_ALPHA: f64 = 0.1;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GlobalStats {
+    pub matches_resolved: u64,
+    // Exponential moving average of the rounds a match waited for its second player.
+    pub avg_wait_rounds: f64,
+
+    // Resolutions per outcome, the first letter being player 1's vote.
+    pub cc_count: u64,
+    pub cd_count: u64,
+    pub dc_count: u64,
+    pub dd_count: u64,
+
+    // Everything that ever went into and out of the pot.
+    pub pot_inflow: u64,
+    pub pot_outflow: u64,
+
+    // Everything ever staked in matches, and cashed out of the balances.
+    pub total_staked: u64,
+    pub total_cashed_out: u64,
+}
+
+impl GlobalStats {
+    pub fn record_resolution(&mut self, wait_duration: u64) {
+        if self.matches_resolved == 0 {
+            self.avg_wait_rounds = wait_duration as f64;
+        } else {
+            self.avg_wait_rounds += (wait_duration as f64 - self.avg_wait_rounds) * WAIT_ROUNDS_EMA_ALPHA;
+        }
+
+        self.matches_resolved += 1;
+    }
+
+    pub fn record_outcome(&mut self, p1_vote: u8, p2_vote: u8) {
+        match outcome(p1_vote, p2_vote) {
+            "cc" => self.cc_count += 1,
+            "cd" => self.cd_count += 1,
+            "dc" => self.dc_count += 1,
+            "dd" => self.dd_count += 1,
+            _ => {}
+        }
+    }
+
+    // Percentage of the votes of all resolved matches that cooperated.
+    pub fn coop_rate_pct(&self) -> u64 {
+        let votes = 2 * (self.cc_count + self.cd_count + self.dc_count + self.dd_count);
+        if votes == 0 {
+            return 0;
+        }
+
+        (2 * self.cc_count + self.cd_count + self.dc_count) * 100 / votes
+    }
+
+    pub fn record_pot_change(&mut self, change: i128) {
+        if change > 0 {
+            self.pot_inflow += change as u64;
+        } else {
+            self.pot_outflow += (-change) as u64;
+        }
+    }
+}
+
+// The transaction a call came with, and the hash function of the runtime.
+pub struct Env {
+    pub sender: [u8; 32],
+    pub tx_id: [u8; 32],
+    pub amount: u64,
+    pub round_idx: u64,
+    pub round_id: [u8; 32],
+    pub sha256: fn(&[u8]) -> [u8; 32],
+}
+
+impl Env {
+    pub fn hash(&self, data: &[u8]) -> [u8; 32] {
+        (self.sha256)(data)
+    }
+}
+
+// What a call wants done outside of the state: the messages to log and the
+// (destination, amount) transfers to send, in the order they were made.
+#[derive(Default, Debug)]
+pub struct Effects {
+    pub logs: Vec<String>,
+    pub transfers: Vec<([u8; 32], u64)>,
+}
+
+impl Effects {
+    pub fn log(&mut self, message: &str) {
+        self.logs.push(message.to_string());
+    }
+
+    pub fn transfer(&mut self, destination: [u8; 32], amount: u64) {
+        self.transfers.push((destination, amount));
+    }
+}
+
+// The payload of a play.
+pub struct PlayRequest {
+    pub vote: u8,
+    pub strategy_id: u8,
+    pub min_opponent_stake: u64,
+    pub blinded_vote: Option<[u8; 32]>,
+    // The referrer as hex, an empty string means there is none.
+    pub referrer: String,
+    pub insured: bool,
+}
+
+// The payload of a batch of plays.
+pub struct PlayManyRequest {
+    pub count: u8,
+    pub vote: u8,
+    pub strategy_id: u8,
+    pub min_opponent_stake: u64,
+}
+
+// How the amount of a batch of plays is split: the stake of each match, the
+// dust left over and the transaction id of each match.
+struct Batch {
+    stake: u64,
+    dust: u64,
+    tx_ids: Vec<[u8; 32]>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GameState {
+    #[serde(with = "hex_bytes")]
+    pub owner: [u8; 32],
+    // The owner proposed by `transfer_ownership`, until they accept.
+    #[serde(with = "hex_option")]
+    pub pending_owner: Option<[u8; 32]>,
+    // While paused new matches, challenges and bets are rejected, but players
+    // can still cancel their matches and cash out.
+    pub paused: bool,
+    // A shut down contract stays paused for good.
+    pub shut_down: bool,
+    #[serde(with = "hex_key_map")]
+    pub balances: HashMap<[u8; 32], u64>,
+    pub pot: u64,
+    pub threshold: u32,
+    pub threshold_decay_mode: ThresholdMode,
+    pub matching_strategy: MatchingStrategy,
+    pub reward_schedule: RewardSchedule,
+    // Fee charged on both stakes of a match in which someone abstained.
+    pub abstain_fee_bps: u64,
+    pub waiting: Vec<Match>,
+    pub history: Vec<Match>,
+    // The most resolved matches kept in the history, older ones are archived.
+    pub history_capacity: usize,
+    #[serde(with = "hex_key_map")]
+    pub stats: HashMap<[u8; 32], PlayerStats>,
+    // Pairs of (cooperation streak milestone, pot bonus in basis points).
+    pub streak_bonuses: Vec<(u64, u64)>,
+    // Once the pot exceeds the trigger the jackpot is armed, and stays armed
+    // until the next mutual cooperation claims it, even if the pot drops back
+    // below the trigger in the meantime. A trigger of 0 disables it.
+    pub jackpot_trigger: u64,
+    pub jackpot_share_bps: u64,
+    pub jackpot_armed: bool,
+    // Bonus in basis points of the stake, paid from the pot when a player
+    // reaches one of the match count milestones.
+    pub milestone_bonus_bps: u64,
+    // Below this pot matches pay back the stakes but no pot rewards or bonuses.
+    // 0 always pays them.
+    pub min_pot_for_rewards: u64,
+    // Share of the pot in basis points paid to a player after three mutual
+    // cooperations in a row. 0 disables it.
+    pub jackpot_pct_bps: u64,
+    pub global_stats: GlobalStats,
+    // Receives a share of the stakes forfeited by mutual defections.
+    #[serde(with = "hex_option")]
+    pub charity: Option<[u8; 32]>,
+    pub charity_share_bps: u64,
+    // Round index -> (cooperations, defections) of the matches resolved in that round.
+    pub round_stats: HashMap<u64, (u32, u32)>,
+    // Every `decay_period` rounds `decay_bps` of the pot is shared among the
+    // senders of the last `decay_matches` resolved matches. A period of 0 disables it.
+    pub decay_period: u64,
+    pub decay_bps: u64,
+    pub decay_matches: usize,
+    // The last period in which the pot decayed.
+    pub last_decay_period: u64,
+    #[serde(with = "hex_key_map")]
+    pub aliases: HashMap<[u8; 32], String>,
+    // Share of the pot paid to a referrer once the referred player's first
+    // match resolves, capped at `max_referral_reward`.
+    pub referral_bps: u64,
+    pub max_referral_reward: u64,
+    // Players whose referrer has already been paid.
+    #[serde(with = "hex_set")]
+    pub referred: HashSet<[u8; 32]>,
+    // Bitmask of the badges each player has earned.
+    #[serde(with = "hex_key_map")]
+    pub achievements: HashMap<[u8; 32], u64>,
+    // Pairs of (lifetime matches played, extra pot reward in basis points),
+    // in ascending order of matches played.
+    pub loyalty_tiers: Vec<(u64, u64)>,
+    pub min_stake: u64,
+    // Fee paid into the pot to put a waiting match in front of the queue.
+    // A fee of 0 disables priority matches.
+    pub priority_fee: u64,
+    // Players below the reputation floor are only matched with each other.
+    pub reputation_floor: u64,
+    pub defector_bonus_cap_multiplier: u64,
+    // Divides the pot reward of players who defected in most of their recent
+    // matches, in basis points, and multiplies what they lose in a mutual
+    // defection. 10000 doesn't penalize them.
+    pub defection_penalty_multiplier: u64,
+    // The pot above the threshold can be shared among the players of the
+    // last `surplus_matches` resolved matches.
+    pub surplus_threshold: u64,
+    pub surplus_matches: usize,
+    // How often each pair of players met. Pairs not seen for a whole
+    // retention period are dropped.
+    #[serde(with = "entries_map")]
+    pub pairs: HashMap<([u8; 32], [u8; 32]), PairRecord>,
+    pub pair_retention_rounds: u64,
+    pub last_pair_prune: u64,
+    pub multisig_requests: Vec<MultisigRequest>,
+    pub multisig_expiry_rounds: u64,
+    pub next_multisig_nonce: u64,
+    // Checks the accounting after every state change, to catch bugs early.
+    pub invariants_enabled: bool,
+    // Everything taken in with the plays, and everything sent back out.
+    pub total_deposits: u64,
+    pub total_withdrawals: u64,
+    // The pot burned by a shutdown, which nobody can withdraw anymore.
+    pub total_burned: u64,
+    // Players whose match payouts are sent to them right away instead of
+    // going into their balance.
+    #[serde(with = "hex_set")]
+    pub auto_cashout: HashSet<[u8; 32]>,
+    // Player -> the address allowed to cash out the player's balance for them.
+    #[serde(with = "hex_key_map")]
+    pub cash_out_delegates: HashMap<[u8; 32], [u8; 32]>,
+    // The most matches that can wait at once. 0 means there is no limit.
+    pub max_waiting_pool: usize,
+    // Shows players by a per-round pseudonym instead of their address in the logs.
+    pub anonymous: bool,
+    pub side_bets: Vec<SideBet>,
+    // Committed votes that are not revealed within `commitment_expiry_rounds`
+    // are dropped, and their stakes sent back.
+    pub pending_commits: Vec<PendingCommit>,
+    pub commitment_expiry_rounds: u64,
+    // Match id -> (player 1 stake, player 2 stake) held for the match until it is settled.
+    pub escrow: HashMap<String, (u64, u64)>,
+    // The ids of each player's resolved matches, oldest first.
+    #[serde(with = "hex_key_map")]
+    pub player_history: HashMap<[u8; 32], Vec<String>>,
+    // Lifetime earnings -> the players who earned that much, to rank players
+    // without going through all the stats. It is rebuilt from the stats on import.
+    #[serde(skip)]
+    pub earnings_index: BTreeMap<u64, Vec<[u8; 32]>>,
+    // Matches with a blinded vote that an opponent joined, waiting for player 1
+    // to reveal the vote. Unrevealed votes are forfeited after `commitment_expiry_rounds`.
+    pub awaiting_reveal: Vec<Match>,
+    // The most recent cooperate (true) and defect (false) votes of all matches,
+    // and how many of them are cooperations.
+    pub coop_window: VecDeque<bool>,
+    pub coop_window_sum: u32,
+    // Waiting matches older than `match_expiry_rounds` are refunded, at most
+    // `sweep_limit` of them per play. An expiry of 0 disables it.
+    pub match_expiry_rounds: u64,
+    pub sweep_limit: usize,
+    // The sequence number of the last emitted event.
+    pub seq: u64,
+    // Players who only play when challenged. Their matches wait in
+    // `challenge_only_waiting`, which random matchmaking never looks at.
+    #[serde(with = "hex_set")]
+    pub opt_out_random: HashSet<[u8; 32]>,
+    pub challenge_only_waiting: Vec<Match>,
+    // The transactions played in the last `tx_id_retention_rounds` rounds, so
+    // they can not be replayed, and the rounds they were played in, oldest first.
+    #[serde(with = "hex_set")]
+    pub seen_tx_ids: HashSet<[u8; 32]>,
+    pub seen_tx_rounds: VecDeque<(u64, [u8; 32])>,
+    pub tx_id_retention_rounds: u64,
+    // Pairs of (lower bound, upper bound) of the stake tiers, in ascending order.
+    // Players are only matched with players of the same tier.
+    pub stake_tiers: Vec<(u64, u64)>,
+    // Match id -> what is left of the matches pruned from the history, and
+    // the archived ids, oldest first.
+    pub archive: HashMap<String, ArchivedMatch>,
+    pub archive_order: VecDeque<String>,
+}
+
+// Every configuration field of a game, as logged by `get_config`.
+#[derive(Serialize)]
+pub struct Config<'a> {
+    pub version: &'static str,
+    pub schema_version: u32,
+    #[serde(with = "hex_bytes")]
+    pub owner: [u8; 32],
+    #[serde(with = "hex_option")]
+    pub pending_owner: Option<[u8; 32]>,
+    pub paused: bool,
+    pub shut_down: bool,
+    pub threshold: u32,
+    pub threshold_decay_mode: ThresholdMode,
+    pub matching_strategy: MatchingStrategy,
+    pub reward_schedule: &'a RewardSchedule,
+    pub abstain_fee_bps: u64,
+    pub streak_bonuses: &'a [(u64, u64)],
+    pub jackpot_trigger: u64,
+    pub jackpot_share_bps: u64,
+    pub milestone_bonus_bps: u64,
+    pub min_pot_for_rewards: u64,
+    pub jackpot_pct_bps: u64,
+    #[serde(with = "hex_option")]
+    pub charity: Option<[u8; 32]>,
+    pub charity_share_bps: u64,
+    pub decay_period: u64,
+    pub decay_bps: u64,
+    pub decay_matches: usize,
+    pub referral_bps: u64,
+    pub max_referral_reward: u64,
+    pub loyalty_tiers: &'a [(u64, u64)],
+    pub min_stake: u64,
+    pub history_capacity: usize,
+    pub priority_fee: u64,
+    pub reputation_floor: u64,
+    pub defector_bonus_cap_multiplier: u64,
+    pub defection_penalty_multiplier: u64,
+    pub surplus_threshold: u64,
+    pub surplus_matches: usize,
+    pub pair_retention_rounds: u64,
+    pub multisig_expiry_rounds: u64,
+    pub invariants_enabled: bool,
+    pub max_waiting_pool: usize,
+    pub anonymous: bool,
+    pub commitment_expiry_rounds: u64,
+    pub match_expiry_rounds: u64,
+    pub sweep_limit: usize,
+    pub stake_tiers: &'a [(u64, u64)],
+}
+
+impl GameState {
+    // A fresh game with the default configuration.
+    pub fn new(owner: [u8; 32]) -> Self {
+        Self {
+            owner,
+            pending_owner: None,
+            paused: false,
+            shut_down: false,
+            balances: HashMap::new(),
+            threshold: 50,
+            threshold_decay_mode: ThresholdMode::Linear,
+            matching_strategy: MatchingStrategy::Fifo,
+            pot: 0,
+            reward_schedule: RewardSchedule::default(),
+            abstain_fee_bps: 100,
+            waiting: Vec::new(),
+            history: Vec::new(),
+            history_capacity: DEFAULT_HISTORY_CAPACITY,
+            stats: HashMap::new(),
+            streak_bonuses: vec![(3, 50), (5, 100), (10, 200)],
+            jackpot_trigger: 0,
+            jackpot_share_bps: 5_000,
+            jackpot_armed: false,
+            milestone_bonus_bps: 0,
+            min_pot_for_rewards: 0,
+            jackpot_pct_bps: 0,
+            global_stats: GlobalStats::default(),
+            charity: None,
+            charity_share_bps: 0,
+            round_stats: HashMap::new(),
+            decay_period: 0,
+            decay_bps: 0,
+            decay_matches: 10,
+            last_decay_period: 0,
+            aliases: HashMap::new(),
+            referral_bps: 50,
+            max_referral_reward: 1_000_000,
+            referred: HashSet::new(),
+            achievements: HashMap::new(),
+            loyalty_tiers: vec![(10, 10), (50, 25)],
+            min_stake: 0,
+            priority_fee: 0,
+            reputation_floor: 0,
+            defector_bonus_cap_multiplier: 10,
+            defection_penalty_multiplier: 10_000,
+            surplus_threshold: 1_000_000_000,
+            surplus_matches: 10,
+            pairs: HashMap::new(),
+            pair_retention_rounds: 10_000,
+            last_pair_prune: 0,
+            multisig_requests: Vec::new(),
+            multisig_expiry_rounds: 100,
+            next_multisig_nonce: 0,
+            invariants_enabled: false,
+            total_deposits: 0,
+            total_burned: 0,
+            total_withdrawals: 0,
+            auto_cashout: HashSet::new(),
+            cash_out_delegates: HashMap::new(),
+            max_waiting_pool: 0,
+            anonymous: false,
+            side_bets: Vec::new(),
+            pending_commits: Vec::new(),
+            commitment_expiry_rounds: 100,
+            escrow: HashMap::new(),
+            player_history: HashMap::new(),
+            earnings_index: BTreeMap::new(),
+            awaiting_reveal: Vec::new(),
+            coop_window: VecDeque::with_capacity(COOP_WINDOW_SIZE),
+            coop_window_sum: 0,
+            match_expiry_rounds: 0,
+            sweep_limit: 5,
+            seq: 0,
+            opt_out_random: HashSet::new(),
+            challenge_only_waiting: Vec::new(),
+            seen_tx_ids: HashSet::new(),
+            seen_tx_rounds: VecDeque::new(),
+            tx_id_retention_rounds: 1000,
+            stake_tiers: Vec::new(),
+            archive: HashMap::new(),
+            archive_order: VecDeque::new(),
+        }
+    }
+
+    // Pruned matches are archived, and the archive drops its oldest matches in turn.
+    pub fn prune_old_history(&mut self) {
+        while self.history.len() > self.history_capacity {
+            let m = self.history.remove(0);
+            self.archive.insert(m.id.clone(), m.archive());
+            self.archive_order.push_back(m.id);
+
+            if self.archive_order.len() > MAX_ARCHIVE_CAPACITY {
+                if let Some(oldest) = self.archive_order.pop_front() {
+                    self.archive.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    pub fn config(&self) -> Config<'_> {
+        Config {
+            version: env!("CARGO_PKG_VERSION"),
+            schema_version: SCHEMA_VERSION,
+            owner: self.owner,
+            pending_owner: self.pending_owner,
+            paused: self.paused,
+            shut_down: self.shut_down,
+            threshold: self.threshold,
+            threshold_decay_mode: self.threshold_decay_mode,
+            matching_strategy: self.matching_strategy,
+            reward_schedule: &self.reward_schedule,
+            abstain_fee_bps: self.abstain_fee_bps,
+            streak_bonuses: &self.streak_bonuses,
+            jackpot_trigger: self.jackpot_trigger,
+            jackpot_share_bps: self.jackpot_share_bps,
+            milestone_bonus_bps: self.milestone_bonus_bps,
+            min_pot_for_rewards: self.min_pot_for_rewards,
+            jackpot_pct_bps: self.jackpot_pct_bps,
+            charity: self.charity,
+            charity_share_bps: self.charity_share_bps,
+            decay_period: self.decay_period,
+            decay_bps: self.decay_bps,
+            decay_matches: self.decay_matches,
+            referral_bps: self.referral_bps,
+            max_referral_reward: self.max_referral_reward,
+            loyalty_tiers: &self.loyalty_tiers,
+            min_stake: self.min_stake,
+            history_capacity: self.history_capacity,
+            priority_fee: self.priority_fee,
+            reputation_floor: self.reputation_floor,
+            defector_bonus_cap_multiplier: self.defector_bonus_cap_multiplier,
+            defection_penalty_multiplier: self.defection_penalty_multiplier,
+            surplus_threshold: self.surplus_threshold,
+            surplus_matches: self.surplus_matches,
+            pair_retention_rounds: self.pair_retention_rounds,
+            multisig_expiry_rounds: self.multisig_expiry_rounds,
+            invariants_enabled: self.invariants_enabled,
+            max_waiting_pool: self.max_waiting_pool,
+            anonymous: self.anonymous,
+            commitment_expiry_rounds: self.commitment_expiry_rounds,
+            match_expiry_rounds: self.match_expiry_rounds,
+            sweep_limit: self.sweep_limit,
+            stake_tiers: &self.stake_tiers,
+        }
+    }
+}
+
+impl GameState {
+    // The minimum stake is at least 1, whether it is set at init or later.
+    pub fn check_min_stake(min_stake: u64) -> Result<(), ContractError> {
+        if min_stake == 0 {
+            return Err(ContractError::InvalidParameter("The minimum stake must be at least 1.".to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Sets the tunable parameter with the given name, returning its old value.
+    pub fn set_param(&mut self, name: &str, value: u64) -> Result<u64, Box<dyn Error>> {
+        let old = match name {
+            "threshold" => {
+                if value > 100 {
+                    return Err(ContractError::InvalidParameter("The threshold must be at most 100.".to_string()).into());
+                }
+
+                std::mem::replace(&mut self.threshold, value as u32) as u64
+            }
+            "cooperate_bps" => {
+                RewardSchedule::check_bps(value, self.reward_schedule.defect_bps)?;
+
+                std::mem::replace(&mut self.reward_schedule.cooperate_bps, value)
+            }
+            "defect_bps" => {
+                RewardSchedule::check_bps(self.reward_schedule.cooperate_bps, value)?;
+
+                std::mem::replace(&mut self.reward_schedule.defect_bps, value)
+            }
+            "min_stake" => {
+                Self::check_min_stake(value)?;
+
+                std::mem::replace(&mut self.min_stake, value)
+            }
+            "max_waiting_pool" => std::mem::replace(&mut self.max_waiting_pool, value as usize) as u64,
+            "match_expiry_rounds" => std::mem::replace(&mut self.match_expiry_rounds, value),
+            "min_pot_for_rewards" => std::mem::replace(&mut self.min_pot_for_rewards, value),
+            "commitment_expiry_rounds" => {
+                if value == 0 {
+                    return Err(ContractError::InvalidParameter("The commitment expiry must be at least 1 round.".to_string()).into());
+                }
+
+                std::mem::replace(&mut self.commitment_expiry_rounds, value)
+            }
+            "history_capacity" => {
+                if value == 0 {
+                    return Err(ContractError::InvalidParameter("The history capacity must be at least 1.".to_string()).into());
+                }
+
+                // A lowered capacity archives the excess history right away
+                let old = std::mem::replace(&mut self.history_capacity, value as usize) as u64;
+                self.prune_old_history();
+
+                old
+            }
+            _ => return Err(ContractError::InvalidParameter(format!("Unknown parameter {}.", name)).into()),
+        };
+
+        Ok(old)
+    }
+
+    // Takes the player's stake, which still includes the insurance premium,
+    // and enters the player into a match.
+    pub fn play_stake(&mut self, mut p: Player, env: &Env, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        let amount = p.stake;
+        let tx_id = p.tx_id;
+
+        self.total_deposits += amount;
+
+        self.decay_pot(env, effects);
+        self.expire_commitments(env, effects);
+        self.forfeit_unrevealed_matches(env, effects);
+        self.sweep_expired_matches(env, effects);
+
+        // The insurance premium goes into the pot right away
+        if p.insured {
+            p.insurance_premium = bps_of(amount, INSURANCE_PREMIUM_PCT * 100);
+            p.stake -= p.insurance_premium;
+            self.pot += p.insurance_premium;
+            self.global_stats.pot_inflow += p.insurance_premium;
+        }
+
+        self.enter(p, env, effects)?;
+
+        self.record_tx(tx_id, env.round_idx);
+
+        Ok(())
+    }
+
+    // Remembers a transaction, so that it can not be played again.
+    pub fn record_tx(&mut self, tx_id: [u8; 32], round: u64) {
+        if self.seen_tx_ids.insert(tx_id) {
+            self.seen_tx_rounds.push_back((round, tx_id));
+        }
+    }
+
+    // Puts the amount attached to a rejected call into the sender's balance,
+    // from where it can be cashed out. The transaction is remembered, so a
+    // replay of it can't be refunded again.
+    pub fn refund_attached(&mut self, env: &Env) {
+        if env.amount == 0 || self.seen_tx_ids.contains(&env.tx_id) {
+            return;
+        }
+
+        self.total_deposits += env.amount;
+        update_balance(&mut self.balances, env.sender, env.amount as i128);
+        self.record_tx(env.tx_id, env.round_idx);
+    }
+
+    // While paused nothing new can be staked. The attached stake is refunded.
+    pub fn reject_if_paused(&mut self, env: &Env) -> Result<(), ContractError> {
+        if !self.paused {
+            return Ok(());
+        }
+
+        self.refund_attached(env);
+
+        Err(ContractError::Paused)
+    }
+
+    pub fn only_owner(&self, sender: [u8; 32]) -> Result<(), ContractError> {
+        if sender != self.owner {
+            return Err(ContractError::Unauthorized);
+        }
+
+        Ok(())
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.balances.is_empty() && self.pot == 0 && self.waiting.is_empty() && self.history.is_empty() && self.stats.is_empty()
+    }
+
+    // Match ids are the first 16 hex characters of the id of the transaction
+    // that created the match, with a suffix in the unlikely case that another
+    // match already has the id.
+    pub fn generate_id(&self, tx_id: [u8; 32]) -> String {
+        let base = to_hex_string(tx_id)[..16].to_string();
+
+        let mut id = base.clone();
+        let mut suffix = 1;
+        while self.has_match(&id) {
+            suffix += 1;
+            id = format!("{}-{}", base, suffix);
+        }
+
+        id
+    }
+
+    pub fn has_match(&self, id: &str) -> bool {
+        self.waiting.iter()
+            .chain(self.challenge_only_waiting.iter())
+            .chain(self.awaiting_reveal.iter())
+            .chain(self.history.iter())
+            .any(|m| m.id == id)
+            || self.archive.contains_key(id)
+    }
+
+    // The index of a waiting match created by the sender.
+    pub fn find_own_waiting_match(&self, id: &str, sender: [u8; 32]) -> Result<usize, Box<dyn Error>> {
+        let index = match self.waiting.iter().position(|m| m.id == id) {
+            Some(index) => index,
+            None => {
+                if self.history.iter().any(|m| m.id == id) || self.archive.contains_key(id) {
+                    return Err(ContractError::MatchAlreadyPlayed.into());
+                }
+
+                return Err(ContractError::MatchNotFound.into());
+            }
+        };
+
+        if self.waiting[index].p1.sender != sender {
+            return Err(ContractError::NotYourMatch.into());
+        }
+
+        Ok(index)
+    }
+
+    // Creates a new match for the player and puts it into the waiting pool.
+    // Players who can afford the priority fee get in front of the queue.
+    pub fn wait_for_opponent(&mut self, mut player: Player, env: &Env, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        // Both pools count towards the limit
+        let queued = self.waiting.len() + self.challenge_only_waiting.len();
+
+        if self.max_waiting_pool > 0 && queued >= self.max_waiting_pool {
+            self.refund_stake(player.sender, player.stake + player.insurance_premium, player.insurance_premium);
+            self.record_tx(player.tx_id, env.round_idx);
+
+            return Err(ContractError::WaitingPoolFull.into());
+        }
+
+        let priority = self.priority_fee > 0 && player.stake >= self.priority_fee + self.min_stake;
+
+        if priority {
+            player.stake -= self.priority_fee;
+            self.pot += self.priority_fee;
+            self.global_stats.pot_inflow += self.priority_fee;
+        }
+
+        let id = self.generate_id(player.tx_id);
+        let stake = player.stake;
+        let creator = self.log_address(env, player.sender, env.round_idx);
+
+        // The stake stays in escrow until the match is settled or cancelled
+        self.escrow.insert(id.clone(), (player.stake, 0));
+
+        let challenge_only = self.opt_out_random.contains(&player.sender);
+
+        let mut m = Match::new(id.clone(), player, env.round_idx);
+        m.priority = priority;
+
+        if challenge_only {
+            self.challenge_only_waiting.push(m);
+        } else {
+            self.waiting.push(m);
+
+            // Priority matches come first, then the oldest ones. The sort is
+            // stable, so matches queued in the same round keep their insertion order.
+            self.waiting.sort_by_key(|m| (!m.priority, m.queued_at));
+        }
+
+        self.emit(effects, Event::MatchCreated(MatchCreated {
+            schema_version: SCHEMA_VERSION,
+            match_id: id,
+            stake,
+            creator,
+            priority_fee: if priority { Some(self.priority_fee) } else { None },
+        }));
+
+        Ok(())
+    }
+
+    // How an address shows up in the logs. In anonymous mode players are only
+    // shown by a pseudonym that changes every round.
+    pub fn log_address(&self, env: &Env, addr: [u8; 32], round: u64) -> String {
+        if self.anonymous {
+            return anonymize_address(env.sha256, addr, round.to_le_bytes());
+        }
+
+        to_hex_string(addr)
+    }
+
+    // The aliases to show next to the addresses. An alias would give away
+    // who an anonymized address belongs to, so none are shown in anonymous mode.
+    pub fn shown_aliases(&self) -> Option<&HashMap<[u8; 32], String>> {
+        if self.anonymous {
+            return None;
+        }
+
+        Some(&self.aliases)
+    }
+
+    // Gives a player whose play failed the stake back, including the insurance
+    // premium that already went into the pot.
+    pub fn refund_stake(&mut self, sender: [u8; 32], amount: u64, insurance_premium: u64) {
+        self.pot -= insurance_premium;
+        self.global_stats.pot_inflow -= insurance_premium;
+        update_balance(&mut self.balances, sender, amount as i128);
+
+        // The refunded stake was never played
+        if let Some(stats) = self.stats.get_mut(&sender) {
+            stats.lifetime_staked = stats.lifetime_staked.saturating_sub(amount - insurance_premium);
+        }
+        self.global_stats.total_staked = self.global_stats.total_staked.saturating_sub(amount - insurance_premium);
+    }
+
+    // Forgets the transactions played before the retention period.
+    pub fn evict_seen_tx_ids(&mut self, round: u64) {
+        while let Some((played_round, tx_id)) = self.seen_tx_rounds.front().copied() {
+            if played_round + self.tx_id_retention_rounds >= round {
+                break;
+            }
+
+            self.seen_tx_rounds.pop_front();
+            self.seen_tx_ids.remove(&tx_id);
+        }
+    }
+
+    // Counts a stake that went into a match.
+    pub fn record_stake(&mut self, sender: [u8; 32], stake: u64) {
+        let stats = self.stats.entry(sender).or_default();
+        stats.lifetime_staked = stats.lifetime_staked.saturating_add(stake);
+        stats.reputation = stats.compute_reputation();
+
+        self.global_stats.total_staked = self.global_stats.total_staked.saturating_add(stake);
+    }
+
+    // Pays the correct side bets on a resolved match twice their stake, as far
+    // as the pot allows, and puts the lost stakes into the pot. Bets are
+    // refunded when someone abstained, as no prediction can be right.
+    pub fn settle_side_bets(&mut self, env: &Env, match_id: &str, p1_vote: u8, p2_vote: u8, round: u64) -> Vec<SettledSideBet> {
+        let outcome = side_bet_outcome(p1_vote, p2_vote);
+
+        let (bets, remaining): (Vec<SideBet>, Vec<SideBet>) = self.side_bets.drain(..).partition(|b| b.match_id == match_id);
+        self.side_bets = remaining;
+
+        let mut settled = Vec::new();
+        for bet in bets {
+            let payout = match outcome {
+                None => bet.stake,
+                Some(outcome) if outcome == bet.predicted_outcome => {
+                    let winnings = bet.stake.min(self.pot);
+                    self.pot -= winnings;
+                    self.global_stats.pot_outflow += winnings;
+
+                    bet.stake + winnings
+                }
+                Some(_) => {
+                    self.pot += bet.stake;
+                    self.global_stats.pot_inflow += bet.stake;
+
+                    0
+                }
+            };
+
+            if payout > 0 {
+                update_balance(&mut self.balances, bet.bettor, payout as i128);
+            }
+
+            settled.push(SettledSideBet {
+                bettor: self.log_address(env, bet.bettor, round),
+                predicted_outcome: bet.predicted_outcome,
+                stake: bet.stake,
+                payout,
+            });
+        }
+
+        settled
+    }
+
+    // Gives the stakes of the side bets on a match that will not be played back.
+    pub fn refund_side_bets(&mut self, match_id: &str) {
+        let (bets, remaining): (Vec<SideBet>, Vec<SideBet>) = self.side_bets.drain(..).partition(|b| b.match_id == match_id);
+        self.side_bets = remaining;
+
+        for bet in bets {
+            update_balance(&mut self.balances, bet.bettor, bet.stake as i128);
+        }
+    }
+
+    // Counts the meeting of the two players and returns how often they played together.
+    pub fn record_pair(&mut self, a: [u8; 32], b: [u8; 32], round: u64) -> u32 {
+        // Once per retention period, forget the pairs that were not seen in the last one.
+        let retention = self.pair_retention_rounds.max(1);
+        if round / retention > self.last_pair_prune {
+            self.last_pair_prune = round / retention;
+            self.pairs.retain(|_, record| record.last_seen_round + retention >= round);
+        }
+
+        let record = self.pairs.entry(pair_key(a, b)).or_default();
+        record.times_played += 1;
+        record.last_seen_round = round;
+
+        record.times_played
+    }
+
+    // Verifies that no funds appeared or vanished, and that the match lists are
+    // consistent. Does nothing unless the checks were enabled at init.
+    // Every PERL deposited and not withdrawn has to be held by the pot, a balance,
+    // the escrow, a side bet or a pending commitment.
+    pub fn verify_conservation(&self) -> Result<(), Box<dyn Error>> {
+        let balances: u128 = self.balances.values().map(|b| *b as u128).sum();
+        let locked: u128 = self.escrow.values().map(|(p1_stake, p2_stake)| *p1_stake as u128 + *p2_stake as u128).sum::<u128>()
+            + self.side_bets.iter().map(|b| b.stake as u128).sum::<u128>()
+            + self.pending_commits.iter().map(|c| c.stake as u128).sum::<u128>();
+        let held = self.pot as u128 + balances + locked;
+        let expected = self.total_deposits as u128 - self.total_withdrawals as u128 - self.total_burned as u128;
+        if held != expected {
+            return Err(format!("Invariant broken: pot, balances, escrow, side bets and commitments hold {} PERLs, but {} were deposited and not withdrawn.", held, expected).into());
+        }
+
+        Ok(())
+    }
+
+    pub fn check_invariants(&self) -> Result<(), Box<dyn Error>> {
+        if !self.invariants_enabled {
+            return Ok(());
+        }
+
+        self.verify_conservation()?;
+
+        let escrow_matches = self.escrow.len() == self.waiting.len() + self.challenge_only_waiting.len() + self.awaiting_reveal.len()
+            && self.waiting.iter().chain(self.challenge_only_waiting.iter()).all(|m| self.escrow.get(&m.id) == Some(&(m.p1.stake, 0)))
+            && self.awaiting_reveal.iter().all(|m| self.escrow.get(&m.id) == Some(&(m.p1.stake, m.p2.as_ref().unwrap().stake)));
+        if !escrow_matches {
+            return Err("Invariant broken: the escrow does not match the waiting stakes.".into());
+        }
+
+        if self.waiting.iter().chain(self.challenge_only_waiting.iter()).any(|m| m.p2.is_some()) {
+            return Err("Invariant broken: a waiting match already has a second player.".into());
+        }
+
+        if self.history.len() > self.history_capacity {
+            return Err("Invariant broken: the history exceeds its capacity.".into());
+        }
+
+        Ok(())
+    }
+
+    // Sends the stakes of the commitments that were not revealed in time back.
+    pub fn expire_commitments(&mut self, env: &Env, effects: &mut Effects) {
+        let round = env.round_idx;
+        let expiry = self.commitment_expiry_rounds;
+        let (expired, pending): (Vec<PendingCommit>, Vec<PendingCommit>) = self.pending_commits.drain(..).partition(|c| c.committed_round + expiry < round);
+        self.pending_commits = pending;
+
+        for commit in expired {
+            effects.transfer(commit.sender, commit.stake);
+
+            self.total_withdrawals += commit.stake;
+
+            let sender = self.log_address(env, commit.sender, env.round_idx);
+            self.emit(effects, Event::CommitmentExpired(CommitmentExpired {
+                schema_version: SCHEMA_VERSION,
+                sender,
+                refund: commit.stake,
+            }));
+        }
+    }
+
+    // Player 1 forfeits a blinded match that was not revealed in time, and
+    // player 2 gets both stakes.
+    pub fn forfeit_unrevealed_matches(&mut self, env: &Env, effects: &mut Effects) {
+        let round = env.round_idx;
+        let expiry = self.commitment_expiry_rounds;
+        let (expired, pending): (Vec<Match>, Vec<Match>) = self.awaiting_reveal.drain(..).partition(|m| m.joined_round + expiry < round);
+        self.awaiting_reveal = pending;
+
+        for m in expired {
+            let p2 = m.p2.as_ref().unwrap();
+            self.escrow.remove(&m.id);
+            update_balance(&mut self.balances, p2.sender, m.p1.stake as i128 + p2.stake as i128);
+
+            // The outcome is never known, so the side bets are called off and
+            // only the resolution itself is counted
+            self.refund_side_bets(&m.id);
+            self.global_stats.record_resolution(m.joined_round.saturating_sub(m.created_round));
+
+            self.emit(effects, Event::MatchForfeited(MatchForfeited {
+                schema_version: SCHEMA_VERSION,
+                match_id: m.id.clone(),
+                payout: m.p1.stake + p2.stake,
+            }));
+        }
+    }
+
+    // Logs an event as JSON, with the next sequence number.
+    pub fn emit(&mut self, effects: &mut Effects, event: Event) {
+        self.seq += 1;
+
+        let envelope = Envelope {
+            seq: self.seq,
+            event,
+        };
+        effects.log(&serde_json::to_string(&envelope).unwrap());
+    }
+
+    // Logs the change of a configuration value.
+    pub fn emit_config(&mut self, effects: &mut Effects, name: &str, old: u64, new: u64) {
+        self.emit(effects, Event::ConfigChanged(ConfigChanged {
+            schema_version: SCHEMA_VERSION,
+            name: name.to_string(),
+            old,
+            new,
+        }));
+    }
+
+    // Logs the response to a query as JSON. Queries do not change the state,
+    // so the response carries the sequence number of the last event.
+    pub fn emit_query(&self, effects: &mut Effects, event: Event) {
+        let envelope = Envelope {
+            seq: self.seq,
+            event,
+        };
+        effects.log(&serde_json::to_string(&envelope).unwrap());
+    }
+
+    // Refunds the oldest waiting matches that expired. Only up to `sweep_limit`
+    // matches are checked, so the cost of the sweep is spread over many plays.
+    pub fn sweep_expired_matches(&mut self, env: &Env, effects: &mut Effects) {
+        let round = env.round_idx;
+        if self.match_expiry_rounds == 0 {
+            return;
+        }
+
+        // The pool is ordered by priority and then by age, so the oldest
+        // matches are at the front of the priority and of the regular matches.
+        let len = self.waiting.len();
+        let first_regular = self.waiting.iter().position(|m| !m.priority).unwrap_or(len);
+        let (mut i, mut j) = (0, first_regular);
+
+        let mut expired = Vec::new();
+        while expired.len() < self.sweep_limit {
+            let next = if i < first_regular && (j >= len || self.waiting[i].queued_at <= self.waiting[j].queued_at) {
+                i += 1;
+                i - 1
+            } else if j < len {
+                j += 1;
+                j - 1
+            } else {
+                break;
+            };
+
+            // Every other match is younger
+            if self.waiting[next].queued_at + self.match_expiry_rounds >= round {
+                break;
+            }
+
+            expired.push(next);
+        }
+
+        expired.sort_unstable_by(|a, b| b.cmp(a));
+        let mut expired: Vec<Match> = expired.into_iter().map(|index| self.waiting.remove(index)).collect();
+
+        // Challenge-only matches are queued in order, the oldest one first
+        while expired.len() < self.sweep_limit
+            && self.challenge_only_waiting.first().is_some_and(|m| m.queued_at + self.match_expiry_rounds < round)
+        {
+            expired.push(self.challenge_only_waiting.remove(0));
+        }
+
+        for m in expired {
+            self.escrow.remove(&m.id);
+
+            update_balance(&mut self.balances, m.p1.sender, m.p1.stake as i128);
+            self.refund_side_bets(&m.id);
+
+            self.emit(effects, Event::MatchExpired(MatchExpired {
+                schema_version: SCHEMA_VERSION,
+                match_id: m.id,
+                refund: m.p1.stake,
+            }));
+        }
+    }
+
+    pub fn check_nash_equilibrium(&mut self, effects: &mut Effects) {
+        if !self.global_stats.matches_resolved.is_multiple_of(NASH_CHECK_INTERVAL) || self.coop_window.len() < COOP_WINDOW_SIZE {
+            return;
+        }
+
+        let coop_rate = self.coop_rate();
+        if coop_rate <= NASH_EQUILIBRIUM_COOP_RATE as u32 + NASH_TOLERANCE_PCT {
+            let rounds_observed = self.coop_window.len() as u64;
+            self.emit(effects, Event::NashEquilibriumApproached(NashEquilibriumApproached {
+                schema_version: SCHEMA_VERSION,
+                coop_rate,
+                rounds_observed,
+            }));
+        }
+    }
+
+    // Adds a vote to the cooperation window, evicting the oldest one once it is full.
+    pub fn record_coop(&mut self, cooperated: bool) {
+        if self.coop_window.len() == COOP_WINDOW_SIZE {
+            if let Some(true) = self.coop_window.pop_front() {
+                self.coop_window_sum -= 1;
+            }
+        }
+
+        self.coop_window.push_back(cooperated);
+        if cooperated {
+            self.coop_window_sum += 1;
+        }
+    }
+
+    // The percentage of cooperations in the window, 50 while there are no votes yet.
+    pub fn coop_rate(&self) -> u32 {
+        if self.coop_window.is_empty() {
+            return 50;
+        }
+
+        self.coop_window_sum * 100 / self.coop_window.len() as u32
+    }
+
+    pub fn prune_expired_multisig_requests(&mut self, round: u64) {
+        let expiry = self.multisig_expiry_rounds;
+        self.multisig_requests.retain(|r| r.created_round + expiry >= round);
+    }
+
+    // The distinct players of the last resolved matches, most recent first.
+    pub fn recent_players(&self, matches: usize) -> Vec<[u8; 32]> {
+        let mut players: Vec<[u8; 32]> = Vec::new();
+
+        for m in self.history.iter().rev().take(matches) {
+            for sender in &[m.p1.sender, m.p2.as_ref().unwrap().sender] {
+                if !players.contains(sender) {
+                    players.push(*sender);
+                }
+            }
+        }
+
+        players
+    }
+
+    // Shares a part of the pot among the recent players, at most once per decay period.
+    pub fn decay_pot(&mut self, env: &Env, effects: &mut Effects) {
+        let round = env.round_idx;
+        if self.decay_period == 0 || self.decay_bps == 0 {
+            return;
+        }
+
+        let period = round / self.decay_period;
+        if period <= self.last_decay_period {
+            return;
+        }
+        self.last_decay_period = period;
+
+        let recipients = self.recent_players(self.decay_matches);
+        if recipients.is_empty() {
+            return;
+        }
+
+        let decayed = bps_of(self.pot, self.decay_bps);
+        let (share, dust) = split_amount(decayed, recipients.len() as u64);
+        if share == 0 {
+            return;
+        }
+
+        for recipient in &recipients {
+            update_balance(&mut self.balances, *recipient, share as i128);
+        }
+
+        // The dust goes back into the pot
+        self.pot -= decayed - dust;
+        self.global_stats.pot_outflow += decayed - dust;
+
+        let recipients = recipients.iter().map(|r| self.log_address(env, *r, round)).collect();
+        self.emit(effects, Event::PotDecayed(PotDecayed {
+            schema_version: SCHEMA_VERSION,
+            round,
+            recipients,
+            amount: share,
+            pot: self.pot,
+        }));
+    }
+
+    // Puts a player whose stake has been taken in into a match, resolving
+    // the match if the player got paired with a waiting one.
+    pub fn enter(&mut self, p: Player, env: &Env, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        let sender = p.sender;
+
+        self.record_stake(sender, p.stake);
+        if let Some(stats) = self.stats.get_mut(&sender) {
+            stats.strategy_id = p.strategy_id;
+        }
+
+        // A blinded vote is only known once it is revealed, so the player has to
+        // wait. So do players who only play when challenged.
+        if p.blinded_vote.is_some() || self.opt_out_random.contains(&sender) {
+            self.wait_for_opponent(p, env, effects)?;
+
+            return self.check_invariants();
+        }
+
+        if random(env) > self.threshold {
+            // Create a new match for the player and put the match into the waiting pool
+
+            self.threshold = self.threshold_decay_mode.raise(self.threshold);
+
+            self.wait_for_opponent(p, env, effects)?;
+
+            return self.check_invariants();
+        }
+
+        self.threshold = self.threshold_decay_mode.lower(self.threshold);
+
+        // Put the player with the first match in the waiting pool.
+        // If there's no match in the waiting pool, create a new match for the player.
+        // Players below the reputation floor only get matched with each other.
+        let floor = self.reputation_floor;
+        let low_reputation = reputation_of(&self.stats, &sender) < floor;
+        let stats = &self.stats;
+        let opt_out_random = &self.opt_out_random;
+        let tiers = &self.stake_tiers;
+        let tier = stake_tier(tiers, p.stake);
+
+        let eligible: Vec<(usize, u64)> = self.waiting.iter().enumerate()
+            .filter(|(_, m)| {
+                m.p1.sender != sender
+                    && !opt_out_random.contains(&m.p1.sender)
+                    && stake_tier(tiers, m.p1.stake) == tier
+                    && (reputation_of(stats, &m.p1.sender) < floor) == low_reputation
+            })
+            .map(|(i, m)| (i, m.p1.stake))
+            .collect();
+
+        // The draw mixes in the oldest eligible match, so the joining player
+        // alone can't pick the match to join
+        let draw = eligible.first().map_or(0, |(i, _)| random_from(entropy_for_match(&self.waiting[*i], env)));
+
+        let index = match self.matching_strategy.select(&eligible, p.stake, draw) {
+            Some(v) => v,
+            None => {
+                self.wait_for_opponent(p, env, effects)?;
+
+                return self.check_invariants();
+            }
+        };
+
+        self.join(index, p, env, effects)
+    }
+
+    // Attaches the player as the second player of the waiting match at the index.
+    pub fn join(&mut self, index: usize, p: Player, env: &Env, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        let sender = p.sender;
+
+        // The match is resolved once player 1 revealed the blinded vote
+        if self.waiting[index].p1.blinded_vote.is_some() {
+            let mut m = self.waiting.remove(index);
+            self.escrow.insert(m.id.clone(), (m.p1.stake, p.stake));
+
+            let player_2 = self.log_address(env, sender, env.round_idx);
+            self.emit(effects, Event::MatchJoined(MatchJoined {
+                schema_version: SCHEMA_VERSION,
+                match_id: m.id.clone(),
+                player_2,
+                awaiting_reveal: true,
+            }));
+
+            m.joined_round = env.round_idx;
+            m.p2 = Some(p);
+            self.awaiting_reveal.push(m);
+
+            return self.check_invariants();
+        }
+
+        let player_2 = self.log_address(env, sender, env.round_idx);
+        self.emit(effects, Event::MatchJoined(MatchJoined {
+            schema_version: SCHEMA_VERSION,
+            match_id: self.waiting[index].id.clone(),
+            player_2,
+            awaiting_reveal: false,
+        }));
+
+        self.settle(index, p, env, effects)
+    }
+
+    // Resolves the waiting match at the index against the joining player.
+    pub fn settle(&mut self, index: usize, p: Player, env: &Env, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        let sender = p.sender;
+        let insurance_premium = p.insurance_premium;
+        let amount = p.stake + insurance_premium;
+
+        let opponent = self.waiting[index].p1.sender;
+        let times_played_together = self.record_pair(opponent, sender, env.round_idx);
+
+        let stats = &self.stats;
+        let is_repeat_defector = |sender| stats.get(sender).map(|s: &PlayerStats| s.is_repeat_defector()).unwrap_or(false);
+
+        let rules = PayoutRules {
+            schedule: self.reward_schedule,
+            abstain_fee_bps: self.abstain_fee_bps,
+            jackpot_share_bps: if self.jackpot_armed { Some(self.jackpot_share_bps) } else { None },
+            charity_share_bps: if self.charity.is_some() { self.charity_share_bps } else { 0 },
+            defector_bonus_cap_multiplier: self.defector_bonus_cap_multiplier,
+            defection_penalty_multiplier: self.defection_penalty_multiplier,
+            repeat_defectors: (is_repeat_defector(&opponent), is_repeat_defector(&sender)),
+            min_pot_for_rewards: self.min_pot_for_rewards,
+        };
+        let pot_before = self.pot;
+        let resolution = match resolve_match(&mut self.waiting[index], p, self.pot, &rules, self.charity, env.round_idx) {
+            Ok(resolution) => resolution,
+            Err(err) => {
+                // The match can't be played, so it is unwound and both
+                // players get their stakes back
+                let m = self.waiting.remove(index);
+                self.escrow.remove(&m.id);
+                self.refund_stake(m.p1.sender, m.p1.stake, 0);
+                self.refund_side_bets(&m.id);
+                self.refund_stake(sender, amount, insurance_premium);
+
+                return Err(err);
+            }
+        };
+
+        // Remove the match from the waiting list
+        let mut m = self.waiting.remove(index);
+        m.resolved_round_id = env.round_id;
+
+        // Both stakes are settled by the payouts, so the match leaves the escrow
+        let escrow = self.escrow.remove(&m.id).map(|(p1_stake, _)| (p1_stake, m.p2.as_ref().unwrap().stake));
+
+        self.global_stats.record_resolution(m.wait_duration());
+        self.global_stats.record_outcome(m.p1.vote, m.p2.as_ref().unwrap().vote);
+
+        let round_stats = self.round_stats.entry(env.round_idx).or_insert((0, 0));
+        for vote in &[m.p1.vote, m.p2.as_ref().unwrap().vote] {
+            match vote {
+                1 => round_stats.0 += 1,
+                2 => round_stats.1 += 1,
+                _ => {}
+            }
+        }
+        for vote in &[m.p1.vote, m.p2.as_ref().unwrap().vote] {
+            match vote {
+                1 => self.record_coop(true),
+                2 => self.record_coop(false),
+                _ => {}
+            }
+        }
+
+        // A claimed jackpot has to be armed again by the pot
+        if m.jackpot_payout > 0 {
+            self.jackpot_armed = false;
+        }
+
+        // Player 2 is put back into the match once the match has been updated
+        let p2 = m.p2.take().unwrap();
+
+        // Update the players' balances
+
+        let mut paid_out = Vec::new();
+        for (recipient, credit) in &resolution.credits {
+            if self.auto_cashout.contains(recipient) && *credit > 0 {
+                effects.transfer(*recipient, *credit);
+
+                self.total_withdrawals += *credit;
+                self.global_stats.total_cashed_out += *credit;
+                paid_out.push(*recipient);
+            } else {
+                update_balance(&mut self.balances, *recipient, *credit as i128);
+            }
+        }
+
+        // The loyalty tiers are based on the matches played before this one
+
+        let stats = &self.stats;
+        let matches_played = |sender| stats.get(sender).map(|s: &PlayerStats| s.matches_played).unwrap_or(0);
+        let (_, p1_loyalty_bps) = loyalty_tier(&self.loyalty_tiers, matches_played(&m.p1.sender));
+        let (_, p2_loyalty_bps) = loyalty_tier(&self.loyalty_tiers, matches_played(&p2.sender));
+
+        // Update the players' stats
+
+        update_stats(&mut self.stats, &mut self.earnings_index, &p2, m.p1.vote, m.p2_payout);
+        update_stats(&mut self.stats, &mut self.earnings_index, &m.p1, p2.vote, m.p1_payout);
+
+        // Award the badges
+
+        let won_jackpot = m.jackpot_payout > 0;
+        update_achievements(&mut self.achievements, &self.stats, m.p1.sender, won_jackpot);
+        update_achievements(&mut self.achievements, &self.stats, p2.sender, won_jackpot);
+
+        // Update the pot.
+
+        self.global_stats.record_pot_change(resolution.pot as i128 - self.pot as i128);
+        self.pot = resolution.pot;
+
+        // Repeat defectors lose more than their stake in a mutual defection
+
+        let p1_penalty = charge_defection_penalty(rules.defection_penalty_multiplier, &mut self.pot, &mut self.balances, &m.p1, p2.vote, rules.repeat_defectors.0);
+        let p2_penalty = charge_defection_penalty(rules.defection_penalty_multiplier, &mut self.pot, &mut self.balances, &p2, m.p1.vote, rules.repeat_defectors.1);
+        self.global_stats.pot_inflow += p1_penalty + p2_penalty;
+
+        if self.jackpot_trigger > 0 && self.pot > self.jackpot_trigger {
+            self.jackpot_armed = true;
+        }
+
+        let pot_before_bonuses = self.pot;
+
+        // Below the minimum pot the bonuses are skipped like the pot rewards.
+        // Milestones and referrals are still used up, so they pay nothing later.
+        let rewards = pot_before >= self.min_pot_for_rewards;
+        let bonus_bps = |bps: u64| if rewards { bps } else { 0 };
+        let streak_bonuses: &[(u64, u64)] = if rewards { &self.streak_bonuses } else { &[] };
+
+        // Pay the streak bonuses out of the remaining pot
+
+        let p1_streak_bonus = pay_streak_bonus(streak_bonuses, &mut self.pot, &mut self.balances, &self.stats, m.p1.sender);
+        let p2_streak_bonus = pay_streak_bonus(streak_bonuses, &mut self.pot, &mut self.balances, &self.stats, p2.sender);
+
+        // Refund the insured players who were betrayed
+
+        let p1_insurance_refund = pay_insurance(&mut self.pot, &mut self.balances, &m.p1, p2.vote);
+        let p2_insurance_refund = pay_insurance(&mut self.pot, &mut self.balances, &p2, m.p1.vote);
+
+        // Pay the loyalty rewards out of the remaining pot
+
+        let p1_loyalty_bonus = pay_pot_bonus(bonus_bps(p1_loyalty_bps), &mut self.pot, &mut self.balances, m.p1.sender);
+        let p2_loyalty_bonus = pay_pot_bonus(bonus_bps(p2_loyalty_bps), &mut self.pot, &mut self.balances, p2.sender);
+
+        // Pay the referrers of players who just finished their first match
+
+        let p1_referral = pay_referral(bonus_bps(self.referral_bps), self.max_referral_reward, &mut self.pot, &mut self.balances, &mut self.referred, &self.stats, &m.p1);
+        let p2_referral = pay_referral(bonus_bps(self.referral_bps), self.max_referral_reward, &mut self.pot, &mut self.balances, &mut self.referred, &self.stats, &p2);
+
+        // Pay the cooperation jackpots
+
+        let p1_coop_jackpot = pay_coop_jackpot(bonus_bps(self.jackpot_pct_bps), &mut self.pot, &mut self.balances, &mut self.stats, m.p1.sender);
+        let p2_coop_jackpot = pay_coop_jackpot(bonus_bps(self.jackpot_pct_bps), &mut self.pot, &mut self.balances, &mut self.stats, p2.sender);
+
+        // Pay the players who just reached a match count milestone
+
+        let p1_milestone = check_milestones(bonus_bps(self.milestone_bonus_bps), &mut self.pot, &mut self.balances, &mut self.stats, &m.p1);
+        let p2_milestone = check_milestones(bonus_bps(self.milestone_bonus_bps), &mut self.pot, &mut self.balances, &mut self.stats, &p2);
+
+        self.global_stats.pot_outflow += pot_before_bonuses - self.pot;
+
+        let side_bets = self.settle_side_bets(env, &m.id, m.p1.vote, p2.vote, env.round_idx);
+
+        m.pot_after = self.pot;
+        m.pot_change = (self.pot as i128 - pot_before as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+
+        m.p2 = Some(p2);
+        let p2 = m.p2.as_ref().unwrap();
+
+        // Generate the match result
+
+        let mut event = m.resolution(self.shown_aliases(), |a| self.log_address(env, a, m.resolved_round));
+        event.wait_duration = Some(m.wait_duration());
+        event.times_played_together = Some(times_played_together);
+        event.escrow_released = escrow;
+        event.side_bets = side_bets;
+
+        let referral = |referral: Option<([u8; 32], u64)>| referral.map(|(referrer, reward)| ReferralPaid {
+            referrer: self.log_address(env, referrer, env.round_idx),
+            reward,
+        });
+        let paid = |amount: u64| Some(amount).filter(|amount| *amount > 0);
+
+        for (result, player, streak_bonus, insurance_refund, penalty, loyalty_bonus, coop_jackpot, milestone, referral) in [
+            (&mut event.player_1, &m.p1, p1_streak_bonus, p1_insurance_refund, p1_penalty, p1_loyalty_bonus, p1_coop_jackpot, p1_milestone, referral(p1_referral)),
+            (&mut event.player_2, p2, p2_streak_bonus, p2_insurance_refund, p2_penalty, p2_loyalty_bonus, p2_coop_jackpot, p2_milestone, referral(p2_referral)),
+        ] {
+            result.paid_out = paid_out.contains(&player.sender);
+            result.streak_bonus = paid(streak_bonus);
+            if player.insured {
+                result.insurance_premium = Some(player.insurance_premium);
+                result.insurance_refund = Some(insurance_refund);
+            }
+            result.defection_penalty = paid(penalty);
+            result.loyalty_bonus = paid(loyalty_bonus);
+            result.coop_jackpot = paid(coop_jackpot);
+            result.milestone = milestone.map(|(matches_played, bonus)| MilestoneReached { matches_played, bonus });
+            result.referral = referral;
+        }
+
+        // Index the match for both players. The index never needs to be
+        // longer than the history it points into.
+        for sender in &[m.p1.sender, p2.sender] {
+            let ids = self.player_history.entry(*sender).or_default();
+            ids.push(m.id.clone());
+            if ids.len() > self.history_capacity {
+                let excess = ids.len() - self.history_capacity;
+                ids.drain(..excess);
+            }
+        }
+
+        // Only mutual cooperations and betrayals pay pot rewards
+        let rewarded = m.p1.vote != 3 && p2.vote != 3 && (m.p1.vote == 1 || p2.vote == 1);
+        let pot_too_small = if pot_before < self.min_pot_for_rewards && rewarded {
+            Some(PotTooSmall {
+                schema_version: SCHEMA_VERSION,
+                match_id: m.id.clone(),
+                pot: pot_before,
+                min_pot_for_rewards: self.min_pot_for_rewards,
+            })
+        } else {
+            None
+        };
+
+        // Save the match into the history list
+        self.history.push(m);
+
+        // Prune old history if needed
+        self.prune_old_history();
+
+        self.emit(effects, Event::MatchResolved(Box::new(event)));
+        if let Some(event) = pot_too_small {
+            self.emit(effects, Event::PotTooSmall(event));
+        }
+
+        self.check_nash_equilibrium(effects);
+
+        debug_assert!(self.verify_conservation().is_ok());
+
+        self.check_invariants()
+    }
+
+    // The calls that move funds. The contract reads their payloads and
+    // carries out the effects, the state changes are made here. Calls that
+    // take a stake get their payload as read, so a stake attached to a
+    // malformed payload is handled like any other rejected stake.
+
+    pub fn handle_play(&mut self, env: &Env, request: Result<PlayRequest, ContractError>, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        self.evict_seen_tx_ids(env.round_idx);
+        if self.seen_tx_ids.contains(&env.tx_id) {
+            return Err(ContractError::DuplicateTransaction.into());
+        }
+
+        self.reject_if_paused(env)?;
+
+        // A rejected play keeps the stake in the sender's balance
+        let p = match self.play_request(env, request) {
+            Ok(p) => p,
+            Err(err) => {
+                self.refund_attached(env);
+                return Err(err);
+            }
+        };
+
+        self.play_stake(p, env, effects)
+    }
+
+    // The player a play enters with, if the request is valid.
+    fn play_request(&self, env: &Env, request: Result<PlayRequest, ContractError>) -> Result<Player, Box<dyn Error>> {
+        let request = request?;
+        let vote = request.vote;
+
+        if vote != 1 && vote != 2 && vote != 3 && vote != 4 && vote != 5 {
+            return Err(ContractError::InvalidVote.into());
+        }
+
+        if env.amount == 0 {
+            return Err(ContractError::ZeroStake.into());
+        }
+        if env.amount < self.min_stake {
+            return Err(ContractError::StakeTooLow(self.min_stake).into());
+        }
+
+        let mut referrer = None;
+        if !request.referrer.is_empty() {
+            referrer = match from_hex_string(&request.referrer) {
+                Ok(r) => Some(r),
+                Err(_) => return Err(ContractError::InvalidReferrer.into()),
+            };
+        }
+
+        if referrer == Some(env.sender) {
+            return Err(ContractError::SelfReferral.into());
+        }
+
+        Ok(Player {
+            sender: env.sender,
+            tx_id: env.tx_id,
+            stake: env.amount,
+            vote,
+            original_vote: vote,
+            min_opponent_stake: request.min_opponent_stake,
+            referrer,
+            insured: request.insured,
+            insurance_premium: 0,
+            blinded_vote: request.blinded_vote,
+            strategy_id: request.strategy_id,
+        })
+    }
+
+    // Enters several matches at once, splitting the amount evenly between
+    // them. A match that can't be entered stops the batch, the matches
+    // entered before it stay entered and the stakes of the remaining ones go
+    // to the sender's balance.
+    pub fn handle_play_many(&mut self, env: &Env, request: Result<PlayManyRequest, ContractError>, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        let sender = env.sender;
+        let tx_id = env.tx_id;
+
+        self.evict_seen_tx_ids(env.round_idx);
+        if self.seen_tx_ids.contains(&tx_id) {
+            return Err(ContractError::DuplicateTransaction.into());
+        }
+
+        self.reject_if_paused(env)?;
+
+        // A rejected batch keeps the amount in the sender's balance
+        let batch = match request {
+            Ok(request) => self.split_batch(env, &request).map(|batch| (request, batch)),
+            Err(err) => Err(err.into()),
+        };
+        let (request, Batch { stake, dust, tx_ids }) = match batch {
+            Ok(batch) => batch,
+            Err(err) => {
+                self.refund_attached(env);
+                return Err(err);
+            }
+        };
+        let (count, vote) = (request.count, request.vote);
+
+        let mut played = Vec::new();
+        let mut failure = None;
+        for (i, sub_tx_id) in tx_ids.iter().enumerate() {
+            let p = Player {
+                sender,
+                tx_id: *sub_tx_id,
+                stake,
+                vote,
+                original_vote: vote,
+                min_opponent_stake: request.min_opponent_stake,
+                referrer: None,
+                insured: false,
+                insurance_premium: 0,
+                blinded_vote: None,
+                strategy_id: request.strategy_id,
+            };
+
+            if let Err(err) = self.play_stake(p, env, effects) {
+                failure = Some((i, err.to_string()));
+                break;
+            }
+
+            played.push(to_hex_string(*sub_tx_id));
+        }
+
+        // The dust of the split, and the stakes of the matches that were
+        // never entered, go back to the sender
+        let unplayed = match failure {
+            Some((i, _)) => (count as usize - i - 1) as u64 * stake,
+            None => 0,
+        };
+        if dust + unplayed > 0 {
+            self.total_deposits += dust + unplayed;
+            update_balance(&mut self.balances, sender, (dust + unplayed) as i128);
+        }
+
+        self.record_tx(tx_id, env.round_idx);
+
+        self.emit(effects, Event::BatchPlayed(BatchPlayed {
+            schema_version: SCHEMA_VERSION,
+            count,
+            stake,
+            played,
+            refund: dust + unplayed,
+            failed: failure.map(|(index, error)| BatchFailure { index, error }),
+        }));
+
+        Ok(())
+    }
+
+    // Splits the amount of a batch between its matches, if the request is valid.
+    fn split_batch(&self, env: &Env, request: &PlayManyRequest) -> Result<Batch, Box<dyn Error>> {
+        let (count, vote) = (request.count, request.vote);
+
+        if count == 0 || count > MAX_PLAY_MANY_COUNT {
+            return Err(ContractError::InvalidBatchSize.into());
+        }
+
+        // A blinded vote is revealed per match, so it can't be shared by a batch
+        if vote != 1 && vote != 2 && vote != 3 && vote != 4 {
+            return Err(ContractError::InvalidVote.into());
+        }
+
+        let (stake, dust) = split_amount(env.amount, count as u64);
+        if stake == 0 {
+            return Err(ContractError::ZeroStake.into());
+        }
+        if stake < self.min_stake {
+            return Err(ContractError::StakeTooLow(self.min_stake).into());
+        }
+
+        // Every match gets its own transaction id, derived from the batch's
+        let tx_ids: Vec<[u8; 32]> = (0..count).map(|i| {
+            let mut input = env.tx_id.to_vec();
+            input.push(i);
+            env.hash(&input)
+        }).collect();
+
+        if tx_ids.iter().any(|id| self.seen_tx_ids.contains(id)) {
+            return Err(ContractError::DuplicateTransaction.into());
+        }
+
+        Ok(Batch { stake, dust, tx_ids })
+    }
+
+    pub fn handle_commit_vote(&mut self, env: &Env, commitment: Result<[u8; 32], ContractError>, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        self.reject_if_paused(env)?;
+
+        self.expire_commitments(env, effects);
+
+        // A rejected commitment keeps the stake in the sender's balance
+        let commitment = match self.check_commitment(env, commitment) {
+            Ok(commitment) => commitment,
+            Err(err) => {
+                self.refund_attached(env);
+                return Err(err);
+            }
+        };
+
+        self.total_deposits += env.amount;
+        self.pending_commits.push(PendingCommit {
+            sender: env.sender,
+            tx_id: env.tx_id,
+            commitment,
+            stake: env.amount,
+            committed_round: env.round_idx,
+        });
+
+        self.emit(effects, Event::VoteCommitted(VoteCommitted {
+            schema_version: SCHEMA_VERSION,
+            commitment: to_hex_string(commitment),
+            stake: env.amount,
+            expires_after_round: env.round_idx + self.commitment_expiry_rounds,
+        }));
+
+        self.check_invariants()
+    }
+
+    // The commitment, if the sender can commit to a vote.
+    fn check_commitment(&self, env: &Env, commitment: Result<[u8; 32], ContractError>) -> Result<[u8; 32], Box<dyn Error>> {
+        let commitment = commitment?;
+
+        if env.amount == 0 {
+            return Err(ContractError::ZeroStake.into());
+        }
+        if env.amount < self.min_stake {
+            return Err(ContractError::StakeTooLow(self.min_stake).into());
+        }
+
+        if self.pending_commits.iter().any(|c| c.sender == env.sender) {
+            return Err(ContractError::CooldownActive.into());
+        }
+
+        Ok(commitment)
+    }
+
+    pub fn handle_reveal_vote(&mut self, env: &Env, vote: u8, nonce: [u8; 32], effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        if vote != 1 && vote != 2 && vote != 3 {
+            return Err(ContractError::InvalidVote.into());
+        }
+
+        // Expired commitments have been refunded, and can not be revealed anymore
+        self.expire_commitments(env, effects);
+
+        let index = match self.pending_commits.iter().position(|c| c.sender == env.sender) {
+            Some(index) => index,
+            None => return Err(ContractError::CommitmentNotFound.into()),
+        };
+
+        if vote_commitment(env.sha256, vote, nonce) != self.pending_commits[index].commitment {
+            return Err(ContractError::CommitmentMismatch.into());
+        }
+
+        let commit = self.pending_commits.remove(index);
+
+        self.decay_pot(env, effects);
+
+        let p = Player {
+            sender: commit.sender,
+            tx_id: commit.tx_id,
+            stake: commit.stake,
+            vote,
+            original_vote: vote,
+            min_opponent_stake: 0,
+            referrer: None,
+            insured: false,
+            insurance_premium: 0,
+            blinded_vote: None,
+            strategy_id: 0,
+        };
+
+        self.enter(p, env, effects)
+    }
+
+    pub fn handle_reveal_blinded_vote(&mut self, env: &Env, id: &str, vote: u8, nonce: [u8; 32], effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        self.forfeit_unrevealed_matches(env, effects);
+
+        let index = match self.awaiting_reveal.iter().position(|m| m.id == id) {
+            Some(index) => index,
+            None => {
+                // A blinded vote is revealed once someone joined the match
+                if self.waiting.iter().chain(self.challenge_only_waiting.iter()).any(|m| m.id == id) {
+                    return Err(ContractError::MatchStillWaiting.into());
+                }
+
+                return Err(ContractError::MatchNotFound.into());
+            }
+        };
+
+        if self.awaiting_reveal[index].p1.sender != env.sender {
+            return Err(ContractError::NotYourMatch.into());
+        }
+
+        if vote != 1 && vote != 2 && vote != 3 {
+            return Err(ContractError::InvalidVote.into());
+        }
+
+        if Some(vote_commitment(env.sha256, vote, nonce)) != self.awaiting_reveal[index].p1.blinded_vote {
+            return Err(ContractError::CommitmentMismatch.into());
+        }
+
+        let mut m = self.awaiting_reveal.remove(index);
+        let p2 = m.p2.take().unwrap();
+
+        m.p1.vote = vote;
+        m.p1.original_vote = vote;
+        m.p1.blinded_vote = None;
+
+        // The revealed match is resolved like any waiting match that player 2 joins
+        self.escrow.insert(m.id.clone(), (m.p1.stake, 0));
+        self.waiting.insert(0, m);
+
+        self.settle(0, p2, env, effects)
+    }
+
+    pub fn handle_cancel(&mut self, env: &Env, id: &str, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        let m = match self.challenge_only_waiting.iter().position(|m| m.id == id && m.p1.sender == env.sender) {
+            Some(index) => self.challenge_only_waiting.remove(index),
+            None => {
+                let index = self.find_own_waiting_match(id, env.sender)?;
+                self.waiting.remove(index)
+            }
+        };
+        self.escrow.remove(&m.id);
+
+        // The stake goes back to the player's balance, and so do the stakes of
+        // the side bets on the match
+        update_balance(&mut self.balances, m.p1.sender, m.p1.stake as i128);
+        self.refund_side_bets(&m.id);
+
+        let stats = self.stats.entry(m.p1.sender).or_default();
+        stats.cancellations += 1;
+        stats.reputation = stats.compute_reputation();
+
+        self.emit(effects, Event::MatchCancelled(MatchCancelled {
+            schema_version: SCHEMA_VERSION,
+            match_id: m.id,
+            cancelled: true,
+            refund: m.p1.stake,
+        }));
+
+        self.check_invariants()
+    }
+
+    // Turns random matching on or off for the sender. Turning it back on
+    // moves the sender's challenge-only matches into the waiting pool, oldest
+    // first, if they would still be let in as new matches: the pools must
+    // have room and the stake must meet the minimum. The others are refunded.
+    // In the pool they are only joined within their stake tier, like any
+    // other match.
+    pub fn handle_set_random_matching(&mut self, env: &Env, enabled: bool, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        if !enabled {
+            self.opt_out_random.insert(env.sender);
+        } else if self.opt_out_random.remove(&env.sender) {
+            let sender = env.sender;
+            let (own, others): (Vec<Match>, Vec<Match>) = self.challenge_only_waiting.drain(..).partition(|m| m.p1.sender == sender);
+            self.challenge_only_waiting = others;
+
+            for m in own {
+                let queued = self.waiting.len() + self.challenge_only_waiting.len();
+                let pool_full = self.max_waiting_pool > 0 && queued >= self.max_waiting_pool;
+
+                if pool_full || m.p1.stake + m.p1.insurance_premium < self.min_stake {
+                    self.escrow.remove(&m.id);
+                    update_balance(&mut self.balances, m.p1.sender, m.p1.stake as i128);
+                    self.refund_side_bets(&m.id);
+
+                    self.emit(effects, Event::MatchCancelled(MatchCancelled {
+                        schema_version: SCHEMA_VERSION,
+                        match_id: m.id,
+                        cancelled: true,
+                        refund: m.p1.stake,
+                    }));
+                } else {
+                    self.waiting.push(m);
+                }
+            }
+
+            self.waiting.sort_by_key(|m| (!m.priority, m.queued_at));
+        }
+
+        self.emit(effects, Event::RandomMatchingChanged(RandomMatchingChanged {
+            schema_version: SCHEMA_VERSION,
+            random_matching: enabled,
+        }));
+
+        self.check_invariants()
+    }
+
+    // Plays against a challenge-only match, whose id the creator shared.
+    pub fn handle_challenge(&mut self, env: &Env, challenge: Result<(String, u8), ContractError>, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        self.reject_if_paused(env)?;
+
+        // A rejected challenge keeps the stake in the sender's balance
+        let (index, vote) = match self.challenged_match(env, challenge) {
+            Ok(challenged) => challenged,
+            Err(err) => {
+                self.refund_attached(env);
+                return Err(err);
+            }
+        };
+
+        self.total_deposits += env.amount;
+
+        self.decay_pot(env, effects);
+
+        let p = Player {
+            sender: env.sender,
+            tx_id: env.tx_id,
+            stake: env.amount,
+            vote,
+            original_vote: vote,
+            min_opponent_stake: 0,
+            referrer: None,
+            insured: false,
+            insurance_premium: 0,
+            blinded_vote: None,
+            strategy_id: 0,
+        };
+
+        self.record_stake(p.sender, p.stake);
+
+        // The match is joined like any waiting match
+        let m = self.challenge_only_waiting.remove(index);
+        self.waiting.insert(0, m);
+
+        self.join(0, p, env, effects)
+    }
+
+    // The index of the challenged match and the vote of the challenger, if
+    // the challenge is valid.
+    fn challenged_match(&self, env: &Env, challenge: Result<(String, u8), ContractError>) -> Result<(usize, u8), Box<dyn Error>> {
+        let (id, vote) = challenge?;
+
+        if vote != 1 && vote != 2 && vote != 3 {
+            return Err(ContractError::InvalidVote.into());
+        }
+
+        if env.amount < self.min_stake {
+            return Err(ContractError::StakeTooLow(self.min_stake).into());
+        }
+
+        let index = match self.challenge_only_waiting.iter().position(|m| m.id == id) {
+            Some(index) => index,
+            None => return Err(ContractError::MatchNotFound.into()),
+        };
+
+        if self.challenge_only_waiting[index].p1.sender == env.sender {
+            return Err(ContractError::OwnMatch.into());
+        }
+
+        Ok((index, vote))
+    }
+
+    pub fn handle_top_up(&mut self, env: &Env, id: Result<String, ContractError>, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        self.reject_if_paused(env)?;
+
+        if env.amount == 0 {
+            return Err(ContractError::ZeroStake.into());
+        }
+
+        // A rejected top-up goes to the sender's balance
+        let index = match id.map_err(Box::from).and_then(|id| self.find_own_waiting_match(&id, env.sender)) {
+            Ok(index) => index,
+            Err(err) => {
+                self.refund_attached(env);
+                return Err(err);
+            }
+        };
+
+        self.total_deposits += env.amount;
+
+        let m = &mut self.waiting[index];
+        m.p1.stake += env.amount;
+        let (id, stake) = (m.id.clone(), m.p1.stake);
+        self.escrow.insert(id.clone(), (stake, 0));
+
+        self.record_stake(env.sender, env.amount);
+
+        self.emit(effects, Event::MatchToppedUp(MatchToppedUp {
+            schema_version: SCHEMA_VERSION,
+            match_id: id,
+            stake,
+        }));
+
+        self.check_invariants()
+    }
+
+    pub fn handle_side_bet(&mut self, env: &Env, bet: Result<(String, u8), ContractError>, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        self.reject_if_paused(env)?;
+
+        if env.amount == 0 {
+            return Err(ContractError::ZeroStake.into());
+        }
+
+        // A rejected bet keeps the stake in the sender's balance
+        let (match_id, predicted_outcome) = match self.check_side_bet(env, bet) {
+            Ok(bet) => bet,
+            Err(err) => {
+                self.refund_attached(env);
+                return Err(err);
+            }
+        };
+
+        self.total_deposits += env.amount;
+        self.side_bets.push(SideBet {
+            match_id: match_id.clone(),
+            bettor: env.sender,
+            predicted_outcome,
+            stake: env.amount,
+        });
+
+        self.emit(effects, Event::SideBetPlaced(SideBetPlaced {
+            schema_version: SCHEMA_VERSION,
+            match_id,
+            predicted_outcome,
+            stake: env.amount,
+        }));
+
+        self.check_invariants()
+    }
+
+    // The match and the predicted outcome of a bet, if the bet can be placed.
+    fn check_side_bet(&self, env: &Env, bet: Result<(String, u8), ContractError>) -> Result<(String, u8), Box<dyn Error>> {
+        let (match_id, predicted_outcome) = bet?;
+
+        if predicted_outcome > 3 {
+            return Err(ContractError::InvalidOutcome.into());
+        }
+
+        let m = match self.waiting.iter().find(|m| m.id == match_id) {
+            Some(m) => m,
+            None => return Err(ContractError::MatchNotFound.into()),
+        };
+
+        if m.p1.sender == env.sender {
+            return Err(ContractError::OwnMatch.into());
+        }
+
+        Ok((match_id, predicted_outcome))
+    }
+
+    // Cashes out the balance of the player, who is either the sender or
+    // someone whose delegate the sender is. The balance is always sent to the player.
+    pub fn handle_cash_out(&mut self, env: &Env, balance_owner: [u8; 32], effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        if balance_owner != env.sender && self.cash_out_delegates.get(&balance_owner) != Some(&env.sender) {
+            return Err(ContractError::NotDelegate.into());
+        }
+
+        let sender_balance = match self.balances.get(&balance_owner) {
+            Some(balance) => *balance,
+            None => 0,
+        };
+        if sender_balance == 0 {
+            return Err(ContractError::InsufficientBalance.into());
+        }
+
+        effects.transfer(balance_owner, sender_balance);
+
+        self.balances.insert(balance_owner, 0);
+        self.total_withdrawals += sender_balance;
+        self.global_stats.total_cashed_out += sender_balance;
+
+        self.check_invariants()
+    }
+
+    pub fn handle_multisig_approve(&mut self, env: &Env, nonce: u64, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        self.prune_expired_multisig_requests(env.round_idx);
+
+        let index = match self.multisig_requests.iter().position(|r| r.nonce == nonce) {
+            Some(index) => index,
+            None => {
+                return Err(ContractError::MultisigNotFound.into());
+            }
+        };
+
+        if self.multisig_requests[index].approver != env.sender {
+            return Err(ContractError::NotApprover.into());
+        }
+
+        let request = self.multisig_requests.remove(index);
+
+        let requester_balance = match self.balances.get(&request.requester) {
+            Some(balance) => *balance,
+            None => 0,
+        };
+        if request.amount > requester_balance {
+            return Err(ContractError::InsufficientBalance.into());
+        }
+
+        effects.transfer(request.requester, request.amount);
+
+        self.balances.insert(request.requester, requester_balance - request.amount);
+        self.total_withdrawals += request.amount;
+        self.global_stats.total_cashed_out += request.amount;
+
+        let requester = self.log_address(env, request.requester, env.round_idx);
+        self.emit(effects, Event::MultisigApproved(MultisigApproved {
+            schema_version: SCHEMA_VERSION,
+            nonce,
+            requester,
+            amount: request.amount,
+        }));
+
+        self.check_invariants()
+    }
+
+    // Retires the contract: it stays paused for good, and every stake still
+    // in play goes back to its owner's balance, from where it can be cashed
+    // out. The pot is either swept into the owner's balance or burned.
+    // Shutting down again finds nothing left to unwind.
+    pub fn handle_shutdown(&mut self, sweep_pot: bool, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        // Everything was already refunded, and the pot swept or burned
+        if self.shut_down {
+            return Ok(());
+        }
+
+        self.paused = true;
+        self.shut_down = true;
+
+        let mut matches_unwound = 0;
+        let mut refunded = 0;
+
+        let waiting: Vec<Match> = self.waiting.drain(..).chain(self.challenge_only_waiting.drain(..)).collect();
+        for m in waiting {
+            self.escrow.remove(&m.id);
+            update_balance(&mut self.balances, m.p1.sender, m.p1.stake as i128);
+
+            matches_unwound += 1;
+            refunded += m.p1.stake;
+        }
+
+        // Both players of a match waiting for a reveal get their stakes back
+        let unrevealed: Vec<Match> = self.awaiting_reveal.drain(..).collect();
+        for m in unrevealed {
+            let p2 = m.p2.as_ref().unwrap();
+            self.escrow.remove(&m.id);
+            update_balance(&mut self.balances, m.p1.sender, m.p1.stake as i128);
+            update_balance(&mut self.balances, p2.sender, p2.stake as i128);
+
+            matches_unwound += 1;
+            refunded += m.p1.stake + p2.stake;
+        }
+
+        let commits: Vec<PendingCommit> = self.pending_commits.drain(..).collect();
+        for commit in commits {
+            update_balance(&mut self.balances, commit.sender, commit.stake as i128);
+            refunded += commit.stake;
+        }
+
+        let bets: Vec<SideBet> = self.side_bets.drain(..).collect();
+        for bet in bets {
+            update_balance(&mut self.balances, bet.bettor, bet.stake as i128);
+            refunded += bet.stake;
+        }
+
+        let pot = self.pot;
+        self.pot = 0;
+        self.global_stats.pot_outflow += pot;
+        if sweep_pot {
+            update_balance(&mut self.balances, self.owner, pot as i128);
+        } else {
+            self.total_burned += pot;
+        }
+
+        self.emit(effects, Event::ContractShutdown(ContractShutdown {
+            schema_version: SCHEMA_VERSION,
+            matches_unwound,
+            refunded,
+            pot_swept: if sweep_pot { pot } else { 0 },
+            pot_burned: if sweep_pot { 0 } else { pot },
+        }));
+
+        self.check_invariants()
+    }
+
+    // Looks a match up wherever it is: waiting, waiting for a reveal, in the
+    // history or in the archive.
+    pub fn handle_result(&self, env: &Env, id: String, effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        // Check the match in the waiting pools
+        if let Some(m) = self.waiting.iter().chain(self.challenge_only_waiting.iter()).find(|m| m.id == id) {
+            effects.log(&m.waiting_json().to_string());
+
+            return Ok(());
+        }
+
+        // Check the matches waiting for a blinded vote to be revealed
+        if let Some(m) = self.awaiting_reveal.iter().find(|m| m.id == id) {
+            let mut result = m.waiting_json();
+            result["status"] = json!("awaiting_reveal");
+            effects.log(&result.to_string());
+
+            return Ok(());
+        }
+
+        let found = match self.history.iter().find(|m| m.id == id) {
+            Some(m) => m,
+            None => {
+                if let Some(archived) = self.archive.get(&id) {
+                    let event = self.archived_resolution(env, id, archived);
+                    self.emit_query(effects, Event::MatchResolved(Box::new(event)));
+
+                    return Ok(());
+                }
+
+                return Err(ContractError::MatchNotFound.into());
+            }
+        };
+
+        let mut event = found.resolution(self.shown_aliases(), |a| self.log_address(env, a, found.resolved_round));
+        event.status = Some("resolved".to_string());
+        self.emit_query(effects, Event::MatchResolved(Box::new(event)));
+
+        Ok(())
+    }
+
+    // Only the players, payouts and outcome of archived matches are left.
+    pub fn archived_resolution(&self, env: &Env, match_id: String, archived: &ArchivedMatch) -> MatchResolved {
+        let player = |sender: [u8; 32], payout: u64| PlayerResult {
+            sender: self.log_address(env, sender, env.round_idx),
+            payout,
+            ..PlayerResult::default()
+        };
+
+        MatchResolved {
+            schema_version: SCHEMA_VERSION,
+            match_id,
+            player_1: player(archived.p1_sender, archived.p1_payout),
+            player_2: player(archived.p2_sender, archived.p2_payout),
+            outcome: archived.outcome.clone(),
+            status: Some("resolved".to_string()),
+            archived: true,
+            created_round: None,
+            resolved_round: None,
+            resolved_round_id: None,
+            pot_change: None,
+            pot_after: None,
+            wait_duration: None,
+            times_played_together: None,
+            escrow_released: None,
+            jackpot: None,
+            charity: None,
+            defector_bonus: None,
+            side_bets: Vec::new(),
+        }
+    }
+
+    // Logs the match that a transaction was played in. Archived matches
+    // don't keep their transaction ids, so they can't be found.
+    pub fn handle_result_by_tx(&self, env: &Env, tx_id: [u8; 32], effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        if let Some(m) = self.waiting.iter().chain(self.challenge_only_waiting.iter()).find(|m| m.p1.tx_id == tx_id) {
+            effects.log(&m.waiting_json().to_string());
+
+            return Ok(());
+        }
+
+        let played_in = |m: &&Match| m.p1.tx_id == tx_id || m.p2.as_ref().map(|p2| p2.tx_id) == Some(tx_id);
+
+        if let Some(m) = self.awaiting_reveal.iter().find(played_in) {
+            let mut result = m.waiting_json();
+            result["status"] = json!("awaiting_reveal");
+            effects.log(&result.to_string());
+
+            return Ok(());
+        }
+
+        let found = match self.history.iter().find(played_in) {
+            Some(m) => m,
+            None => {
+                return Err(ContractError::MatchNotFound.into());
+            }
+        };
+
+        let mut result = found.resolution(self.shown_aliases(), |a| self.log_address(env, a, found.resolved_round));
+        result.status = Some("resolved".to_string());
+        effects.log(&serde_json::to_string(&result)?);
+
+        Ok(())
+    }
+
+    // Logs the results of several matches, in the order of the ids. Matches
+    // that are not resolved yet or don't exist get an error instead.
+    pub fn handle_results_batch(&self, env: &Env, ids: &[String], effects: &mut Effects) -> Result<(), Box<dyn Error>> {
+        let results: Vec<serde_json::Value> = ids.iter().map(|id| {
+            if self.waiting.iter().chain(self.challenge_only_waiting.iter()).any(|m| &m.id == id) {
+                return json!({
+                    "match_id": id,
+                    "error": "pending",
+                });
+            }
+
+            if self.awaiting_reveal.iter().any(|m| &m.id == id) {
+                return json!({
+                    "match_id": id,
+                    "error": "awaiting_reveal",
+                });
+            }
+
+            if let Some(m) = self.history.iter().find(|m| &m.id == id) {
+                return json!(m.resolution(self.shown_aliases(), |a| self.log_address(env, a, m.resolved_round)));
+            }
+
+            match self.archive.get(id) {
+                Some(archived) => json!(self.archived_resolution(env, id.clone(), archived)),
+                None => json!({
+                    "match_id": id,
+                    "error": "not_found",
+                }),
+            }
+        }).collect();
+
+        effects.log(&json!(results).to_string());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Not sha256, but deterministic and spread over all the bytes, which is
+    // all the game needs from the hash.
+    fn fake_sha256(data: &[u8]) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        for (i, b) in data.iter().enumerate() {
+            for (j, d) in digest.iter_mut().enumerate() {
+                *d = d.wrapping_mul(31).wrapping_add(b ^ (i + j) as u8);
+            }
+        }
+
+        digest
+    }
+
+    fn env(sender: u8, tx: u8, amount: u64, round_idx: u64) -> Env {
+        Env {
+            sender: [sender; 32],
+            tx_id: [tx; 32],
+            amount,
+            round_idx,
+            round_id: [round_idx as u8; 32],
+            sha256: fake_sha256,
+        }
+    }
+
+    fn vote(vote: u8) -> Result<PlayRequest, ContractError> {
+        Ok(PlayRequest {
+            vote,
+            strategy_id: 0,
+            min_opponent_stake: 0,
+            blinded_vote: None,
+            referrer: String::new(),
+            insured: false,
+        })
+    }
+
+    // A game in which every play joins the oldest waiting match, and which
+    // checks its accounting after every call.
+    fn game() -> GameState {
+        let mut state = GameState::new([9; 32]);
+        state.threshold = 100;
+        state.threshold_decay_mode = ThresholdMode::Fixed;
+        state.invariants_enabled = true;
+
+        state
+    }
+
+    fn events(effects: &Effects) -> Vec<String> {
+        effects.logs.iter()
+            .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+            .filter_map(|v| v["event"].as_str().map(|e| e.to_string()))
+            .collect()
+    }
+
+    fn player(sender: u8, stake: u64, vote: u8) -> Player {
+        Player {
+            sender: [sender; 32],
+            tx_id: [sender; 32],
+            stake,
+            vote,
+            original_vote: vote,
+            min_opponent_stake: 0,
+            referrer: None,
+            insured: false,
+            insurance_premium: 0,
+            blinded_vote: None,
+            strategy_id: 0,
+        }
+    }
+
+    #[test]
+    fn first_player_waits_for_an_opponent() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+
+        assert_eq!(state.waiting.len(), 1);
+        assert_eq!(state.escrow.get(&state.waiting[0].id), Some(&(1000, 0)));
+        assert_eq!(events(&effects), vec!["match_created"]);
+        assert!(effects.transfers.is_empty());
+    }
+
+    #[test]
+    fn second_player_resolves_the_match() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(2), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 1000, 2), vote(1), &mut effects).unwrap();
+
+        assert!(state.waiting.is_empty());
+        assert!(state.escrow.is_empty());
+        assert_eq!(state.history.len(), 1);
+        assert_eq!(events(&effects), vec!["match_created", "match_joined", "match_resolved"]);
+
+        // Every PERL staked is either in a balance or in the pot
+        let balances: u64 = state.balances.values().sum();
+        assert_eq!(balances + state.pot, 2000);
+        assert!(state.verify_conservation().is_ok());
+    }
+
+    // The match is resolved where it waits, moved into the history, and its
+    // players are never copied on the way.
+    #[test]
+    fn resolving_a_match_does_not_clone_it() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        state.handle_play(&env(1, 1, 1000, 1), vote(2), &mut effects).unwrap();
+
+        PLAYER_CLONES.with(|clones| clones.set(0));
+        state.handle_play(&env(2, 2, 1000, 2), vote(1), &mut effects).unwrap();
+
+        assert_eq!(state.history.len(), 1);
+        assert_eq!(PLAYER_CLONES.with(|clones| clones.get()), 0);
+    }
+
+    #[test]
+    fn replayed_transaction_is_rejected() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+        let err = state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap_err();
+
+        assert!(err.to_string().contains("DUPLICATE_TRANSACTION"));
+        assert_eq!(state.waiting.len(), 1);
+    }
+
+    #[test]
+    fn invalid_vote_is_rejected() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        let err = state.handle_play(&env(1, 1, 0, 1), vote(6), &mut effects).unwrap_err();
+
+        assert!(err.to_string().contains("INVALID_VOTE"));
+        assert!(state.waiting.is_empty());
+    }
+
+    #[test]
+    fn paused_game_keeps_the_stake_in_the_balance() {
+        let mut state = game();
+        state.paused = true;
+        let mut effects = Effects::default();
+
+        let err = state.handle_play(&env(1, 1, 500, 1), vote(1), &mut effects).unwrap_err();
+
+        assert!(err.to_string().contains("PAUSED"));
+        assert_eq!(state.balances.get(&[1; 32]), Some(&500));
+        assert!(state.waiting.is_empty());
+    }
+
+    #[test]
+    fn cancel_refunds_the_stake() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+
+        assert!(state.handle_cancel(&env(2, 2, 0, 2), &id, &mut effects).is_err());
+        state.handle_cancel(&env(1, 2, 0, 2), &id, &mut effects).unwrap();
+
+        assert!(state.waiting.is_empty());
+        assert!(state.escrow.is_empty());
+        assert_eq!(state.balances.get(&[1; 32]), Some(&1000));
+    }
+
+    #[test]
+    fn cash_out_sends_the_balance() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+        state.handle_cancel(&env(1, 2, 0, 2), &id, &mut effects).unwrap();
+
+        state.handle_cash_out(&env(1, 3, 0, 3), [1; 32], &mut effects).unwrap();
+
+        assert_eq!(effects.transfers, vec![([1; 32], 1000)]);
+        assert_eq!(state.balances.get(&[1; 32]), Some(&0));
+        assert_eq!(state.total_withdrawals, 1000);
+
+        let err = state.handle_cash_out(&env(1, 4, 0, 4), [1; 32], &mut effects).unwrap_err();
+        assert!(err.to_string().contains("INSUFFICIENT_BALANCE"));
+    }
+
+    #[test]
+    fn committed_vote_is_played_once_revealed() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        let nonce = [7; 32];
+        let commitment = vote_commitment(fake_sha256, 1, nonce);
+
+        state.handle_commit_vote(&env(1, 1, 1000, 1), Ok(commitment), &mut effects).unwrap();
+        assert_eq!(state.pending_commits.len(), 1);
+
+        assert!(state.handle_reveal_vote(&env(1, 2, 0, 2), 2, nonce, &mut effects).is_err());
+        state.handle_reveal_vote(&env(1, 2, 0, 2), 1, nonce, &mut effects).unwrap();
+
+        assert!(state.pending_commits.is_empty());
+        assert_eq!(state.waiting.len(), 1);
+        assert_eq!(state.waiting[0].p1.vote, 1);
+    }
+
+    #[test]
+    fn result_finds_waiting_and_resolved_matches() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+
+        let mut result = Effects::default();
+        state.handle_result(&env(3, 3, 0, 2), id.clone(), &mut result).unwrap();
+        assert_eq!(result.logs.len(), 1);
+
+        state.handle_play(&env(2, 2, 1000, 2), vote(1), &mut effects).unwrap();
+
+        let mut result = Effects::default();
+        state.handle_result(&env(3, 3, 0, 3), id, &mut result).unwrap();
+        assert_eq!(events(&result), vec!["match_resolved"]);
+
+        let err = state.handle_result(&env(3, 3, 0, 3), "unknown".to_string(), &mut result).unwrap_err();
+        assert!(err.to_string().contains("MATCH_NOT_FOUND"));
+    }
+
+    #[test]
+    fn anonymous_addresses_change_every_round() {
+        let mut state = game();
+        let env = env(1, 1, 0, 1);
+
+        assert_eq!(state.log_address(&env, [1; 32], 1), to_hex_string([1; 32]));
+
+        state.anonymous = true;
+        let pseudonym = state.log_address(&env, [1; 32], 1);
+        assert_ne!(pseudonym, to_hex_string([1; 32]));
+        assert_eq!(pseudonym, state.log_address(&env, [1; 32], 1));
+        assert_ne!(pseudonym, state.log_address(&env, [1; 32], 2));
+    }
+
+    #[test]
+    fn hex_strings_round_trip() {
+        let bytes = [0xab; 32];
+        let hex = to_hex_string(bytes);
+
+        assert_eq!(hex.len(), 64);
+        assert_eq!(from_hex_string(&hex).unwrap(), bytes);
+        assert_eq!(from_hex_string(&format!("0x{}", hex.to_uppercase())).unwrap(), bytes);
+        assert!(from_hex_string("abc").is_err());
+        assert!(from_hex_string(&format!("+{}", &hex[1..])).is_err());
+    }
+
+    // write_hex replaced a format! per byte, and has to write the same strings.
+    #[test]
+    fn hex_strings_match_the_formatted_bytes() {
+        let mut rng = rand::rngs::SmallRng::from_seed([68; 16]);
+
+        for _ in 0..1000 {
+            let mut bytes = [0u8; 32];
+            rng.fill(&mut bytes);
+
+            let formatted = bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join("");
+            let mut buf = [0u8; 64];
+            assert_eq!(write_hex(&bytes, &mut buf), formatted);
+            assert_eq!(to_hex_string(bytes), formatted);
+        }
+    }
+
+    #[test]
+    fn errors_display_as_json() {
+        let error: serde_json::Value = serde_json::from_str(&ContractError::StakeTooLow(5).to_string()).unwrap();
+
+        assert_eq!(error["code"], "STAKE_TOO_LOW");
+        assert_eq!(error["message"], "The stake must be at least 5 PERLs.");
+    }
+
+    #[test]
+    fn state_round_trips_through_json() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(2), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 1000, 2), vote(1), &mut effects).unwrap();
+
+        let json = serde_json::to_string(&state).unwrap();
+        let imported: GameState = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(imported.balances, state.balances);
+        assert_eq!(imported.pot, state.pot);
+        assert_eq!(imported.history.len(), 1);
+        assert_eq!(imported.seen_tx_ids, state.seen_tx_ids);
+    }
+
+    // The ids come from the transaction ids, and a taken id gets the next free
+    // suffix. All of that is kept in the state, so a restored state goes on
+    // with the ids the original would have used, without reusing any.
+    #[test]
+    fn ids_stay_unique_across_a_state_round_trip() {
+        // Transactions that only differ after the bytes the ids are made of
+        let colliding = |i: u8| {
+            let mut tx_id = [1; 32];
+            tx_id[31] = i;
+            Env { tx_id, ..env(i + 1, 0, 100, i as u64) }
+        };
+
+        let mut state = game();
+        state.history_capacity = 1;
+        let mut effects = Effects::default();
+        let mut ids = Vec::new();
+        for i in 0..6 {
+            state.handle_play(&colliding(i), vote(1), &mut effects).unwrap();
+            if let Some(m) = state.waiting.first() {
+                ids.push(m.id.clone());
+            }
+        }
+        assert_eq!(state.archive.len(), 2);
+
+        let mut restored: GameState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        state.handle_play(&colliding(6), vote(1), &mut Effects::default()).unwrap();
+        restored.handle_play(&colliding(6), vote(1), &mut Effects::default()).unwrap();
+        assert_eq!(restored.waiting[0].id, state.waiting[0].id);
+        ids.push(restored.waiting[0].id.clone());
+
+        let base = &to_hex_string([1; 32])[..16];
+        let expected: Vec<String> = (1..=4).map(|n| if n == 1 { base.to_string() } else { format!("{}-{}", base, n) }).collect();
+        assert_eq!(ids, expected);
+    }
+
+    // Whatever the votes, stakes, pot and rules, a match pays out exactly the
+    // stakes and what it takes from the pot, and never more than the pot has.
+    // Like a property test, the inputs lean towards the ends of their ranges,
+    // and a failure reports the case it failed on.
+    #[test]
+    fn payouts_conserve_the_stakes() {
+        let mut rng = rand::rngs::SmallRng::from_seed([65; 16]);
+        let mut draw = |below: u64| match rng.gen_range(0, 8) {
+            0 => 0,
+            1 => below - 1,
+            _ => rng.gen_range(0, below),
+        };
+
+        for case in 0..10_000 {
+            for &(vote1, vote2) in &[(1, 1), (1, 2), (2, 1), (2, 2)] {
+                let stake1 = draw(1_000_000_000_000);
+                let stake2 = draw(1_000_000_000_000);
+                let pot = draw(1_000_000_000_000_000);
+
+                let rules = PayoutRules {
+                    schedule: RewardSchedule {
+                        cooperate_bps: draw(5_001),
+                        defect_bps: draw(5_001),
+                        ..RewardSchedule::default()
+                    },
+                    abstain_fee_bps: draw(10_001),
+                    jackpot_share_bps: if draw(2) == 1 { Some(draw(10_001)) } else { None },
+                    charity_share_bps: draw(10_001),
+                    defector_bonus_cap_multiplier: draw(100),
+                    defection_penalty_multiplier: draw(100_000),
+                    repeat_defectors: (draw(2) == 1, draw(2) == 1),
+                    min_pot_for_rewards: draw(1_000_000),
+                };
+                let inputs = format!("case {}: votes {} {}, stakes {} {}, pot {}, {:?}", case, vote1, vote2, stake1, stake2, pot, rules);
+
+                let mut m = Match::new(format!("{}", case), player(1, stake1, vote1), 1);
+                m.play(player(2, stake2, vote2), pot, &rules).unwrap();
+
+                let stakes = stake1 as i128 + stake2 as i128;
+                let paid = m.p1_payout as i128 + m.p2_payout as i128 + m.charity_payout as i128;
+                assert_eq!(paid + m.pot_payout as i128, stakes, "{}", inputs);
+                assert!(m.pot_payout as i128 >= -(pot as i128), "{}", inputs);
+                assert!(m.pot_payout as i128 <= stakes, "{}", inputs);
+            }
+        }
+    }
+
+    #[test]
+    fn cooperate_reward_is_capped_at_half_the_pot() {
+        let mut state = game();
+
+        assert!(state.set_param("cooperate_bps", 5_001).is_err());
+        assert_eq!(state.set_param("cooperate_bps", 5_000).unwrap(), RewardSchedule::default().cooperate_bps);
+        assert_eq!(state.reward_schedule.cooperate_bps, 5_000);
+
+        assert!(state.set_param("defect_bps", 10_001).is_err());
+        state.set_param("defect_bps", 10_000).unwrap();
+        assert_eq!(state.reward_schedule.defect_bps, 10_000);
+    }
+
+    #[test]
+    fn pot_can_not_go_negative() {
+        assert_eq!(pot_after_payout(100, -100).unwrap(), 0);
+        assert_eq!(pot_after_payout(100, 50).unwrap(), 150);
+        assert!(pot_after_payout(100, -101).is_err());
+    }
+
+    #[test]
+    fn match_paying_more_than_the_pot_refunds_both_stakes() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        // Rewards of the whole pot for each player, which set_params would reject
+        state.reward_schedule.cooperate_bps = 10_000;
+        state.reward_schedule.max_reward = u64::MAX;
+        state.pot = 10_000;
+        state.total_deposits = 10_000;
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+        state.handle_side_bet(&env(3, 3, 100, 1), Ok((id, 0)), &mut effects).unwrap();
+
+        assert!(state.handle_play(&env(2, 2, 1000, 2), vote(1), &mut effects).is_err());
+
+        assert_eq!(state.pot, 10_000);
+        assert_eq!(state.balances.get(&[1; 32]), Some(&1000));
+        assert_eq!(state.balances.get(&[2; 32]), Some(&1000));
+        assert_eq!(state.balances.get(&[3; 32]), Some(&100));
+        assert!(state.waiting.is_empty());
+        assert!(state.escrow.is_empty());
+        assert!(state.history.is_empty());
+        assert!(state.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn replayed_play_is_refunded_once_while_paused() {
+        let mut state = game();
+        state.paused = true;
+        let mut effects = Effects::default();
+
+        let err = state.handle_play(&env(1, 1, 500, 1), vote(1), &mut effects).unwrap_err();
+        assert!(err.to_string().contains("PAUSED"));
+
+        let err = state.handle_play(&env(1, 1, 500, 1), vote(1), &mut effects).unwrap_err();
+        assert!(err.to_string().contains("DUPLICATE_TRANSACTION"));
+
+        let request = Ok(PlayManyRequest { count: 2, vote: 1, strategy_id: 0, min_opponent_stake: 0 });
+        let err = state.handle_play_many(&env(1, 1, 500, 1), request, &mut effects).unwrap_err();
+        assert!(err.to_string().contains("DUPLICATE_TRANSACTION"));
+
+        assert_eq!(state.balances.get(&[1; 32]), Some(&500));
+        assert_eq!(state.total_deposits, 500);
+    }
+
+    #[test]
+    fn full_waiting_pool_refunds_the_stake_once() {
+        let mut state = game();
+        state.max_waiting_pool = 1;
+        state.opt_out_random.insert([1; 32]);
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        assert_eq!(state.challenge_only_waiting.len(), 1);
+
+        let err = state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap_err();
+        assert!(err.to_string().contains("POOL_FULL"));
+        assert!(state.waiting.is_empty());
+
+        let err = state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap_err();
+        assert!(err.to_string().contains("DUPLICATE_TRANSACTION"));
+
+        assert_eq!(state.balances.get(&[2; 32]), Some(&100));
+    }
+
+    #[test]
+    fn rejected_play_keeps_the_stake_in_the_balance() {
+        let mut state = game();
+        state.min_stake = 100;
+        let mut effects = Effects::default();
+
+        let err = state.handle_play(&env(1, 1, 500, 1), vote(9), &mut effects).unwrap_err();
+        assert!(err.to_string().contains("INVALID_VOTE"));
+
+        let err = state.handle_play(&env(1, 2, 50, 1), vote(1), &mut effects).unwrap_err();
+        assert!(err.to_string().contains("STAKE_TOO_LOW"));
+
+        let err = state.handle_play(&env(1, 3, 500, 1), Err(ContractError::MalformedParameter), &mut effects).unwrap_err();
+        assert!(err.to_string().contains("MALFORMED_PARAMETER"));
+
+        let request = Ok(PlayManyRequest { count: 0, vote: 1, strategy_id: 0, min_opponent_stake: 0 });
+        state.handle_play_many(&env(1, 4, 500, 1), request, &mut effects).unwrap_err();
+
+        // Replays don't refund again
+        state.handle_play(&env(1, 1, 500, 1), vote(9), &mut effects).unwrap_err();
+        state.handle_play_many(&env(1, 4, 500, 1), Err(ContractError::MalformedParameter), &mut effects).unwrap_err();
+
+        assert_eq!(state.balances.get(&[1; 32]), Some(&1550));
+        assert_eq!(state.total_deposits, 1550);
+        assert!(state.waiting.is_empty());
+    }
+
+    #[test]
+    fn rejected_challenge_keeps_the_stake_in_the_balance() {
+        let mut state = game();
+        state.opt_out_random.insert([1; 32]);
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        let id = state.challenge_only_waiting[0].id.clone();
+
+        state.handle_challenge(&env(2, 2, 100, 1), Ok(("missing".to_string(), 1)), &mut effects).unwrap_err();
+        state.handle_challenge(&env(2, 3, 100, 1), Ok((id.clone(), 9)), &mut effects).unwrap_err();
+        state.handle_challenge(&env(1, 4, 100, 1), Ok((id.clone(), 1)), &mut effects).unwrap_err();
+        state.handle_challenge(&env(2, 5, 100, 1), Err(ContractError::MalformedParameter), &mut effects).unwrap_err();
+
+        assert_eq!(state.balances.get(&[2; 32]), Some(&300));
+        assert_eq!(state.balances.get(&[1; 32]), Some(&100));
+        assert_eq!(state.challenge_only_waiting.len(), 1);
+        state.verify_conservation().unwrap();
+    }
+
+    #[test]
+    fn rejected_commitment_keeps_the_stake_in_the_balance() {
+        let mut state = game();
+        state.min_stake = 100;
+        let mut effects = Effects::default();
+
+        state.handle_commit_vote(&env(1, 1, 100, 1), Ok([7; 32]), &mut effects).unwrap();
+        state.handle_commit_vote(&env(1, 2, 100, 1), Ok([8; 32]), &mut effects).unwrap_err();
+        state.handle_commit_vote(&env(2, 3, 50, 1), Ok([8; 32]), &mut effects).unwrap_err();
+        state.handle_commit_vote(&env(2, 4, 100, 1), Err(ContractError::MalformedParameter), &mut effects).unwrap_err();
+
+        assert_eq!(state.pending_commits.len(), 1);
+        assert_eq!(state.balances.get(&[1; 32]), Some(&100));
+        assert_eq!(state.balances.get(&[2; 32]), Some(&150));
+        state.verify_conservation().unwrap();
+    }
+
+    #[test]
+    fn rejected_top_up_goes_to_the_balance() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+
+        state.handle_top_up(&env(2, 2, 50, 1), Ok(id.clone()), &mut effects).unwrap_err();
+        state.handle_top_up(&env(1, 3, 50, 1), Ok("missing".to_string()), &mut effects).unwrap_err();
+        state.handle_top_up(&env(1, 4, 50, 1), Err(ContractError::MalformedParameter), &mut effects).unwrap_err();
+
+        assert_eq!(state.waiting[0].p1.stake, 100);
+        assert_eq!(state.balances.get(&[1; 32]), Some(&100));
+        assert_eq!(state.balances.get(&[2; 32]), Some(&50));
+        state.verify_conservation().unwrap();
+    }
+
+    #[test]
+    fn rejected_side_bet_keeps_the_stake_in_the_balance() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+
+        state.handle_side_bet(&env(2, 2, 10, 1), Ok((id.clone(), 4)), &mut effects).unwrap_err();
+        state.handle_side_bet(&env(2, 3, 10, 1), Ok(("missing".to_string(), 0)), &mut effects).unwrap_err();
+        state.handle_side_bet(&env(1, 4, 10, 1), Ok((id.clone(), 0)), &mut effects).unwrap_err();
+        state.handle_side_bet(&env(2, 5, 10, 1), Err(ContractError::MalformedParameter), &mut effects).unwrap_err();
+
+        assert!(state.side_bets.is_empty());
+        assert_eq!(state.balances.get(&[1; 32]), Some(&10));
+        assert_eq!(state.balances.get(&[2; 32]), Some(&30));
+        state.verify_conservation().unwrap();
+    }
+
+    #[test]
+    fn forfeited_match_refunds_the_side_bets() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        let blinded = Ok(PlayRequest { vote: 5, blinded_vote: Some([9; 32]), ..vote(5).unwrap() });
+        state.handle_play(&env(1, 1, 100, 1), blinded, &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+
+        state.handle_side_bet(&env(3, 2, 10, 1), Ok((id, 0)), &mut effects).unwrap();
+        state.handle_play(&env(2, 3, 100, 1), vote(1), &mut effects).unwrap();
+        assert_eq!(state.awaiting_reveal.len(), 1);
+
+        // Player 1 never reveals the vote
+        state.handle_play(&env(4, 4, 100, 1 + state.commitment_expiry_rounds + 1), vote(1), &mut effects).unwrap();
+
+        assert!(state.awaiting_reveal.is_empty());
+        assert!(state.side_bets.is_empty());
+        assert_eq!(state.balances.get(&[2; 32]), Some(&200));
+        assert_eq!(state.balances.get(&[3; 32]), Some(&10));
+    }
+
+    #[test]
+    fn forfeited_match_counts_as_resolved() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        let blinded = Ok(PlayRequest { vote: 5, blinded_vote: Some([9; 32]), ..vote(5).unwrap() });
+        state.handle_play(&env(1, 1, 100, 1), blinded, &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 3), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(3, 3, 100, 4 + state.commitment_expiry_rounds), vote(1), &mut effects).unwrap();
+        assert!(state.awaiting_reveal.is_empty());
+
+        let stats = &state.global_stats;
+        assert_eq!((stats.matches_resolved, stats.avg_wait_rounds), (1, 2.0));
+        assert_eq!(stats.cc_count + stats.cd_count + stats.dc_count + stats.dd_count, 0);
+        assert_eq!((stats.pot_inflow, stats.pot_outflow), (0, 0));
+    }
+
+    #[test]
+    fn aliases_are_hidden_in_anonymous_mode() {
+        let mut state = game();
+        state.aliases.insert([1; 32], "alice".to_string());
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap();
+        let id = state.history[0].id.clone();
+
+        state.handle_result(&env(3, 3, 0, 2), id.clone(), &mut effects).unwrap();
+        assert!(effects.logs.last().unwrap().contains("alice"));
+
+        state.anonymous = true;
+        effects = Effects::default();
+        state.handle_result(&env(3, 3, 0, 2), id, &mut effects).unwrap();
+        assert!(!effects.logs.last().unwrap().contains("alice"));
+
+        // The resolution event of a match played in anonymous mode
+        effects = Effects::default();
+        state.handle_play(&env(1, 4, 100, 2), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 5, 100, 2), vote(1), &mut effects).unwrap();
+        assert!(events(&effects).contains(&"match_resolved".to_string()));
+        assert!(effects.logs.iter().all(|log| !log.contains("alice")));
+    }
+
+    #[test]
+    fn results_are_found_in_every_pool() {
+        let mut state = game();
+        state.history_capacity = 1;
+        state.opt_out_random.insert([5; 32]);
+        let mut effects = Effects::default();
+
+        // Two resolved matches, the first of which gets archived
+        for tx in [1, 3] {
+            state.handle_play(&env(1, tx, 100, 1), vote(1), &mut effects).unwrap();
+            state.handle_play(&env(2, tx + 1, 100, 1), vote(1), &mut effects).unwrap();
+        }
+        let archived = state.archive_order[0].clone();
+        let resolved = state.history[0].id.clone();
+
+        let blinded = Ok(PlayRequest { vote: 5, blinded_vote: Some([9; 32]), ..vote(5).unwrap() });
+        state.handle_play(&env(3, 5, 100, 1), blinded, &mut effects).unwrap();
+        state.handle_play(&env(4, 6, 100, 1), vote(1), &mut effects).unwrap();
+        let unrevealed = state.awaiting_reveal[0].id.clone();
+
+        state.handle_play(&env(5, 7, 100, 1), vote(1), &mut effects).unwrap();
+        let challenge_only = state.challenge_only_waiting[0].id.clone();
+
+        let ids = vec![archived, resolved, unrevealed, challenge_only, "missing".to_string()];
+        effects = Effects::default();
+        state.handle_results_batch(&env(9, 9, 0, 1), &ids, &mut effects).unwrap();
+
+        let results: Vec<serde_json::Value> = serde_json::from_str(&effects.logs[0]).unwrap();
+        assert_eq!(results[0]["archived"], json!(true));
+        assert_eq!(results[1]["outcome"], json!(state.history[0].outcome()));
+        assert_eq!(results[2]["error"], json!("awaiting_reveal"));
+        assert_eq!(results[3]["error"], json!("pending"));
+        assert_eq!(results[4]["error"], json!("not_found"));
+
+        // The joiner of a match waiting for a reveal and a challenge-only player
+        for (tx, status) in [(6, "awaiting_reveal"), (7, "waiting")] {
+            effects = Effects::default();
+            state.handle_result_by_tx(&env(9, 9, 0, 1), [tx; 32], &mut effects).unwrap();
+            let result: serde_json::Value = serde_json::from_str(&effects.logs[0]).unwrap();
+            assert_eq!(result["status"], json!(status));
+        }
+    }
+
+    #[test]
+    fn expired_challenge_only_matches_are_swept() {
+        let mut state = game();
+        state.match_expiry_rounds = 10;
+        state.opt_out_random.insert([1; 32]);
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap();
+        assert_eq!((state.waiting.len(), state.challenge_only_waiting.len()), (1, 1));
+
+        state.handle_play(&env(3, 3, 100, 12), vote(1), &mut effects).unwrap();
+
+        assert!(state.challenge_only_waiting.is_empty());
+        assert_eq!(state.waiting.len(), 1);
+        assert_eq!(state.waiting[0].p1.sender, [3; 32]);
+        assert_eq!(state.balances.get(&[1; 32]), Some(&100));
+        assert_eq!(state.balances.get(&[2; 32]), Some(&100));
+    }
+
+    #[test]
+    fn opted_out_players_are_only_matched_by_challenges() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_set_random_matching(&env(1, 0, 0, 1), false, &mut effects).unwrap();
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        let id = state.challenge_only_waiting[0].id.clone();
+
+        // A random player waits instead of joining
+        state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap();
+        assert_eq!((state.waiting.len(), state.challenge_only_waiting.len()), (1, 1));
+
+        state.handle_challenge(&env(3, 3, 100, 2), Ok((id.clone(), 2)), &mut effects).unwrap();
+        assert!(state.challenge_only_waiting.is_empty());
+        assert_eq!(state.history[0].id, id);
+        assert_eq!(state.history[0].p2.as_ref().unwrap().sender, [3; 32]);
+        assert_eq!(state.waiting[0].p1.sender, [2; 32]);
+    }
+
+    #[test]
+    fn opting_back_in_returns_the_matches_to_their_tier() {
+        let mut state = game();
+        state.stake_tiers = vec![(0, 100), (101, 200)];
+        let mut effects = Effects::default();
+
+        state.handle_set_random_matching(&env(1, 0, 0, 1), false, &mut effects).unwrap();
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        state.handle_set_random_matching(&env(1, 0, 0, 2), true, &mut effects).unwrap();
+
+        assert!(state.challenge_only_waiting.is_empty());
+        assert_eq!(state.waiting.len(), 1);
+        assert!(!state.opt_out_random.contains(&[1; 32]));
+
+        // A player of another tier doesn't join it, one of the same tier does
+        state.handle_play(&env(2, 2, 150, 2), vote(1), &mut effects).unwrap();
+        assert_eq!(state.waiting.len(), 2);
+        state.handle_play(&env(3, 3, 50, 2), vote(1), &mut effects).unwrap();
+        assert_eq!(state.history[0].p1.sender, [1; 32]);
+        assert_eq!(state.history[0].p2.as_ref().unwrap().sender, [3; 32]);
+    }
+
+    #[test]
+    fn opting_back_in_refunds_the_matches_that_no_longer_fit() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_set_random_matching(&env(1, 0, 0, 1), false, &mut effects).unwrap();
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(1, 2, 300, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(1, 3, 200, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 4, 300, 1), vote(1), &mut effects).unwrap();
+
+        // The limits were tightened since the matches were queued
+        state.max_waiting_pool = 2;
+        state.min_stake = 150;
+        let mut effects = Effects::default();
+        state.handle_set_random_matching(&env(1, 0, 0, 2), true, &mut effects).unwrap();
+
+        // The first stake is too low now, and the last doesn't fit the pool
+        let stakes: Vec<u64> = state.waiting.iter().map(|m| m.p1.stake).collect();
+        assert_eq!(stakes, vec![300, 300]);
+        assert!(state.challenge_only_waiting.is_empty());
+        assert_eq!(state.balances.get(&[1; 32]), Some(&300));
+        assert_eq!(events(&effects), vec!["match_cancelled", "match_cancelled", "random_matching_changed"]);
+        state.verify_conservation().unwrap();
+    }
+
+    #[test]
+    fn match_entropy_mixes_both_transactions() {
+        let m = Match::new("a".to_string(), player(1, 100, 1), 1);
+        let joined = Match { p2: Some(Player { tx_id: [7; 32], ..player(2, 100, 1) }), ..m.clone() };
+
+        let seed = entropy_for_match(&m, &env(2, 7, 100, 1));
+        assert_eq!(seed, entropy_for_match(&m, &env(2, 7, 100, 1)));
+        assert_eq!(seed, entropy_for_match(&joined, &env(3, 9, 0, 1)));
+
+        // Either transaction id, and the round, change the seed
+        assert_ne!(seed, entropy_for_match(&m, &env(2, 8, 100, 1)));
+        let other_p1 = Match { p1: Player { tx_id: [8; 32], ..m.p1.clone() }, ..m.clone() };
+        assert_ne!(seed, entropy_for_match(&other_p1, &env(2, 7, 100, 1)));
+        assert_ne!(seed, entropy_for_match(&m, &env(2, 7, 100, 2)));
+    }
+
+    #[test]
+    fn abstaining_returns_both_stakes_minus_the_fee() {
+        let rules = PayoutRules {
+            schedule: RewardSchedule::default(),
+            abstain_fee_bps: 100,
+            jackpot_share_bps: None,
+            charity_share_bps: 0,
+            defector_bonus_cap_multiplier: 10,
+            defection_penalty_multiplier: 10_000,
+            repeat_defectors: (false, false),
+            min_pot_for_rewards: 0,
+        };
+
+        for &(vote1, vote2) in &[(3, 1), (3, 2), (3, 3), (1, 3), (2, 3)] {
+            let mut m = Match::new("a".to_string(), player(1, 1000, vote1), 1);
+            m.play(player(2, 2000, vote2), 1_000_000, &rules).unwrap();
+
+            assert_eq!((m.p1_payout, m.p2_payout), (990, 1980), "votes {} {}", vote1, vote2);
+            assert_eq!(m.pot_payout, 30, "votes {} {}", vote1, vote2);
+            assert_eq!(m.outcome(), "abstain");
+        }
+
+        // Without an abstention the pot pays out
+        let mut m = Match::new("a".to_string(), player(1, 1000, 1), 1);
+        m.play(player(2, 2000, 1), 1_000_000, &rules).unwrap();
+        assert!(m.pot_payout < 0);
+    }
+
+    #[test]
+    fn matches_record_how_long_they_waited() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 2), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(1, 3, 100, 2), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 4, 100, 7), vote(1), &mut effects).unwrap();
+
+        let waits: Vec<(u64, u64, u64)> = state.history.iter().map(|m| (m.created_round, m.resolved_round, m.wait_duration())).collect();
+        assert_eq!(waits, vec![(1, 2, 1), (2, 7, 5)]);
+        assert_eq!(state.global_stats.matches_resolved, 2);
+        assert!((state.global_stats.avg_wait_rounds - 1.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn average_wait_converges() {
+        let mut stats = GlobalStats::default();
+        stats.record_resolution(100);
+
+        let mut previous = stats.avg_wait_rounds;
+        for _ in 0..10 {
+            stats.record_resolution(10);
+            assert!(stats.avg_wait_rounds < previous && stats.avg_wait_rounds > 10.0);
+            previous = stats.avg_wait_rounds;
+        }
+
+        // 10 + 90 * 0.9^10
+        assert!((stats.avg_wait_rounds - (10.0 + 90.0 * 0.9f64.powi(10))).abs() < 1e-9);
+    }
+
+    #[test]
+    fn waiting_pool_is_limited() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        // A player's own matches can't be joined by the player, so they all
+        // wait, in a pool that is unlimited by default
+        for tx in 1..=5 {
+            state.handle_play(&env(1, tx, 100, 1), vote(1), &mut effects).unwrap();
+        }
+        assert_eq!(state.waiting.len(), 5);
+
+        state.max_waiting_pool = 6;
+        state.handle_play(&env(1, 6, 100, 1), vote(1), &mut effects).unwrap();
+        assert!(state.handle_play(&env(1, 7, 100, 1), vote(1), &mut effects).is_err());
+        assert_eq!(state.waiting.len(), 6);
+
+        // Cancelling frees a slot
+        let id = state.waiting[0].id.clone();
+        state.handle_cancel(&env(1, 8, 0, 1), &id, &mut effects).unwrap();
+        state.handle_play(&env(1, 9, 100, 1), vote(1), &mut effects).unwrap();
+        assert_eq!(state.waiting.len(), 6);
+    }
+
+    #[test]
+    fn side_bets_are_settled_by_the_outcome() {
+        for &(vote1, vote2, outcome) in &[(1, 1, 0), (2, 2, 1), (2, 1, 2), (1, 2, 3)] {
+            let mut state = game();
+            state.pot = 1_000_000;
+            state.total_deposits = 1_000_000;
+            let mut effects = Effects::default();
+
+            state.handle_play(&env(1, 1, 100, 1), vote(vote1), &mut effects).unwrap();
+            let id = state.waiting[0].id.clone();
+            state.handle_side_bet(&env(3, 2, 10, 1), Ok((id.clone(), outcome)), &mut effects).unwrap();
+            state.handle_side_bet(&env(4, 3, 10, 1), Ok((id, (outcome + 1) % 4)), &mut effects).unwrap();
+
+            let pot = state.pot;
+            state.handle_play(&env(2, 4, 100, 1), vote(vote2), &mut effects).unwrap();
+            let pot_change = state.history[0].pot_change;
+
+            assert!(state.side_bets.is_empty());
+            assert_eq!(state.balances.get(&[3; 32]), Some(&20), "outcome {}", outcome);
+            assert_eq!(state.balances.get(&[4; 32]).copied().unwrap_or(0), 0, "outcome {}", outcome);
+            // The winner is paid from the pot and the loser's stake goes into it
+            assert_eq!(state.pot as i128, pot as i128 + pot_change as i128, "outcome {}", outcome);
+        }
+    }
+
+    #[test]
+    fn side_bets_on_a_cancelled_match_are_refunded() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+        state.handle_side_bet(&env(3, 2, 10, 1), Ok((id.clone(), 0)), &mut effects).unwrap();
+
+        state.handle_cancel(&env(1, 3, 0, 1), &id, &mut effects).unwrap();
+
+        assert!(state.side_bets.is_empty());
+        assert_eq!(state.balances.get(&[3; 32]), Some(&10));
+    }
+
+    #[test]
+    fn waiting_matches_are_ordered_by_age() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        for (tx, round) in [(1, 5), (2, 10), (3, 2), (4, 5)] {
+            state.handle_play(&env(1, tx, 100, round), vote(1), &mut effects).unwrap();
+        }
+
+        let queued = |state: &GameState| state.waiting.iter().map(|m| (m.queued_at, m.p1.tx_id[0])).collect::<Vec<(u64, u8)>>();
+        assert_eq!(queued(&state), vec![(2, 3), (5, 1), (5, 4), (10, 2)]);
+
+        let id = state.waiting[1].id.clone();
+        state.handle_cancel(&env(1, 5, 0, 10), &id, &mut effects).unwrap();
+        assert_eq!(queued(&state), vec![(2, 3), (5, 4), (10, 2)]);
+
+        // The oldest match is joined first
+        state.handle_play(&env(2, 6, 100, 10), vote(1), &mut effects).unwrap();
+        assert_eq!(state.history[0].p1.tx_id, [3; 32]);
+    }
+
+    #[test]
+    fn unrevealed_commitments_expire_and_are_refunded() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        let nonce = [7; 32];
+        let expiry = state.commitment_expiry_rounds;
+
+        state.handle_commit_vote(&env(1, 1, 1000, 1), Ok(vote_commitment(fake_sha256, 1, nonce)), &mut effects).unwrap();
+
+        // Still pending at the last round before the expiry
+        state.expire_commitments(&env(2, 2, 0, 1 + expiry), &mut effects);
+        assert_eq!(state.pending_commits.len(), 1);
+        assert!(effects.transfers.is_empty());
+
+        state.expire_commitments(&env(2, 2, 0, 2 + expiry), &mut effects);
+        assert!(state.pending_commits.is_empty());
+        assert_eq!(effects.transfers, vec![([1; 32], 1000)]);
+
+        assert!(state.handle_reveal_vote(&env(1, 3, 0, 2 + expiry), 1, nonce, &mut effects).is_err());
+        assert!(state.waiting.is_empty());
+        state.verify_conservation().unwrap();
+    }
+
+    #[test]
+    fn escrow_holds_the_stakes_until_the_match_ends() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(1, 2, 500, 1), vote(2), &mut effects).unwrap();
+        let (resolved, cancelled) = (state.waiting[0].id.clone(), state.waiting[1].id.clone());
+        assert_eq!(state.escrow.values().map(|(p1, p2)| p1 + p2).sum::<u64>(), 1500);
+        state.verify_conservation().unwrap();
+
+        state.handle_cancel(&env(1, 3, 0, 1), &cancelled, &mut effects).unwrap();
+        assert!(!state.escrow.contains_key(&cancelled));
+        state.verify_conservation().unwrap();
+
+        state.handle_play(&env(2, 4, 1000, 1), vote(1), &mut effects).unwrap();
+        assert!(state.escrow.is_empty());
+        assert_eq!(state.history[0].id, resolved);
+        state.verify_conservation().unwrap();
+
+        // PERLs that appear from nowhere break the invariant
+        state.pot += 1;
+        assert!(state.verify_conservation().is_err());
+    }
+
+    #[test]
+    fn blinded_vote_resolves_once_revealed() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        let nonce = [7; 32];
+
+        let blinded = Ok(PlayRequest { vote: 5, blinded_vote: Some(vote_commitment(fake_sha256, 2, nonce)), ..vote(5).unwrap() });
+        state.handle_play(&env(1, 1, 100, 1), blinded, &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+        state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap();
+
+        let err = state.handle_reveal_blinded_vote(&env(1, 3, 0, 2), &id, 2, [8; 32], &mut effects).unwrap_err();
+        assert!(err.to_string().contains("COMMITMENT_MISMATCH"));
+        let err = state.handle_reveal_blinded_vote(&env(1, 3, 0, 2), &id, 1, nonce, &mut effects).unwrap_err();
+        assert!(err.to_string().contains("COMMITMENT_MISMATCH"));
+        assert!(state.handle_reveal_blinded_vote(&env(2, 3, 0, 2), &id, 2, nonce, &mut effects).is_err());
+        assert_eq!(state.awaiting_reveal.len(), 1);
+
+        state.handle_reveal_blinded_vote(&env(1, 3, 0, 2), &id, 2, nonce, &mut effects).unwrap();
+        assert!(state.awaiting_reveal.is_empty());
+        assert_eq!(state.history[0].outcome(), "dc");
+    }
+
+    // A game with a stake in every place a shutdown has to refund: a match
+    // waiting for a reveal, a waiting match with a side bet on it, a committed
+    // vote and a challenge-only match.
+    fn busy_game() -> GameState {
+        let mut state = game();
+        state.pot = 10_000;
+        state.total_deposits = 10_000;
+        let mut effects = Effects::default();
+
+        let blinded = Ok(PlayRequest { vote: 5, blinded_vote: Some(vote_commitment(fake_sha256, 2, [7; 32])), ..vote(5).unwrap() });
+        state.handle_play(&env(1, 1, 100, 1), blinded, &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 200, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(3, 3, 300, 1), vote(1), &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+        state.handle_side_bet(&env(4, 4, 40, 1), Ok((id, 0)), &mut effects).unwrap();
+        state.handle_commit_vote(&env(5, 5, 500, 1), Ok([8; 32]), &mut effects).unwrap();
+        state.handle_set_random_matching(&env(6, 0, 0, 1), false, &mut effects).unwrap();
+        state.handle_play(&env(6, 6, 600, 1), vote(2), &mut effects).unwrap();
+
+        assert_eq!((state.awaiting_reveal.len(), state.waiting.len(), state.challenge_only_waiting.len()), (1, 1, 1));
+        assert_eq!((state.side_bets.len(), state.pending_commits.len()), (1, 1));
+
+        state
+    }
+
+    #[test]
+    fn shutdown_refunds_every_stake_and_sweeps_the_pot() {
+        let mut state = busy_game();
+        let pot = state.pot;
+        let mut effects = Effects::default();
+
+        state.handle_shutdown(true, &mut effects).unwrap();
+
+        assert!(state.paused && state.shut_down);
+        assert!(state.waiting.is_empty() && state.challenge_only_waiting.is_empty() && state.awaiting_reveal.is_empty());
+        assert!(state.side_bets.is_empty() && state.pending_commits.is_empty() && state.escrow.is_empty());
+        for (sender, stake) in [(1, 100), (2, 200), (3, 300), (4, 40), (5, 500), (6, 600)] {
+            assert_eq!(state.balances.get(&[sender; 32]), Some(&stake), "player {}", sender);
+        }
+        assert_eq!((state.pot, state.balances.get(&[9; 32])), (0, Some(&pot)));
+
+        let shutdown: serde_json::Value = serde_json::from_str(effects.logs.last().unwrap()).unwrap();
+        assert_eq!(shutdown["event"], "contract_shutdown");
+        assert_eq!(shutdown["matches_unwound"], 3);
+        assert_eq!(shutdown["refunded"], 1740);
+        assert_eq!((shutdown["pot_swept"].as_u64(), shutdown["pot_burned"].as_u64()), (Some(pot), Some(0)));
+        assert!(state.verify_conservation().is_ok());
+    }
+
+    #[test]
+    fn shutdown_burns_the_pot_unless_swept() {
+        let mut state = busy_game();
+        let pot = state.pot;
+        let mut effects = Effects::default();
+
+        state.handle_shutdown(false, &mut effects).unwrap();
+
+        assert_eq!((state.pot, state.total_burned), (0, pot));
+        assert_eq!(state.balances.get(&[9; 32]), None);
+        assert_eq!(state.balances.values().sum::<u64>(), 1740);
+
+        let shutdown: serde_json::Value = serde_json::from_str(effects.logs.last().unwrap()).unwrap();
+        assert_eq!((shutdown["pot_swept"].as_u64(), shutdown["pot_burned"].as_u64()), (Some(0), Some(pot)));
+        assert!(state.verify_conservation().is_ok());
+    }
+
+    #[test]
+    fn second_shutdown_changes_nothing() {
+        let mut state = busy_game();
+        state.handle_shutdown(false, &mut Effects::default()).unwrap();
+        let before = serde_json::to_vec(&state).unwrap();
+        let mut effects = Effects::default();
+
+        state.handle_shutdown(true, &mut effects).unwrap();
+
+        assert_eq!(serde_json::to_vec(&state).unwrap(), before);
+        assert!(effects.logs.is_empty() && effects.transfers.is_empty());
+    }
+
+    #[test]
+    fn random_matches_conserve_the_deposits() {
+        let mut rng = rand::rngs::SmallRng::from_seed([79; 16]);
+        let mut state = game();
+        state.pot = 1_000_000;
+        state.total_deposits = 1_000_000;
+        let mut effects = Effects::default();
+
+        let mut outcomes = HashSet::new();
+        for i in 0..100u64 {
+            let (vote1, vote2) = (rng.gen_range(1, 3), rng.gen_range(1, 3));
+            let tx = (2 * i) as u8;
+            state.handle_play(&env(1, tx, rng.gen_range(1, 10_000), i), vote(vote1), &mut effects).unwrap();
+            state.handle_play(&env(2, tx + 1, rng.gen_range(1, 10_000), i), vote(vote2), &mut effects).unwrap();
+
+            state.verify_conservation().unwrap();
+            outcomes.insert(state.history.last().unwrap().outcome());
+        }
+
+        assert_eq!(outcomes.len(), 4);
+    }
+
+    #[test]
+    fn sweep_refunds_a_few_expired_matches_per_play() {
+        let mut state = game();
+        state.match_expiry_rounds = 10;
+        let mut effects = Effects::default();
+
+        for tx in 1..=7 {
+            state.handle_play(&env(1, tx, 100, 1), vote(1), &mut effects).unwrap();
+        }
+
+        let swept = |effects: &mut Effects| std::mem::take(&mut effects.logs).iter().filter(|l| l.contains("match_expired")).count();
+        swept(&mut effects);
+
+        // The play sweeps 5 of the 7 expired matches, and joins one of the others
+        state.handle_play(&env(3, 8, 100, 20), vote(1), &mut effects).unwrap();
+        assert_eq!(swept(&mut effects), 5);
+        assert_eq!(state.waiting.len(), 1);
+
+        // The next one sweeps the last, and waits for an opponent
+        state.handle_play(&env(2, 9, 100, 20), vote(1), &mut effects).unwrap();
+        assert_eq!(swept(&mut effects), 1);
+        assert_eq!(state.waiting[0].p1.sender, [2; 32]);
+
+        // Matches that did not expire yet stay
+        state.handle_play(&env(2, 10, 100, 30), vote(1), &mut effects).unwrap();
+        assert_eq!(swept(&mut effects), 0);
+        assert_eq!(state.waiting.len(), 2);
+    }
+
+    #[test]
+    fn mutual_defection_approaches_the_nash_equilibrium() {
+        for &(v, approached) in &[(2, true), (1, false)] {
+            let mut state = game();
+            let mut effects = Effects::default();
+
+            for i in 0..50u8 {
+                state.handle_play(&env(1, 2 * i, 100, 1), vote(v), &mut effects).unwrap();
+                state.handle_play(&env(2, 2 * i + 1, 100, 1), vote(v), &mut effects).unwrap();
+            }
+
+            let reported = events(&effects).contains(&"nash_equilibrium_approached".to_string());
+            assert_eq!(reported, approached, "vote {}", v);
+        }
+    }
+
+    #[test]
+    fn seen_transactions_are_forgotten_after_the_retention() {
+        let mut state = game();
+        state.tx_id_retention_rounds = 10;
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        assert!(state.handle_play(&env(1, 1, 100, 11), vote(1), &mut effects).is_err());
+        assert!(state.seen_tx_ids.contains(&[1; 32]));
+
+        state.evict_seen_tx_ids(12);
+        assert!(state.seen_tx_ids.is_empty());
+        assert!(state.seen_tx_rounds.is_empty());
+
+        state.handle_play(&env(1, 1, 100, 12), vote(1), &mut effects).unwrap();
+        assert_eq!(state.waiting.len(), 2);
+    }
+
+    #[test]
+    fn state_with_several_matches_round_trips_through_json() {
+        let mut state = game();
+        state.aliases.insert([1; 32], "alice".to_string());
+        let mut effects = Effects::default();
+
+        // Five matches between three players, the last one still waiting
+        for (i, &(p1, p2)) in [(1, 2), (2, 3), (3, 1), (1, 2)].iter().enumerate() {
+            let tx = 2 * i as u8;
+            state.handle_play(&env(p1, tx, 1000, i as u64), vote(1 + tx % 2), &mut effects).unwrap();
+            state.handle_play(&env(p2, tx + 1, 500, i as u64), vote(2), &mut effects).unwrap();
+        }
+        state.handle_play(&env(3, 100, 700, 5), vote(1), &mut effects).unwrap();
+
+        let json = serde_json::to_string(&state).unwrap();
+        let imported: GameState = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(imported.history.len(), 4);
+        assert_eq!(imported.waiting.len(), 1);
+        assert_eq!(imported.stats.len(), 3);
+        assert_eq!(imported.pot, state.pot);
+        assert_eq!(imported.balances, state.balances);
+        assert_eq!(imported.aliases, state.aliases);
+        assert_eq!(imported.escrow, state.escrow);
+        assert_eq!(imported.seen_tx_ids, state.seen_tx_ids);
+
+        // The matches and stats don't compare, so their JSON does
+        let to_value = |m: &Match| serde_json::to_value(m).unwrap();
+        assert_eq!(imported.history.iter().map(to_value).collect::<Vec<_>>(), state.history.iter().map(to_value).collect::<Vec<_>>());
+        assert_eq!(to_value(&imported.waiting[0]), to_value(&state.waiting[0]));
+        for (sender, stats) in &state.stats {
+            assert_eq!(serde_json::to_value(&imported.stats[sender]).unwrap(), serde_json::to_value(stats).unwrap());
+        }
+        assert_eq!(serde_json::to_value(&imported.global_stats).unwrap(), serde_json::to_value(&state.global_stats).unwrap());
+    }
+
+    #[test]
+    fn pruned_matches_resolve_from_the_archive() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        let mut ids = Vec::new();
+        for i in 0..150u8 {
+            let mut tx_id = [i; 32];
+            state.handle_play(&Env { tx_id, ..env(1, 0, 100, i as u64) }, vote(1), &mut effects).unwrap();
+            ids.push(state.waiting[0].id.clone());
+
+            tx_id[0] = !i;
+            state.handle_play(&Env { tx_id, ..env(2, 0, 100, i as u64) }, vote(2), &mut effects).unwrap();
+        }
+
+        assert_eq!(state.history.len(), DEFAULT_HISTORY_CAPACITY);
+        assert_eq!(state.archive.len(), 150 - DEFAULT_HISTORY_CAPACITY);
+
+        for (i, id) in ids.iter().enumerate() {
+            let mut result = Effects::default();
+            state.handle_result(&env(3, 0, 0, 200), id.clone(), &mut result).unwrap();
+
+            let archived = result.logs[0].contains(r#""archived":true"#);
+            assert_eq!(archived, i < 150 - DEFAULT_HISTORY_CAPACITY, "match {}", i);
+            assert!(result.logs[0].contains(r#""outcome":"cd""#), "match {}", i);
+        }
+    }
+
+    #[test]
+    fn threshold_modes_converge_over_fifty_steps() {
+        let steps = |mode: ThresholdMode, step: fn(ThresholdMode, u32) -> u32| {
+            (0..50).fold(50, |threshold, _| step(mode, threshold))
+        };
+
+        assert_eq!(steps(ThresholdMode::Linear, ThresholdMode::raise), 100);
+        assert_eq!(steps(ThresholdMode::Linear, ThresholdMode::lower), 0);
+
+        // Exponential stops short of 100 once the remaining distance is under 4.
+        let raised = steps(ThresholdMode::Exponential, ThresholdMode::raise);
+        assert!((97..=100).contains(&raised), "{}", raised);
+        assert_eq!(ThresholdMode::Exponential.raise(raised), raised);
+        assert_eq!(steps(ThresholdMode::Exponential, ThresholdMode::lower), 0);
+        assert_eq!(ThresholdMode::Exponential.raise(50), 62);
+        assert_eq!(ThresholdMode::Exponential.lower(50), 37);
+
+        assert_eq!(steps(ThresholdMode::Fixed, ThresholdMode::raise), 50);
+        assert_eq!(steps(ThresholdMode::Fixed, ThresholdMode::lower), 50);
+    }
+
+    #[test]
+    fn fixed_threshold_never_moves_during_play() {
+        let mut state = game();
+        state.threshold = 50;
+        let mut effects = Effects::default();
+
+        for i in 0..50u8 {
+            state.handle_play(&env(i, i, 100, i as u64), vote(1), &mut effects).unwrap();
+            assert_eq!(state.threshold, 50);
+        }
+    }
+
+    #[test]
+    fn milestones_pay_a_bonus_from_the_pot() {
+        let p = player(1, 1_000, 1);
+        let mut balances = HashMap::new();
+        let mut stats = HashMap::new();
+        let mut pot = 1_000;
+
+        // Counts short of a milestone do nothing.
+        for matches_played in 1..10 {
+            stats.insert(p.sender, PlayerStats { matches_played, ..PlayerStats::default() });
+            assert_eq!(check_milestones(1_000, &mut pot, &mut balances, &mut stats, &p), None);
+        }
+        assert_eq!(pot, 1_000);
+        assert!(balances.is_empty());
+        assert_eq!(stats[&p.sender].last_milestone, 0);
+
+        stats.get_mut(&p.sender).unwrap().matches_played = 10;
+        assert_eq!(check_milestones(1_000, &mut pot, &mut balances, &mut stats, &p), Some((10, 100)));
+        assert_eq!(pot, 900);
+        assert_eq!(balances[&p.sender], 100);
+        assert_eq!(stats[&p.sender].last_milestone, 10);
+
+        // A pot that can't cover the bonus still records the milestone.
+        pot = 50;
+        stats.get_mut(&p.sender).unwrap().matches_played = 50;
+        assert_eq!(check_milestones(1_000, &mut pot, &mut balances, &mut stats, &p), Some((50, 0)));
+        assert_eq!(pot, 50);
+        assert_eq!(balances[&p.sender], 100);
+        assert_eq!(stats[&p.sender].last_milestone, 50);
+    }
+
+    #[test]
+    fn cooperation_streaks_pay_at_their_milestones() {
+        let mut state = game();
+        state.pot = 100_000;
+        state.total_deposits = 100_000;
+        let play = |state: &mut GameState, i: u8, vote1: u8| {
+            let mut effects = Effects::default();
+            state.handle_play(&env(1, 2 * i, 100, i as u64), vote(vote1), &mut effects).unwrap();
+            state.handle_play(&env(2, 2 * i + 1, 100, i as u64), vote(1), &mut effects).unwrap();
+            effects.logs.iter().any(|l| l.contains("streak_bonus"))
+        };
+
+        assert!(!play(&mut state, 0, 1));
+        assert!(!play(&mut state, 1, 1));
+        assert!(play(&mut state, 2, 1));
+        assert_eq!(state.stats[&[1; 32]].cooperation_streak, 3);
+
+        // A defection breaks the streak, which has to be built up again
+        assert!(!play(&mut state, 3, 2));
+        assert_eq!(state.stats[&[1; 32]].cooperation_streak, 0);
+        assert!(!play(&mut state, 4, 1));
+        assert!(!play(&mut state, 5, 1));
+        assert!(play(&mut state, 6, 1));
+
+        let mut pot = 10_000;
+        let mut balances = HashMap::new();
+        let streak = |cooperation_streak| HashMap::from([([1; 32], PlayerStats { cooperation_streak, ..PlayerStats::default() })]);
+        assert_eq!(pay_streak_bonus(&state.streak_bonuses, &mut pot, &mut balances, &streak(4), [1; 32]), 0);
+        assert_eq!(pay_streak_bonus(&state.streak_bonuses, &mut pot, &mut balances, &streak(5), [1; 32]), 100);
+        assert_eq!(pot, 9_900);
+    }
+
+    #[test]
+    fn loyalty_tiers_start_at_their_match_counts() {
+        let tiers = [(10, 10), (50, 25)];
+
+        assert_eq!(loyalty_tier(&tiers, 0), (0, 0));
+        assert_eq!(loyalty_tier(&tiers, 9), (0, 0));
+        assert_eq!(loyalty_tier(&tiers, 10), (1, 10));
+        assert_eq!(loyalty_tier(&tiers, 49), (1, 10));
+        assert_eq!(loyalty_tier(&tiers, 50), (2, 25));
+        assert_eq!(loyalty_tier(&tiers, u64::MAX), (2, 25));
+        assert_eq!(loyalty_tier(&[], 100), (0, 0));
+    }
+
+    #[test]
+    fn referrers_are_paid_once_for_a_first_match() {
+        let mut pot = 1_000_000;
+        let mut balances = HashMap::new();
+        let mut referred = HashSet::new();
+        let referred_player = Player { referrer: Some([7; 32]), ..player(1, 100, 1) };
+        let played = |matches_played| HashMap::from([([1; 32], PlayerStats { matches_played, ..PlayerStats::default() })]);
+
+        // A referral is void once the first match is behind the player
+        assert_eq!(pay_referral(50, 1_000_000, &mut pot, &mut balances, &mut referred, &played(2), &referred_player), None);
+        assert!(referred.is_empty());
+
+        assert_eq!(pay_referral(50, 3_000, &mut pot, &mut balances, &mut referred, &played(1), &referred_player), Some(([7; 32], 3_000)));
+        assert_eq!(pay_referral(50, 3_000, &mut pot, &mut balances, &mut referred, &played(1), &referred_player), None);
+        assert_eq!(balances[&[7; 32]], 3_000);
+        assert_eq!(pot, 997_000);
+
+        let mut state = game();
+        let mut effects = Effects::default();
+        let self_referral = Ok(PlayRequest { referrer: to_hex_string([1; 32]), ..vote(1).unwrap() });
+        let err = state.handle_play(&env(1, 1, 100, 1), self_referral, &mut effects).unwrap_err();
+        assert!(err.to_string().contains("SELF_REFERRAL"));
+        assert_eq!(state.balances[&[1; 32]], 100);
+    }
+
+    #[test]
+    fn coop_jackpot_waits_for_a_pot() {
+        let mut balances = HashMap::new();
+        let mut stats = HashMap::new();
+        let mut s = PlayerStats::default();
+        s.recent_outcomes.extend([OUTCOME_MUTUAL_COOPERATION; COOP_JACKPOT_STREAK]);
+        stats.insert([1; 32], s);
+
+        // An empty pot leaves the streak for a later match
+        let mut pot = 0;
+        assert_eq!(pay_coop_jackpot(1_000, &mut pot, &mut balances, &mut stats, [1; 32]), 0);
+        assert_eq!(stats[&[1; 32]].recent_outcomes.len(), COOP_JACKPOT_STREAK);
+
+        let mut pot = 10_000;
+        assert_eq!(pay_coop_jackpot(1_000, &mut pot, &mut balances, &mut stats, [1; 32]), 1_000);
+        assert_eq!(pot, 9_000);
+        assert!(stats[&[1; 32]].recent_outcomes.is_empty());
+
+        stats.get_mut(&[1; 32]).unwrap().recent_outcomes.extend([OUTCOME_MUTUAL_COOPERATION, OUTCOME_OTHER, OUTCOME_MUTUAL_COOPERATION]);
+        assert_eq!(pay_coop_jackpot(1_000, &mut pot, &mut balances, &mut stats, [1; 32]), 0);
+    }
+
+    #[test]
+    fn charity_gets_its_share_of_a_mutual_defection() {
+        let mut state = game();
+        state.charity = Some([7; 32]);
+        state.charity_share_bps = 1_000;
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(2), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 1), vote(2), &mut effects).unwrap();
+        assert_eq!(state.balances[&[7; 32]], 20);
+        assert_eq!(state.pot, 180);
+
+        // Nothing goes to the charity without a mutual defection
+        state.handle_play(&env(1, 3, 100, 2), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 4, 100, 2), vote(2), &mut effects).unwrap();
+        assert_eq!(state.balances[&[7; 32]], 20);
+    }
+
+    #[test]
+    fn armed_jackpot_survives_a_dip_in_the_pot() {
+        let mut state = game();
+        state.jackpot_trigger = 1_000;
+        state.pot = 900;
+        state.total_deposits = 900;
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(2), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 1), vote(2), &mut effects).unwrap();
+        assert_eq!(state.pot, 1_100);
+        assert!(state.jackpot_armed);
+
+        // The pot drops back below the trigger before anyone claims the jackpot
+        state.decay_period = 10;
+        state.decay_bps = 5_000;
+        state.decay_pot(&env(3, 0, 0, 10), &mut effects);
+        assert_eq!(state.pot, 550);
+        assert!(state.jackpot_armed);
+
+        let mut effects = Effects::default();
+        state.handle_play(&env(1, 3, 100, 11), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 4, 100, 11), vote(1), &mut effects).unwrap();
+
+        let m = state.history.last().unwrap();
+        assert_eq!(m.jackpot_payout, 274);
+        assert_eq!((m.p1_payout, m.p2_payout), (237, 237));
+        assert!(!state.jackpot_armed);
+    }
+
+    #[test]
+    fn pot_decays_at_most_once_per_period() {
+        let mut state = game();
+        state.decay_period = 10;
+        state.decay_bps = 1_000;
+        state.pot = 10_000;
+        state.total_deposits = 10_000;
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(2), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 1), vote(2), &mut effects).unwrap();
+        assert_eq!(state.pot, 10_200);
+
+        let mut decays = Vec::new();
+        for round in [10, 15, 19, 20, 20, 45] {
+            let before = state.pot;
+            state.decay_pot(&env(3, 0, 0, round), &mut effects);
+            decays.push(before - state.pot);
+        }
+
+        assert_eq!(decays, vec![1_020, 0, 0, 918, 0, 826]);
+        assert_eq!(state.balances[&[1; 32]], 510 + 459 + 413);
+    }
+
+    #[test]
+    fn milestones_are_not_awarded_retroactively() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        for i in 0..12u8 {
+            state.handle_play(&env(1, 2 * i, 100, i as u64), vote(1), &mut effects).unwrap();
+            state.handle_play(&env(2, 2 * i + 1, 100, i as u64), vote(1), &mut effects).unwrap();
+        }
+        assert_eq!(state.stats[&[1; 32]].matches_played, 12);
+        assert_eq!(state.stats[&[1; 32]].last_milestone, 10);
+
+        // Turning the bonus on pays nothing for the milestone already passed.
+        state.milestone_bonus_bps = 10_000;
+        state.pot += 1_000_000;
+        state.total_deposits += 1_000_000;
+        let mut effects = Effects::default();
+        state.handle_play(&env(1, 100, 100, 20), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 101, 100, 20), vote(1), &mut effects).unwrap();
+
+        assert_eq!(state.stats[&[1; 32]].last_milestone, 10);
+        assert!(!effects.logs.iter().any(|l| l.contains("milestone")));
+    }
+
+    #[test]
+    fn batch_enters_every_match() {
+        let mut state = game();
+        state.opt_out_random.insert([1; 32]);
+        let mut effects = Effects::default();
+
+        let request = Ok(PlayManyRequest { count: 3, vote: 1, strategy_id: 0, min_opponent_stake: 0 });
+        state.handle_play_many(&env(1, 1, 1_001, 1), request, &mut effects).unwrap();
+
+        assert_eq!(state.challenge_only_waiting.len(), 3);
+        assert!(state.challenge_only_waiting.iter().all(|m| m.p1.stake == 333));
+        assert_eq!(state.balances.get(&[1; 32]), Some(&2));
+
+        let result: serde_json::Value = serde_json::from_str(effects.logs.last().unwrap()).unwrap();
+        assert_eq!(result["played"].as_array().unwrap().len(), 3);
+        assert_eq!(result["refund"], 2);
+        assert!(result.get("failed").is_none());
+    }
+
+    #[test]
+    fn batch_stops_at_the_first_failed_match() {
+        let mut state = game();
+        state.opt_out_random.insert([1; 32]);
+        state.max_waiting_pool = 2;
+        let mut effects = Effects::default();
+
+        let request = Ok(PlayManyRequest { count: 4, vote: 1, strategy_id: 0, min_opponent_stake: 0 });
+        state.handle_play_many(&env(1, 1, 1_000, 1), request, &mut effects).unwrap();
+
+        // The two matches that got in stay, the rest of the amount is refunded
+        assert_eq!(state.challenge_only_waiting.len(), 2);
+        assert_eq!(state.balances.get(&[1; 32]), Some(&500));
+        assert_eq!(state.total_deposits, 1_000);
+
+        let result: serde_json::Value = serde_json::from_str(effects.logs.last().unwrap()).unwrap();
+        assert_eq!(result["played"].as_array().unwrap().len(), 2);
+        assert_eq!(result["failed"]["index"], 2);
+        assert!(result["failed"]["error"].as_str().unwrap().contains("POOL_FULL"));
+    }
+
+    #[test]
+    fn empty_batch_is_rejected() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        let request = Ok(PlayManyRequest { count: 0, vote: 1, strategy_id: 0, min_opponent_stake: 0 });
+        let err = state.handle_play_many(&env(1, 1, 500, 1), request, &mut effects).unwrap_err();
+        assert!(err.to_string().contains("Between 1 and"));
+
+        let request = Ok(PlayManyRequest { count: MAX_PLAY_MANY_COUNT + 1, vote: 1, strategy_id: 0, min_opponent_stake: 0 });
+        state.handle_play_many(&env(1, 2, 500, 1), request, &mut effects).unwrap_err();
+
+        assert!(state.waiting.is_empty());
+        assert_eq!(state.balances.get(&[1; 32]), Some(&1_000));
+    }
+
+    #[test]
+    fn history_follows_its_capacity() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        let mut play_matches = |state: &mut GameState, from: u8, count: u8| {
+            for i in from..from + count {
+                state.handle_play(&env(1, 2 * i, 100, i as u64), vote(1), &mut effects).unwrap();
+                state.handle_play(&env(2, 2 * i + 1, 100, i as u64), vote(1), &mut effects).unwrap();
+            }
+        };
+
+        play_matches(&mut state, 0, 20);
+        assert_eq!(state.history.len(), 20);
+
+        assert_eq!(state.set_param("history_capacity", 5).unwrap(), DEFAULT_HISTORY_CAPACITY as u64);
+        assert_eq!(state.history.len(), 5);
+        assert_eq!(state.archive.len(), 15);
+
+        play_matches(&mut state, 20, 3);
+        assert_eq!(state.history.len(), 5);
+        assert_eq!(state.archive.len(), 18);
+
+        // A raised capacity keeps what is there and lets the history grow again
+        state.set_param("history_capacity", 10).unwrap();
+        assert_eq!(state.history.len(), 5);
+        play_matches(&mut state, 23, 8);
+        assert_eq!(state.history.len(), 10);
+        assert_eq!(state.archive.len(), 21);
+
+        assert!(state.set_param("history_capacity", 0).is_err());
+        assert_eq!(state.history_capacity, 10);
+    }
+
+    #[test]
+    fn small_pot_pays_only_the_stakes() {
+        let rules = |min_pot_for_rewards| PayoutRules {
+            schedule: RewardSchedule::default(),
+            abstain_fee_bps: 100,
+            jackpot_share_bps: None,
+            charity_share_bps: 0,
+            defector_bonus_cap_multiplier: 10,
+            defection_penalty_multiplier: 10_000,
+            repeat_defectors: (false, false),
+            min_pot_for_rewards,
+        };
+        let play = |vote2, pot, min_pot_for_rewards| {
+            let mut m = Match::new("a".to_string(), player(1, 1000, 1), 1);
+            m.play(player(2, 1000, vote2), pot, &rules(min_pot_for_rewards)).unwrap();
+            (m.p1_payout, m.p2_payout, m.pot_payout)
+        };
+
+        assert_eq!(play(1, 999_999, 1_000_000), (1000, 1000, 0));
+        assert_eq!(play(2, 999_999, 1_000_000), (0, 2000, 0));
+
+        for min_pot_for_rewards in [1_000_000, 0] {
+            let (p1, p2, pot) = play(1, 1_000_000, min_pot_for_rewards);
+            assert!(p1 > 1000 && p2 > 1000 && pot < 0);
+
+            let (p1, p2, pot) = play(2, 1_000_000, min_pot_for_rewards);
+            assert!(p1 == 0 && p2 > 2000 && pot < 0);
+        }
+
+        // Without a minimum even a tiny pot pays what it can
+        assert_eq!(play(1, 0, 0), (1000, 1000, 0));
+    }
+
+    #[test]
+    fn small_pot_is_logged() {
+        let mut state = game();
+        state.min_pot_for_rewards = 1_000;
+        state.pot = 999;
+        state.total_deposits = 999;
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap();
+        assert!(events(&effects).contains(&"pot_too_small".to_string()));
+        assert_eq!(state.pot, 999);
+
+        state.pot = 1_000;
+        state.total_deposits += 1;
+        let mut effects = Effects::default();
+        state.handle_play(&env(1, 3, 100, 2), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 4, 100, 2), vote(1), &mut effects).unwrap();
+        assert!(!events(&effects).contains(&"pot_too_small".to_string()));
+        assert!(state.pot < 1_000);
+    }
+
+    // Player 1 reaches a cooperation streak, a loyalty tier, a cooperation
+    // jackpot and a match milestone, and player 2 was referred.
+    fn play_for_every_bonus(pot: u64) -> (GameState, serde_json::Value) {
+        let mut state = game();
+        state.min_pot_for_rewards = 1_000;
+        state.pot = pot;
+        state.total_deposits = pot;
+        state.streak_bonuses = vec![(3, 100)];
+        state.loyalty_tiers = vec![(5, 100)];
+        state.referral_bps = 100;
+        state.jackpot_pct_bps = 100;
+        state.milestone_bonus_bps = 100;
+
+        let stats = state.stats.entry([1; 32]).or_default();
+        stats.matches_played = 9;
+        stats.cooperation_streak = 2;
+        stats.recent_outcomes = VecDeque::from(vec![OUTCOME_MUTUAL_COOPERATION; 2]);
+
+        let mut effects = Effects::default();
+        state.handle_play(&env(1, 1, 1000, 1), vote(1), &mut effects).unwrap();
+        let referred = Ok(PlayRequest { referrer: to_hex_string([7; 32]), ..vote(1).unwrap() });
+        state.handle_play(&env(2, 2, 1000, 1), referred, &mut effects).unwrap();
+
+        let resolved = effects.logs.iter()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .find(|event| event["event"] == "match_resolved")
+            .unwrap();
+
+        (state, resolved)
+    }
+
+    #[test]
+    fn bonuses_wait_for_the_minimum_pot() {
+        let (state, resolved) = play_for_every_bonus(1_000_000);
+        let (p1, p2) = (&resolved["player_1"], &resolved["player_2"]);
+        for bonus in ["streak_bonus", "loyalty_bonus", "coop_jackpot"] {
+            assert!(p1[bonus].as_u64().unwrap() > 0, "{}", bonus);
+        }
+        assert!(p1["milestone"]["bonus"].as_u64().unwrap() > 0);
+        assert!(p2["referral"]["reward"].as_u64().unwrap() > 0);
+        assert!(state.verify_conservation().is_ok());
+
+        let (state, resolved) = play_for_every_bonus(999);
+        let (p1, p2) = (&resolved["player_1"], &resolved["player_2"]);
+        for bonus in ["streak_bonus", "loyalty_bonus", "coop_jackpot"] {
+            assert!(p1[bonus].is_null(), "{}", bonus);
+        }
+        assert_eq!(p1["milestone"], serde_json::json!({ "matches_played": 10, "bonus": 0 }));
+        assert!(p2["referral"].is_null());
+        assert_eq!((state.balances.get(&[1; 32]), state.balances.get(&[2; 32]), state.pot), (Some(&1000), Some(&1000), 999));
+
+        // The referral is used up, the cooperation streak can still claim a jackpot later
+        assert!(state.referred.contains(&[2; 32]));
+        assert_eq!(state.stats[&[1; 32]].recent_outcomes.len(), COOP_JACKPOT_STREAK);
+        assert!(state.verify_conservation().is_ok());
+    }
+
+    #[test]
+    fn insurance_premium_goes_into_the_pot() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        let insured = Ok(PlayRequest { insured: true, ..vote(1).unwrap() });
+        state.handle_play(&env(1, 1, 1_000, 1), insured, &mut effects).unwrap();
+
+        assert_eq!(state.pot, 100);
+        assert_eq!(state.waiting[0].p1.stake, 900);
+        assert_eq!(state.waiting[0].p1.insurance_premium, 100);
+    }
+
+    #[test]
+    fn insurance_cover_is_capped_by_the_pot() {
+        let mut balances = HashMap::new();
+        let insured = Player { insured: true, ..player(1, 1_000, 1) };
+
+        let mut pot = 10_000;
+        assert_eq!(pay_insurance(&mut pot, &mut balances, &insured, 2), 500);
+        assert_eq!(pot, 9_500);
+
+        let mut pot = 100;
+        assert_eq!(pay_insurance(&mut pot, &mut balances, &insured, 2), 100);
+        assert_eq!(pot, 0);
+        assert_eq!(balances[&[1; 32]], 600);
+
+        // Only a betrayed cooperator is covered
+        let mut pot = 10_000;
+        assert_eq!(pay_insurance(&mut pot, &mut balances, &insured, 1), 0);
+        assert_eq!(pay_insurance(&mut pot, &mut balances, &player(1, 1_000, 1), 2), 0);
+        assert_eq!(pot, 10_000);
+    }
+
+    #[test]
+    fn insurance_handles_the_largest_stake() {
+        let mut state = game();
+        let mut effects = Effects::default();
+
+        let insured = Ok(PlayRequest { insured: true, ..vote(1).unwrap() });
+        state.handle_play(&env(1, 1, u64::MAX, 1), insured, &mut effects).unwrap();
+
+        assert_eq!(state.pot, u64::MAX / 10);
+        assert_eq!(state.waiting[0].p1.stake, u64::MAX - u64::MAX / 10);
+
+        let mut pot = u64::MAX;
+        let mut balances = HashMap::new();
+        let insured = Player { insured: true, ..player(1, u64::MAX, 1) };
+        assert_eq!(pay_insurance(&mut pot, &mut balances, &insured, 2), u64::MAX / 2);
+    }
+
+    #[test]
+    fn repeat_defectors_defected_in_three_of_the_last_five() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        let mut play = |state: &mut GameState, i: u8, vote1: u8| {
+            state.handle_play(&env(1, 2 * i, 100, i as u64), vote(vote1), &mut effects).unwrap();
+            state.handle_play(&env(2, 2 * i + 1, 100, i as u64), vote(1), &mut effects).unwrap();
+            state.stats[&[1; 32]].is_repeat_defector()
+        };
+
+        assert!(!play(&mut state, 0, 2));
+        assert!(!play(&mut state, 1, 2));
+        assert!(play(&mut state, 2, 2));
+
+        // The defections slide out of the window one by one
+        assert!(play(&mut state, 3, 1));
+        assert!(play(&mut state, 4, 1));
+        assert!(!play(&mut state, 5, 1));
+        assert_eq!(state.stats[&[1; 32]].recent_votes, vec![2, 2, 1, 1, 1]);
+        assert!(!state.stats[&[2; 32]].is_repeat_defector());
+    }
+
+    #[test]
+    fn repeat_defectors_win_less_and_lose_more() {
+        let rules = |repeat_defectors| PayoutRules {
+            schedule: RewardSchedule::default(),
+            abstain_fee_bps: 100,
+            jackpot_share_bps: None,
+            charity_share_bps: 0,
+            defector_bonus_cap_multiplier: 10,
+            defection_penalty_multiplier: 20_000,
+            repeat_defectors,
+            min_pot_for_rewards: 0,
+        };
+        let betrayal = |repeat_defectors| {
+            let mut m = Match::new("a".to_string(), player(1, 1000, 2), 1);
+            m.play(player(2, 1000, 1), 1_000_000, &rules(repeat_defectors)).unwrap();
+            m.p1_payout - 2000
+        };
+        assert_eq!(betrayal((false, false)), 2 * betrayal((true, false)));
+        assert_eq!(betrayal((false, true)), betrayal((false, false)));
+
+        // In a mutual defection the repeat defector pays the penalty from the balance
+        let mut state = game();
+        state.defection_penalty_multiplier = 15_000;
+        state.balances.insert([1; 32], 1_000);
+        state.total_deposits = 1_000;
+        let mut recent = PlayerStats::default();
+        recent.recent_votes.extend([2, 2, 2]);
+        state.stats.insert([1; 32], recent);
+        let mut effects = Effects::default();
+
+        state.handle_play(&env(1, 1, 100, 1), vote(2), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 1), vote(2), &mut effects).unwrap();
+
+        assert_eq!(state.balances[&[1; 32]], 950);
+        assert_eq!(state.pot, 250);
+        assert!(effects.logs.iter().any(|l| l.contains(r#""defection_penalty":50"#)));
+    }
+
+    #[test]
+    fn random_matching_draws_from_the_match_entropy() {
+        let mut state = game();
+        state.matching_strategy = MatchingStrategy::Random;
+        state.opt_out_random.insert([1; 32]);
+        let mut effects = Effects::default();
+
+        for i in 0..3u8 {
+            state.handle_play(&env(1, 10 + i, 100, 1), vote(1), &mut effects).unwrap();
+        }
+        state.waiting = std::mem::take(&mut state.challenge_only_waiting);
+        state.opt_out_random.clear();
+
+        let join = env(2, 20, 100, 2);
+        let expected = state.waiting[random_from(entropy_for_match(&state.waiting[0], &join)) as usize % 3].id.clone();
+
+        let mut replay: GameState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        state.handle_play(&join, vote(1), &mut effects).unwrap();
+        replay.handle_play(&join, vote(1), &mut effects).unwrap();
+
+        assert_eq!(state.history[0].id, expected);
+        assert_eq!(replay.history[0].id, expected);
+    }
+
+    fn code(err: Box<dyn Error>) -> String {
+        let error: serde_json::Value = serde_json::from_str(&err.to_string()).unwrap();
+        error["code"].as_str().unwrap().to_string()
+    }
+
+    // A game with a match of player 1 waiting.
+    fn waiting_game() -> (GameState, String) {
+        let mut state = game();
+        state.handle_play(&env(1, 1, 100, 1), vote(1), &mut Effects::default()).unwrap();
+        let id = state.waiting[0].id.clone();
+
+        (state, id)
+    }
+
+    #[test]
+    fn zero_stake_is_rejected() {
+        let (mut state, id) = waiting_game();
+        let mut effects = Effects::default();
+
+        assert_eq!(code(state.handle_play(&env(2, 2, 0, 1), vote(1), &mut effects).unwrap_err()), "ZERO_STAKE");
+        assert_eq!(code(state.handle_top_up(&env(1, 3, 0, 1), Ok(id.clone()), &mut effects).unwrap_err()), "ZERO_STAKE");
+        assert_eq!(code(state.handle_side_bet(&env(2, 4, 0, 1), Ok((id, 0)), &mut effects).unwrap_err()), "ZERO_STAKE");
+        assert_eq!(code(state.handle_commit_vote(&env(2, 5, 0, 1), Ok([0; 32]), &mut effects).unwrap_err()), "ZERO_STAKE");
+    }
+
+    #[test]
+    fn revealing_a_waiting_match_is_rejected() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        let blinded = Ok(PlayRequest { blinded_vote: Some(vote_commitment(fake_sha256, 1, [5; 32])), ..vote(5).unwrap() });
+        state.handle_play(&env(1, 1, 100, 1), blinded, &mut effects).unwrap();
+        let id = state.waiting[0].id.clone();
+
+        let err = state.handle_reveal_blinded_vote(&env(1, 2, 0, 1), &id, 1, [5; 32], &mut effects).unwrap_err();
+        assert_eq!(code(err), "MATCH_STILL_WAITING");
+        let err = state.handle_reveal_blinded_vote(&env(1, 2, 0, 1), "missing", 1, [5; 32], &mut effects).unwrap_err();
+        assert_eq!(code(err), "MATCH_NOT_FOUND");
+    }
+
+    #[test]
+    fn cancelling_a_played_match_is_rejected() {
+        let (mut state, id) = waiting_game();
+        let mut effects = Effects::default();
+        state.handle_play(&env(2, 2, 100, 1), vote(1), &mut effects).unwrap();
+
+        assert_eq!(code(state.handle_cancel(&env(1, 3, 0, 1), &id, &mut effects).unwrap_err()), "MATCH_ALREADY_PLAYED");
+    }
+
+    #[test]
+    fn cancelling_someone_elses_match_is_rejected() {
+        let (mut state, id) = waiting_game();
+
+        assert_eq!(code(state.handle_cancel(&env(2, 2, 0, 1), &id, &mut Effects::default()).unwrap_err()), "NOT_YOUR_MATCH");
+    }
+
+    #[test]
+    fn betting_on_or_challenging_an_own_match_is_rejected() {
+        let (mut state, id) = waiting_game();
+        let mut effects = Effects::default();
+        assert_eq!(code(state.handle_side_bet(&env(1, 2, 100, 1), Ok((id, 0)), &mut effects).unwrap_err()), "OWN_MATCH");
+
+        state.opt_out_random.insert([1; 32]);
+        state.handle_play(&env(1, 3, 100, 1), vote(1), &mut effects).unwrap();
+        let id = state.challenge_only_waiting[0].id.clone();
+        assert_eq!(code(state.handle_challenge(&env(1, 4, 100, 1), Ok((id, 1)), &mut effects).unwrap_err()), "OWN_MATCH");
+    }
+
+    #[test]
+    fn second_commitment_is_rejected_until_revealed() {
+        let mut state = game();
+        let mut effects = Effects::default();
+        state.handle_commit_vote(&env(1, 1, 100, 1), Ok(vote_commitment(fake_sha256, 1, [5; 32])), &mut effects).unwrap();
+
+        let err = state.handle_commit_vote(&env(1, 2, 100, 1), Ok([0; 32]), &mut effects).unwrap_err();
+        assert_eq!(code(err), "COOLDOWN_ACTIVE");
+    }
+
+    #[test]
+    fn revealing_without_a_commitment_is_rejected() {
+        let mut state = game();
+
+        let err = state.handle_reveal_vote(&env(1, 1, 0, 1), 1, [5; 32], &mut Effects::default()).unwrap_err();
+        assert_eq!(code(err), "COMMITMENT_NOT_FOUND");
+    }
+
+    #[test]
+    fn oversized_batch_is_rejected() {
+        let mut state = game();
+        let request = Ok(PlayManyRequest { count: MAX_PLAY_MANY_COUNT + 1, vote: 1, strategy_id: 0, min_opponent_stake: 0 });
+
+        let err = state.handle_play_many(&env(1, 1, 600, 1), request, &mut Effects::default()).unwrap_err();
+        assert_eq!(code(err), "INVALID_BATCH_SIZE");
+    }
+
+    #[test]
+    fn unknown_predicted_outcome_is_rejected() {
+        let (mut state, id) = waiting_game();
+
+        let err = state.handle_side_bet(&env(2, 2, 100, 1), Ok((id, 4)), &mut Effects::default()).unwrap_err();
+        assert_eq!(code(err), "INVALID_OUTCOME");
+    }
+
+    #[test]
+    fn cash_out_by_a_stranger_is_rejected() {
+        let mut state = game();
+        state.balances.insert([1; 32], 100);
+        state.total_deposits = 100;
+
+        let err = state.handle_cash_out(&env(2, 1, 0, 1), [1; 32], &mut Effects::default()).unwrap_err();
+        assert_eq!(code(err), "NOT_DELEGATE");
+    }
+
+    #[test]
+    fn approving_a_missing_multisig_request_is_rejected() {
+        let mut state = game();
+
+        let err = state.handle_multisig_approve(&env(2, 1, 0, 1), 7, &mut Effects::default()).unwrap_err();
+        assert_eq!(code(err), "MULTISIG_NOT_FOUND");
+    }
+
+    #[test]
+    fn approving_someone_elses_multisig_request_is_rejected() {
+        let mut state = game();
+        state.multisig_requests.push(MultisigRequest { requester: [1; 32], approver: [2; 32], amount: 10, nonce: 7, created_round: 1 });
+
+        let err = state.handle_multisig_approve(&env(3, 1, 0, 1), 7, &mut Effects::default()).unwrap_err();
+        assert_eq!(code(err), "NOT_APPROVER");
+    }
+
+    #[test]
+    fn out_of_range_parameter_is_rejected() {
+        let mut state = game();
+
+        assert_eq!(code(state.set_param("threshold", 101).unwrap_err()), "INVALID_PARAMETER");
+        assert_eq!(code(state.set_param("unknown", 1).unwrap_err()), "INVALID_PARAMETER");
+    }
+
+    #[test]
+    fn overflowing_amounts_are_rejected() {
+        assert_eq!(code(narrow_u64(u64::MAX as u128 + 1).unwrap_err()), "OVERFLOW");
+        assert_eq!(code(narrow_i64(i64::MIN as i128 - 1).unwrap_err()), "OVERFLOW");
+    }
+
+    #[test]
+    fn payout_beyond_the_pot_is_rejected() {
+        assert_eq!(code(pot_after_payout(10, -11).unwrap_err()), "PAYOUT_EXCEEDS_POT");
+        assert_eq!(pot_after_payout(10, -10).unwrap(), 0);
+    }
+
+    // A mixed pool, in which the players below the floor skip the matches of
+    // those above it and the other way around, whichever match is older.
+    #[test]
+    fn players_below_the_reputation_floor_only_meet_each_other() {
+        let mut state = game();
+        state.reputation_floor = 60;
+        let mut effects = Effects::default();
+
+        // Serial defectors at 50, cooperators at 100
+        for sender in 1..=6u8 {
+            let defector = sender <= 3;
+            let stats = state.stats.entry([sender; 32]).or_default();
+            stats.matches_played = 10;
+            if defector { stats.defections = 10 } else { stats.cooperations = 10 }
+            stats.reputation = stats.compute_reputation();
+        }
+        assert_eq!((reputation_of(&state.stats, &[1; 32]), reputation_of(&state.stats, &[4; 32])), (50, 100));
+
+        state.handle_play(&env(1, 1, 100, 1), vote(2), &mut effects).unwrap();
+        state.handle_play(&env(4, 4, 100, 2), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(5, 5, 100, 3), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(2, 2, 100, 4), vote(2), &mut effects).unwrap();
+        let pairs: Vec<([u8; 32], [u8; 32])> = state.history.iter().map(|m| (m.p1.sender, m.p2.as_ref().unwrap().sender)).collect();
+        assert_eq!(pairs, vec![([4; 32], [5; 32]), ([1; 32], [2; 32])]);
+
+        // Nobody of the other side joins, however long they waited
+        state.handle_play(&env(6, 6, 100, 5), vote(1), &mut effects).unwrap();
+        state.handle_play(&env(3, 3, 100, 6), vote(2), &mut effects).unwrap();
+        assert_eq!(state.waiting.iter().map(|m| m.p1.sender).collect::<Vec<[u8; 32]>>(), vec![[6; 32], [3; 32]]);
+
+        // Newcomers start above the floor
+        state.handle_play(&env(7, 7, 100, 7), vote(1), &mut effects).unwrap();
+        assert_eq!(state.history.last().unwrap().p1.sender, [6; 32]);
+        assert_eq!(state.waiting.iter().map(|m| m.p1.sender).collect::<Vec<[u8; 32]>>(), vec![[3; 32]]);
+    }
+
+    // Once per retention period, the pairs not seen for a whole period are
+    // forgotten, and count from one again.
+    #[test]
+    fn pairs_are_forgotten_after_the_retention_period() {
+        let mut state = game();
+        state.pair_retention_rounds = 10;
+
+        assert_eq!(state.record_pair([1; 32], [2; 32], 1), 1);
+        assert_eq!(state.record_pair([4; 32], [3; 32], 2), 1);
+        assert_eq!(state.record_pair([2; 32], [1; 32], 9), 2);
+
+        // Round 12 prunes, but the pair seen in round 2 is just within the period
+        assert_eq!(state.record_pair([5; 32], [6; 32], 12), 1);
+        assert_eq!(state.pairs.len(), 3);
+
+        // The next prune waits for the next period
+        assert_eq!(state.record_pair([6; 32], [5; 32], 13), 2);
+        assert_eq!(state.pairs.len(), 3);
+
+        assert_eq!(state.record_pair([1; 32], [2; 32], 20), 1);
+        let mut pairs: Vec<(u8, u8, u32)> = state.pairs.iter().map(|((a, b), record)| (a[0], b[0], record.times_played)).collect();
+        pairs.sort_unstable();
+        assert_eq!(pairs, vec![(1, 2, 1), (5, 6, 2)]);
+    }
+
+    // With stakes of 1000 and 600 the cap is 6000, which 1.5% of a pot of
+    // 400000 just reaches.
+    #[test]
+    fn defector_bonus_is_capped_from_the_exact_limit_on() {
+        let rules = PayoutRules {
+            schedule: RewardSchedule::default(),
+            abstain_fee_bps: 100,
+            jackpot_share_bps: None,
+            charity_share_bps: 0,
+            defector_bonus_cap_multiplier: 10,
+            defection_penalty_multiplier: 10_000,
+            repeat_defectors: (false, false),
+            min_pot_for_rewards: 0,
+        };
+        let betrayal = |pot: u64| {
+            let mut m = Match::new("a".to_string(), player(1, 1000, 1), 1);
+            m.play(player(2, 600, 2), pot, &rules).unwrap();
+            assert_eq!(m.p2_payout, 1600 + m.defector_bonus);
+            assert_eq!(m.pot_payout, -(m.defector_bonus as i64));
+            (m.defector_bonus_uncapped, m.defector_bonus)
+        };
+
+        assert_eq!(betrayal(10_000), (150, 150));
+        assert_eq!(betrayal(399_999), (5_999, 5_999));
+        assert_eq!(betrayal(400_000), (6_000, 6_000));
+        assert_eq!(betrayal(400_067), (6_001, 6_000));
+        assert_eq!(betrayal(100_000_000), (1_500_000, 6_000));
+    }
+
+    // Near u64::MAX the shares are exact down to the truncated last unit.
+    #[test]
+    fn bps_of_is_exact_near_u64_max() {
+        // u64::MAX is 1844674407370955 * 10000 + 1615
+        assert_eq!(bps_of(u64::MAX, 10_000), u64::MAX);
+        assert_eq!(bps_of(u64::MAX, 9_999), u64::MAX - 1_844_674_407_370_956);
+        assert_eq!(bps_of(u64::MAX, 150), 1_844_674_407_370_955 * 150 + 1615 * 150 / 10_000);
+        assert_eq!(bps_of(u64::MAX, 1), 1_844_674_407_370_955);
+        assert_eq!(bps_of(u64::MAX - 1615, 1), 1_844_674_407_370_955);
+        assert_eq!(bps_of(u64::MAX - 1616, 1), 1_844_674_407_370_954);
+        assert_eq!(bps_of(u64::MAX, 0), 0);
+
+        // More than the whole amount saturates instead of wrapping
+        assert_eq!(bps_of(u64::MAX, 20_000), u64::MAX);
+        assert_eq!(bps_of(u64::MAX, u64::MAX), u64::MAX);
+
+        // The cooperation rewards of a match on such a pot are exact too
+        let rules = PayoutRules {
+            schedule: RewardSchedule { high_water: u64::MAX, max_reward: u64::MAX, ..RewardSchedule::default() },
+            abstain_fee_bps: 100,
+            jackpot_share_bps: None,
+            charity_share_bps: 0,
+            defector_bonus_cap_multiplier: 10,
+            defection_penalty_multiplier: 10_000,
+            repeat_defectors: (false, false),
+            min_pot_for_rewards: 0,
+        };
+        let mut m = Match::new("a".to_string(), player(1, 1000, 1), 1);
+        m.play(player(2, 1000, 1), u64::MAX, &rules).unwrap();
+        assert_eq!((m.p1_payout, m.p2_payout), (1000 + u64::MAX / 100, 1000 + u64::MAX / 100));
+        assert_eq!(m.pot_payout, -2 * (u64::MAX / 100) as i64);
+    }
+
+    // The parts and the dust always add back up to the amount, and the dust
+    // is less than a part would be.
+    #[test]
+    fn splits_conserve_every_amount() {
+        let amounts = [0, 1, 2, 3, 7, 97, 999_983, 1_000_000_007, u64::MAX - 1, u64::MAX];
+        let parts = [0, 1, 2, 3, 7, 13, 9_999, u64::MAX - 1, u64::MAX];
+        for amount in amounts {
+            for parts in parts {
+                let (part, dust) = split_amount(amount, parts);
+                assert_eq!(part as u128 * parts as u128 + dust as u128, amount as u128, "{} / {}", amount, parts);
+                assert!(parts == 0 || dust < parts, "{} / {}", amount, parts);
+            }
+        }
+
+        // A jackpot of the whole pot pays out both shares and keeps the dust
+        let rules = PayoutRules {
+            schedule: RewardSchedule::default(),
+            abstain_fee_bps: 100,
+            jackpot_share_bps: Some(10_000),
+            charity_share_bps: 0,
+            defector_bonus_cap_multiplier: 10,
+            defection_penalty_multiplier: 10_000,
+            repeat_defectors: (false, false),
+            min_pot_for_rewards: 0,
+        };
+        for pot in [1, 3, 7, 97, 999_983] {
+            let mut m = Match::new("a".to_string(), player(1, 1000, 1), 1);
+            m.play(player(2, 1000, 1), pot, &rules).unwrap();
+            assert_eq!(m.p1_payout, m.p2_payout);
+            assert_eq!((m.p1_payout - 1000 + m.p2_payout - 1000) as i64, -m.pot_payout);
+            assert_eq!(pot as i64 + m.pot_payout, 1);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Stand-ins for the host functions of the runtime, so that the contract can
+// be called outside of it, by the tests and the fuzzer.
+
+use std::cell::{Cell, RefCell};
+
+use smart_contract::payload::{Parameters, ParametersBuilder, Readable};
+use smart_contract::transaction::Transfer;
+
+use crate::PAYLOAD_HEADER_SIZE;
+
+// The host functions of the runtime. The payload itself is passed in
+// through `Parameters`, only its length is asked from the host.
+thread_local! {
+    static PAYLOAD_LEN: Cell<usize> = const { Cell::new(PAYLOAD_HEADER_SIZE) };
+    pub static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    pub static TRANSFERS: RefCell<Vec<Transfer>> = const { RefCell::new(Vec::new()) };
+}
+
+#[no_mangle]
+extern "C" fn _payload_len() -> usize {
+    PAYLOAD_LEN.with(|len| len.get())
+}
+
+#[no_mangle]
+extern "C" fn _payload(_out: *mut u8) {}
+
+#[no_mangle]
+extern "C" fn _log(content: *const u8, len: usize) {
+    let content = unsafe { std::slice::from_raw_parts(content, len) };
+    LOGS.with(|logs| logs.borrow_mut().push(String::from_utf8_lossy(content).into_owned()));
+}
+
+#[no_mangle]
+extern "C" fn _result(_result: *const u8, _len: usize) {}
+
+#[no_mangle]
+extern "C" fn _send_transaction(_tag: u8, payload: *const u8, payload_len: usize) {
+    let payload = unsafe { std::slice::from_raw_parts(payload, payload_len) };
+    TRANSFERS.with(|transfers| transfers.borrow_mut().push(Transfer::read_from(payload, &mut 0)));
+}
+
+#[no_mangle]
+extern "C" fn _verify_ed25519(_pubkey: *const u8, _pubkey_len: usize, _data: *const u8, _data_len: usize, _sig: *const u8, _sig_len: usize) -> i32 {
+    1
+}
+
+// Not a real hash, but deterministic and spread over all of the output:
+// every output byte is the top byte of an FNV-1a hash of the data,
+// seeded with the byte's position.
+fn fake_hash(data: *const u8, data_len: usize, out: *mut u8, out_len: usize) -> i32 {
+    let data = unsafe { std::slice::from_raw_parts(data, data_len) };
+    let out = unsafe { std::slice::from_raw_parts_mut(out, out_len) };
+
+    for (j, o) in out.iter_mut().enumerate() {
+        let mut h = 0xcbf2_9ce4_8422_2325u64 ^ j as u64;
+        for b in data {
+            h = (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        *o = (h >> 56) as u8;
+    }
+
+    0
+}
+
+#[no_mangle]
+extern "C" fn _hash_blake2b_256(data: *const u8, data_len: usize, out: *mut u8, out_len: usize) -> i32 {
+    fake_hash(data, data_len, out, out_len)
+}
+
+#[no_mangle]
+extern "C" fn _hash_blake2b_512(data: *const u8, data_len: usize, out: *mut u8, out_len: usize) -> i32 {
+    fake_hash(data, data_len, out, out_len)
+}
+
+#[no_mangle]
+extern "C" fn _hash_sha256(data: *const u8, data_len: usize, out: *mut u8, out_len: usize) -> i32 {
+    fake_hash(data, data_len, out, out_len)
+}
+
+#[no_mangle]
+extern "C" fn _hash_sha512(data: *const u8, data_len: usize, out: *mut u8, out_len: usize) -> i32 {
+    fake_hash(data, data_len, out, out_len)
+}
+
+// The parameters of a call from the sender with the amount attached, in
+// the given round.
+pub fn parameters(sender: [u8; 32], tx_id: [u8; 32], amount: u64, round_idx: u64, payload: &[u8]) -> Parameters {
+    PAYLOAD_LEN.with(|len| len.set(PAYLOAD_HEADER_SIZE + payload.len()));
+
+    let mut builder = ParametersBuilder::new()
+        .with_round_idx(round_idx)
+        .with_round_id([round_idx as u8; 32])
+        .with_transaction_id(tx_id)
+        .with_sender(sender)
+        .with_amount(amount);
+    for b in payload {
+        builder.write(b);
+    }
+
+    builder.build()
+}
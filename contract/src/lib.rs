@@ -1,328 +1,3309 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 
-use rand::{Rng, SeedableRng};
 use serde_json::json;
 use smart_contract_macros::smart_contract;
 
+use smart_contract::crypto::{hash, HashAlgorithm};
 use smart_contract::log;
-use smart_contract::payload::Parameters;
+use smart_contract::payload::{Parameters, Readable};
 use smart_contract::transaction::{Transaction, Transfer};
 
-const MAX_HISTORY_CAPACITY: usize = 100;
-static mut COUNTER: u32 = 0;
+use crate::game::{
+    earnings_rank, from_hex_string, index_earnings, loyalty_tier, pair_key, reindex_earnings, reputation_of, split_amount, stake_tier, to_hex_string, update_balance,
+};
+use crate::game::{ContractError, Effects, Env, GameState, Match, MultisigRequest, PlayerStats, PlayManyRequest, PlayRequest, RewardSchedule, ThresholdMode};
+use crate::game::MatchingStrategy;
+use crate::game::{BADGE_NAMES, COOP_WINDOW_SIZE, DEFAULT_HISTORY_CAPACITY};
+use crate::events::{
+    AliasSet, AutoCashoutChanged, BalanceQueried, CharityChanged, ConfigChanged, DelegateRevoked, DelegateSet, Event, GlobalStatsQueried, MatchTransferred, MultisigRequested,
+    OwnershipAccepted, OwnershipProposed, PauseChanged, PlayerStatsReset, StateImported, SurplusDistributed, TiersChanged, SCHEMA_VERSION,
+};
+
+mod events;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+mod game;
+#[cfg(any(test, feature = "fuzzing"))]
+mod host;
+
+const MAX_LEADERBOARD_SIZE: u32 = 50;
+const EXPORT_CHUNK_SIZE: usize = 4096;
+const MAX_RESULTS_BATCH_SIZE: usize = 10;
+const MAX_ROUND_STATS_RANGE: u64 = 50;
+const MAX_ALIAS_LENGTH: usize = 32;
+const MAX_PAIR_HISTORY_SIZE: usize = 5;
+const MAX_HISTORY_PAGE_SIZE: u32 = 25;
+const MAX_BALANCES_PAGE_SIZE: u32 = 50;
+
+// `Parameters::load` reads the round index, round id, transaction id, sender
+// and amount before the function's own payload.
+const PAYLOAD_HEADER_SIZE: usize = 8 + 32 + 32 + 32 + 8;
+
+// The contract's state is the state of the game.
+type PrisonerDilemma = GameState;
+
+// The transaction the contract was called with.
+fn env(params: &Parameters) -> Env {
+    Env {
+        sender: params.sender,
+        tx_id: params.transaction_id,
+        amount: params.amount,
+        round_idx: params.round_idx,
+        round_id: params.round_id,
+        sha256,
+    }
+}
+
+// Logs the messages and sends the transfers a call of the game asked for.
+fn carry_out(effects: Effects) {
+    for message in &effects.logs {
+        log(message);
+    }
 
-fn generate_id() -> String {
-    unsafe {
-        COUNTER = COUNTER + 1;
-        COUNTER.to_string()
+    for (destination, amount) in effects.transfers {
+        Transfer {
+            destination,
+            amount,
+            func_name: vec![],
+            func_params: vec![],
+        }.send_transaction();
     }
 }
 
-fn to_hex_string(bytes: [u8; 32]) -> String {
-    let strs: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
-    strs.join("")
+// Runs a call of the game and carries out its effects. The state changes of a
+// failed call persist, and so do its effects.
+fn run<F>(env: Env, call: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&Env, &mut Effects) -> Result<(), Box<dyn Error>>,
+{
+    let mut effects = Effects::default();
+    let result = call(&env, &mut effects);
+    carry_out(effects);
+
+    result
 }
 
-fn random(params: &Parameters) -> u32 {
-    let mut seed: [u8; 16] = [0; 16];
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    hash(HashAlgorithm::Sha256, data, &mut digest).unwrap();
 
-    for (rref, val) in seed.iter_mut().zip(params.round_id.iter().zip(&params.transaction_id).map(|(a, b)| a + b)) {
-        *rref = val;
-    };
-    let mut rng = rand::rngs::SmallRng::from_seed(seed);
+    digest
+}
+
+// The Merkle leaf of a resolved match: sha256(match_id || p1_payout || p2_payout),
+// with the id as UTF-8 and the payouts in little endian.
+fn merkle_leaf(m: &Match) -> [u8; 32] {
+    let mut data = m.id.as_bytes().to_vec();
+    data.extend_from_slice(&m.p1_payout.to_le_bytes());
+    data.extend_from_slice(&m.p2_payout.to_le_bytes());
+
+    sha256(&data)
+}
 
-    return rng.gen_range(0, 100);
+// Hashes each pair of nodes into the next level. An odd node out is paired with itself.
+fn merkle_level(nodes: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    nodes.chunks(2)
+        .map(|pair| {
+            let right = if pair.len() == 2 { pair[1] } else { pair[0] };
+
+            let mut data = [0u8; 64];
+            data[..32].copy_from_slice(&pair[0]);
+            data[32..].copy_from_slice(&right);
+            sha256(&data)
+        })
+        .collect()
 }
 
-fn prune_old_history(p: &mut PrisonerDilemma) {
-    if p.history.len() > MAX_HISTORY_CAPACITY {
-        p.history.remove(0);
+fn compute_merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return [0; 32];
     }
+
+    let mut nodes = leaves.to_vec();
+    while nodes.len() > 1 {
+        nodes = merkle_level(&nodes);
+    }
+
+    nodes[0]
 }
 
-fn update_balance(balances: &mut HashMap<[u8; 32], u64>, sender: [u8; 32], amount: i64) {
-    let recipient_balance = match balances.get(&sender) {
-        Some(balance) => *balance,
-        None => 0,
-    };
+// The siblings from the leaf up to the root. Whether a sibling is on the left
+// or on the right follows from the bits of the leaf index.
+fn compute_proof(leaves: &[[u8; 32]], mut index: usize) -> Vec<[u8; 32]> {
+    let mut proof = Vec::new();
 
-    let mut updated = recipient_balance as i64 + amount;
-    if updated.is_negative() {
-        // This should never happen.
+    let mut nodes = leaves.to_vec();
+    while nodes.len() > 1 {
+        let sibling = if index.is_multiple_of(2) { (index + 1).min(nodes.len() - 1) } else { index - 1 };
+        proof.push(nodes[sibling]);
 
-        updated = 0
+        nodes = merkle_level(&nodes);
+        index /= 2;
     }
 
-    balances.insert(sender, updated as u64);
+    proof
 }
 
-#[derive(Debug, Clone)]
-struct Player {
-    sender: [u8; 32],
-    tx_id: [u8; 32],
-    stake: u64,
-    vote: u8,
+// Payload parameters that can be read without running past the end of the
+// payload, which `Parameters::read` doesn't check. Returns the value and the
+// number of bytes it took up.
+trait Param: Sized {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError>;
 }
 
-#[derive(Debug, Clone)]
-struct Match {
-    id: String,
-    p1: Player,
-    p2: Option<Player>,
-
-    // The amout goes into Player 1 balance.
-    p1_payout: u64,
-    // The amout goes into Player 2 balance.
-    p2_payout: u64,
-    // The amout goes into pot or minus the pot.
-    pot_payout: i64,
-}
+fn read_fixed<T: Readable>(params: &mut Parameters, remaining: usize) -> Result<(T, usize), ContractError> {
+    let size = std::mem::size_of::<T>();
 
-impl Match {
-    pub fn new(id: String, player: Player) -> Match {
-        let m = Match {
-            id: id,
-            p1: player,
-            p2: None,
-            p1_payout: 0,
-            p2_payout: 0,
-            pot_payout: 0,
-        };
+    if remaining == 0 {
+        return Err(ContractError::MissingParameter);
+    }
+    if remaining < size {
+        return Err(ContractError::MalformedParameter);
+    }
+
+    Ok((params.read(), size))
+}
 
-        return m;
+impl Param for u8 {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError> {
+        read_fixed(params, remaining)
     }
+}
 
-    pub fn play(&mut self, p2: Player, pot: u64) {
-        if self.p1.vote == 2 && p2.vote == 2 {
-            // Both players lose the stakes. The stakes go to the pot
+impl Param for u32 {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError> {
+        read_fixed(params, remaining)
+    }
+}
 
-            self.p1_payout = 0;
-            self.p2_payout = 0;
+impl Param for u64 {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError> {
+        read_fixed(params, remaining)
+    }
+}
 
-            self.pot_payout = (self.p1.stake + p2.stake) as i64;
-        } else if self.p1.vote == 1 && p2.vote == 1 {
-            // Both players get back their stakes plus pot rewards
+impl Param for [u8; 32] {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError> {
+        read_fixed(params, remaining)
+    }
+}
 
-            let p1_pot_payout = (0.01 * pot as f64) as u64;
-            self.p1_payout = self.p1.stake + p1_pot_payout;
+impl Param for bool {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError> {
+        match read_fixed::<u8>(params, remaining)? {
+            (0, size) => Ok((false, size)),
+            (1, size) => Ok((true, size)),
+            _ => Err(ContractError::MalformedParameter),
+        }
+    }
+}
 
-            let p2_pot_payout = (0.01 * pot as f64) as u64;
-            self.p2_payout = p2.stake + p2_pot_payout;
+impl Param for String {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError> {
+        let bytes = read_terminated(params, remaining)?;
+        let size = bytes.len() + 1;
 
-            self.pot_payout = -(p1_pot_payout + p2_pot_payout) as i64;
-        } else if self.p1.vote == 1 && p2.vote == 2 {
-            // Player  1 lose his stake
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok((s, size)),
+            Err(_) => Err(ContractError::MalformedParameter),
+        }
+    }
+}
 
-            self.p1_payout = 0;
+impl Param for Vec<u64> {
+    fn read_checked(params: &mut Parameters, remaining: usize) -> Result<(Self, usize), ContractError> {
+        let (len, len_size) = read_fixed::<usize>(params, remaining)?;
 
-            // Player 2 get back his stake, plus Player 1 stake and pot reward
+        let size = len.checked_mul(8).and_then(|size| size.checked_add(len_size));
+        match size {
+            Some(size) if size <= remaining => Ok(((0..len).map(|_| params.read()).collect(), size)),
+            _ => Err(ContractError::MalformedParameter),
+        }
+    }
+}
 
-            let p2_pot_payout = (0.015 * pot as f64) as u64;
-            self.p2_payout = (p2.stake + self.p1.stake) + p2_pot_payout;
+// Reads the next parameter, which starts `consumed` bytes into the payload.
+fn read_param<T: Param>(params: &mut Parameters, consumed: &mut usize) -> Result<T, ContractError> {
+    let remaining = payload_size().saturating_sub(*consumed);
+    let (value, size) = T::read_checked(params, remaining)?;
+    *consumed += size;
 
-            self.pot_payout = -p2_pot_payout as i64;
-        } else if self.p1.vote == 2 && p2.vote == 1 {
-            // Player 1 get back his stake, plus Player 2 stake and pot reward
+    Ok(value)
+}
 
-            let p1_pot_payout = (0.015 * pot as f64) as u64;
-            self.p1_payout = (p2.stake + self.p1.stake) + p1_pot_payout;
+// Reads a null terminated string, without the terminator.
+fn read_terminated(params: &mut Parameters, remaining: usize) -> Result<Vec<u8>, ContractError> {
+    if remaining == 0 {
+        return Err(ContractError::MissingParameter);
+    }
 
-            self.pot_payout = -p1_pot_payout as i64;
+    let mut bytes = Vec::new();
 
-            // Player 2 lose his stake
+    loop {
+        if bytes.len() == remaining {
+            return Err(ContractError::MalformedParameter);
+        }
 
-            self.p2_payout = 0;
+        let b: u8 = params.read();
+        if b == 0 {
+            break;
         }
 
-        self.p2 = Some(p2);
+        bytes.push(b);
     }
+
+    Ok(bytes)
 }
 
-struct PrisonerDilemma {
-    balances: HashMap<[u8; 32], u64>,
-    pot: u64,
-    threshold: u32,
-    waiting: Vec<Match>,
-    history: Vec<Match>,
+// Reads a null terminated string as raw bytes, so that invalid UTF-8 can be
+// rejected with its own message.
+fn read_string_bytes(params: &mut Parameters, consumed: &mut usize) -> Result<Vec<u8>, ContractError> {
+    let remaining = payload_size().saturating_sub(*consumed);
+    let bytes = read_terminated(params, remaining)?;
+    *consumed += bytes.len() + 1;
+
+    Ok(bytes)
 }
 
-#[smart_contract]
-impl PrisonerDilemma {
-    fn init(_params: &mut Parameters) -> Self {
-        Self {
-            balances: HashMap::new(),
-            threshold: 50,
-            pot: 0,
-            waiting: Vec::new(),
-            history: Vec::new(),
-        }
+// The size of the function's own payload, used to detect optional trailing parameters.
+fn payload_size() -> usize {
+    unsafe { smart_contract::sys::_payload_len() }.saturating_sub(PAYLOAD_HEADER_SIZE)
+}
+
+// Reads the payload of a play. The vote is a big-endian 2-byte field: the
+// vote, then the id of the strategy the player is a bot for, which is 0 for humans.
+fn read_play_request(params: &mut Parameters) -> Result<PlayRequest, ContractError> {
+    let mut consumed = 0;
+    let vote: u8 = read_param(params, &mut consumed)?;
+    let strategy_id: u8 = read_param(params, &mut consumed)?;
+
+    // A conditional vote is followed by the opponent stake needed to cooperate,
+    // and a blinded vote by the commitment to the vote.
+    let mut min_opponent_stake = 0;
+    let mut blinded_vote = None;
+    if vote == 4 {
+        min_opponent_stake = read_param(params, &mut consumed)?;
+    }
+    if vote == 5 {
+        blinded_vote = Some(read_param(params, &mut consumed)?);
     }
 
-    fn play(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
-        let sender = params.sender;
-        let tx_id = params.transaction_id;
-        let amount: u64 = params.amount;
+    // The referrer is optional, an empty string means there is none.
+    let mut referrer = String::new();
+    if payload_size() > consumed {
+        referrer = read_param(params, &mut consumed)?;
+    }
 
-        let vote: u8 = params.read();
+    // Insurance is optional too, and follows the referrer.
+    let mut insured = false;
+    if payload_size() > consumed {
+        insured = read_param(params, &mut consumed)?;
+    }
 
-        if vote != 1 && vote != 2 {
-            return Err("Vote must be either 1 (cooperate) or 2 (defect).".into());
-        }
+    Ok(PlayRequest {
+        vote,
+        strategy_id,
+        min_opponent_stake,
+        blinded_vote,
+        referrer,
+        insured,
+    })
+}
 
-        let p = Player {
-            sender: sender,
-            tx_id: tx_id,
-            stake: amount,
-            vote: vote,
-        };
+fn read_play_many_request(params: &mut Parameters) -> Result<PlayManyRequest, ContractError> {
+    let mut consumed = 0;
+    let count: u8 = read_param(params, &mut consumed)?;
+    let vote: u8 = read_param(params, &mut consumed)?;
+    let strategy_id: u8 = read_param(params, &mut consumed)?;
 
-        if random(params) > self.threshold {
-            // Create a new match for the player and put the match into the waiting pool
+    let mut min_opponent_stake = 0;
+    if vote == 4 {
+        min_opponent_stake = read_param(params, &mut consumed)?;
+    }
 
-            self.threshold += 1;
+    Ok(PlayManyRequest {
+        count,
+        vote,
+        strategy_id,
+        min_opponent_stake,
+    })
+}
 
-            let id = generate_id();
-            self.waiting.push(Match::new(id.clone(), p));
+// Reads a match id followed by a byte, the vote of a challenge or the
+// predicted outcome of a side bet.
+fn read_match_id_and_u8(params: &mut Parameters) -> Result<(String, u8), ContractError> {
+    let mut consumed = 0;
+    let id: String = read_param(params, &mut consumed)?;
+    let value: u8 = read_param(params, &mut consumed)?;
 
-            let result = json!({
-                "match_id": id,
-            });
+    Ok((id, value))
+}
 
-            log(&result.to_string());
 
-            return Ok(());
+impl PrisonerDilemma {
+    // The read-only queries. Their entry points only forward to them, so a
+    // query can never change the state.
+
+    fn query_get_history(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let offset: u32 = read_param(params, &mut consumed)?;
+        let limit: u32 = read_param(params, &mut consumed)?;
+
+        // The outcome filter is optional, and is either "cc", "cd", "dc", "dd" or "any".
+        let mut filter = String::from("any");
+        if payload_size() > consumed {
+            filter = read_param(params, &mut consumed)?;
         }
 
-        if self.threshold > 0 {
-            self.threshold -= 1;
+        if filter != "any" && filter != "cc" && filter != "cd" && filter != "dc" && filter != "dd" {
+            return Err(ContractError::InvalidParameter("The outcome must be either cc, cd, dc, dd or any.".to_string()).into());
         }
 
-        // Put the player with the first match in the waiting pool.
-        // If there's no match in the waiting pool, create a new match for the player.
-        let index = match self.waiting.iter_mut().position(|m| m.p1.sender != sender) {
-            Some(v) => v,
-            None => {
-                let id = generate_id();
-                self.waiting.push(Match::new(id.clone(), p));
+        let filtered: Vec<&Match> = self.history.iter()
+            .filter(|m| filter == "any" || m.outcome() == filter)
+            .collect();
+
+        // Most recent matches first
+        let matches: Vec<serde_json::Value> = filtered.iter().rev()
+            .skip(offset as usize)
+            .take(limit.min(MAX_HISTORY_PAGE_SIZE) as usize)
+            .map(|m| {
+                let p2 = m.p2.as_ref().unwrap();
+                json!({
+                    "match_id": m.id,
+                    "player_1": json!({
+                        "sender": self.log_address(&env, m.p1.sender, m.resolved_round),
+                        "payout": m.p1_payout,
+                        "vote": m.p1.vote,
+                    }),
+                    "player_2": json!({
+                        "sender": self.log_address(&env, p2.sender, m.resolved_round),
+                        "payout": m.p2_payout,
+                        "vote": p2.vote,
+                    }),
+                    "pot_change": m.pot_change,
+                    "outcome": m.outcome(),
+                })
+            })
+            .collect();
+
+        // Older matches have been pruned, so clients can tell when they reached the end
+        let result = json!({
+            "total_available": filtered.len(),
+            "matches": matches,
+        });
+        log(&result.to_string());
 
-                let result = json!({
-                    "match_id": id,
-                });
+        Ok(())
+    }
 
-                log(&result.to_string());
+    fn query_history_for(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        // An empty address means the sender.
+        let mut consumed = 0;
+        let address: String = read_param(params, &mut consumed)?;
+        let limit: u32 = read_param(params, &mut consumed)?;
+
+        let address = if address.is_empty() {
+            params.sender
+        } else {
+            from_hex_string(&address)?
+        };
 
-                return Ok(());
-            }
+        let ids = match self.player_history.get(&address) {
+            Some(ids) => ids.as_slice(),
+            None => &[],
         };
 
-        let m = self.waiting.get_mut(index).unwrap();
-        m.play(p, self.pot);
+        // Newest first. Matches that were pruned from the history are skipped.
+        let matches: Vec<serde_json::Value> = ids.iter().rev()
+            .filter_map(|id| self.history.iter().find(|m| m.id == *id))
+            .take(limit.min(MAX_HISTORY_PAGE_SIZE) as usize)
+            .map(|m| {
+                let p2 = m.p2.as_ref().unwrap();
+                let (you, you_payout, opponent, opponent_payout) = if m.p1.sender == address {
+                    (&m.p1, m.p1_payout, p2, m.p2_payout)
+                } else {
+                    (p2, m.p2_payout, &m.p1, m.p1_payout)
+                };
+
+                json!({
+                    "match_id": m.id,
+                    "resolved_round": m.resolved_round,
+                    "you": json!({
+                        "payout": you_payout,
+                        "vote": you.vote,
+                    }),
+                    "opponent": json!({
+                        "sender": self.log_address(&env, opponent.sender, m.resolved_round),
+                        "payout": opponent_payout,
+                        "vote": opponent.vote,
+                    }),
+                })
+            })
+            .collect();
+
+        log(&json!(matches).to_string());
 
-        let p2 = m.p2.clone().unwrap();
+        Ok(())
+    }
 
-        // Update the players' balances
 
-        update_balance(&mut self.balances, p2.sender, m.p2_payout as i64);
-        update_balance(&mut self.balances, m.p1.sender, m.p1_payout as i64);
+    fn query_match_status(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let id: String = read_param(params, &mut consumed)?;
 
-        // Update the pot.
+        let mut result = json!({
+            "match_id": id,
+            "status": "unknown",
+        });
 
-        let mut new_pot: i64 = self.pot as i64 + m.pot_payout;
-        if new_pot < 0 {
-            new_pot = 0;
+        if let Some(m) = self.waiting.iter().chain(self.challenge_only_waiting.iter()).find(|m| m.id == id) {
+            result["status"] = json!("waiting");
+            result["stake"] = json!(m.p1.stake);
+            result["age"] = json!(params.round_idx.saturating_sub(m.created_round));
+        } else if let Some(m) = self.awaiting_reveal.iter().find(|m| m.id == id) {
+            result["status"] = json!("awaiting_reveal");
+            result["stake"] = json!(m.p1.stake);
+            result["age"] = json!(params.round_idx.saturating_sub(m.created_round));
+        } else if let Some(m) = self.history.iter().find(|m| m.id == id) {
+            result["status"] = json!("resolved");
+            result["outcome"] = json!(m.outcome());
+        } else if let Some(archived) = self.archive.get(&id) {
+            result["status"] = json!("resolved");
+            result["outcome"] = json!(archived.outcome);
+            result["archived"] = json!(true);
         }
-        self.pot = new_pot as u64;
 
-        // Generate the match result
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_result_by_tx(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let tx_id: String = read_param(params, &mut consumed)?;
+
+        let tx_id = match from_hex_string(&tx_id) {
+            Ok(tx_id) => tx_id,
+            Err(_) => return Err(ContractError::InvalidParameter("The transaction id must be 64 hex characters.".to_string()).into()),
+        };
+
+        run(env, |env, effects| self.handle_result_by_tx(env, tx_id, effects))
+    }
+
+    fn query_get_queue_position(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let id: String = read_param(params, &mut consumed)?;
+
+        let position = match self.waiting.iter().position(|m| m.id == id) {
+            Some(position) => position,
+            None => {
+                return Err(ContractError::MatchNotFound.into());
+            }
+        };
 
         let result = json!({
-            "match_id": m.id,
-            "player_1": json!({
-                            "sender": to_hex_string(m.p1.sender),
-                            "payout": m.p1_payout,
-                        }),
-            "player_2": json!({
-                            "sender": to_hex_string(p2.sender),
-                            "payout": m.p2_payout,
-                        }),
+            "match_id": id,
+            "position": position,
+            "queue_length": self.waiting.len(),
         });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_my_matches(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        // The votes of waiting matches stay hidden
+        let random = self.waiting.iter().map(|m| (m, "random"));
+        let challenge = self.challenge_only_waiting.iter().map(|m| (m, "challenge"));
+
+        let matches: Vec<serde_json::Value> = random.chain(challenge)
+            .filter(|(m, _)| m.p1.sender == params.sender)
+            .map(|(m, kind)| {
+                json!({
+                    "match_id": m.id,
+                    "stake": m.p1.stake,
+                    "created_round": m.created_round,
+                    "kind": kind,
+                })
+            })
+            .collect();
+
+        log(&json!(matches).to_string());
+
+        Ok(())
+    }
+
+    fn query_get_results_batch(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let ids: String = read_param(params, &mut consumed)?;
+        let ids: Vec<String> = serde_json::from_str(&ids)?;
+
+        if ids.len() > MAX_RESULTS_BATCH_SIZE {
+            return Err(ContractError::InvalidParameter(format!("At most {} matches can be queried at once.", MAX_RESULTS_BATCH_SIZE)).into());
+        }
+
+        run(env, |env, effects| self.handle_results_batch(env, &ids, effects))
+    }
+
+    fn query_get_pair_history(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let a: String = read_param(params, &mut consumed)?;
+        let b: String = read_param(params, &mut consumed)?;
 
-        // Save the match into the history list
-        self.history.push(m.clone());
+        let a = from_hex_string(&a)?;
+        let b = from_hex_string(&b)?;
 
-        // Remove the match from the waiting list
-        self.waiting.remove(index);
+        let times_played = match self.pairs.get(&pair_key(a, b)) {
+            Some(record) => record.times_played,
+            None => 0,
+        };
 
-        // Prune old history if needed
-        prune_old_history(self);
+        let recent: Vec<serde_json::Value> = self.history.iter().rev()
+            .filter(|m| pair_key(m.p1.sender, m.p2.as_ref().unwrap().sender) == pair_key(a, b))
+            .take(MAX_PAIR_HISTORY_SIZE)
+            .map(|m| {
+                let p2 = m.p2.as_ref().unwrap();
+                json!({
+                    "match_id": m.id,
+                    "player_1": self.log_address(&env, m.p1.sender, m.resolved_round),
+                    "player_2": self.log_address(&env, p2.sender, m.resolved_round),
+                    "outcome": m.outcome(),
+                })
+            })
+            .collect();
 
+        let result = json!({
+            "times_played_together": times_played,
+            "recent_matches": recent,
+        });
         log(&result.to_string());
 
         Ok(())
     }
 
-    fn result(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
-        let id: String = params.read();
+    fn query_get_round_stats(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let start_round: u64 = read_param(params, &mut consumed)?;
+        let end_round: u64 = read_param(params, &mut consumed)?;
 
-        // Check the match in the waiting pool
-        if self.waiting.iter().find(|m| m.id == id).is_some() {
-            return Err("Your match is still waiting for other player.".into());
+        if end_round < start_round {
+            return Err(ContractError::InvalidParameter("The end round must not be before the start round.".to_string()).into());
         }
 
-        let found = match self.history.iter().find(|m| m.id == id) {
-            Some(m) => m,
-            None => {
-                return Err("The match does not exist.".into());
+        if end_round - start_round >= MAX_ROUND_STATS_RANGE {
+            return Err(ContractError::InvalidParameter(format!("At most {} rounds can be queried at once.", MAX_ROUND_STATS_RANGE)).into());
+        }
+
+        let mut results = Vec::new();
+        for round in start_round..=end_round {
+            let (cooperations, defections) = match self.round_stats.get(&round) {
+                Some(stats) => *stats,
+                None => continue,
+            };
+
+            // Rounds that only saw abstentions have nothing to report
+            let total = cooperations as u64 + defections as u64;
+            if total == 0 {
+                continue;
             }
-        };
 
-        let p2 = found.p2.clone().unwrap();
+            let coop_rate_pct = cooperations as u64 * 100 / total;
+
+            results.push(json!({
+                "round": round,
+                "cooperations": cooperations,
+                "defections": defections,
+                "coop_rate_pct": coop_rate_pct,
+            }));
+        }
+
+        log(&json!(results).to_string());
 
+        Ok(())
+    }
+
+    fn query_get_coop_rate(&self, _params: &mut Parameters) -> Result<(), Box<dyn Error>> {
         let result = json!({
-            "player_1": json!({
-                            "sender": to_hex_string(found.p1.sender),
-                            "payout": found.p1_payout,
-                        }),
-            "player_2": json!({
-                            "sender": to_hex_string(p2.sender),
-                            "payout": found.p2_payout,
-                        }),
+            "coop_rate_pct": self.coop_rate(),
+            "window": self.coop_window.len(),
+            "window_size": COOP_WINDOW_SIZE,
         });
         log(&result.to_string());
 
         Ok(())
     }
 
-    fn get_balance(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+    // Counts the players by the strategy of their latest match.
+    fn query_get_strategy_distribution(&self, _params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut distribution: BTreeMap<u8, u64> = BTreeMap::new();
+        for stats in self.stats.values().filter(|s| s.lifetime_staked > 0) {
+            *distribution.entry(stats.strategy_id).or_insert(0) += 1;
+        }
+
+        let result: Vec<serde_json::Value> = distribution.iter()
+            .map(|(strategy_id, players)| {
+                json!({
+                    "strategy_id": strategy_id,
+                    "players": players,
+                })
+            })
+            .collect();
+        log(&json!(result).to_string());
+
+        Ok(())
+    }
+
+    fn query_get_global_stats(&self, _params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut effects = Effects::default();
+        let stats = &self.global_stats;
+
+        let event = GlobalStatsQueried {
+            schema_version: SCHEMA_VERSION,
+            matches_resolved: stats.matches_resolved,
+            cc_count: stats.cc_count,
+            cd_count: stats.cd_count,
+            dc_count: stats.dc_count,
+            dd_count: stats.dd_count,
+            coop_rate_pct: stats.coop_rate_pct(),
+            pot_inflow: stats.pot_inflow,
+            pot_outflow: stats.pot_outflow,
+            avg_wait_rounds: stats.avg_wait_rounds,
+            total_staked: stats.total_staked,
+            total_cashed_out: stats.total_cashed_out,
+            pot: self.pot,
+            waiting: self.waiting.len() as u64,
+            threshold: self.threshold,
+        };
+        self.emit_query(&mut effects, Event::GlobalStatsQueried(event));
+
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn query_get_balance(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut effects = Effects::default();
         let sender_balance = match self.balances.get(&params.sender) {
             Some(balance) => *balance,
             None => 0,
         };
 
-        log(&sender_balance.to_string());
+        self.emit_query(&mut effects, Event::BalanceQueried(BalanceQueried {
+            schema_version: SCHEMA_VERSION,
+            balance: sender_balance,
+        }));
+
+        carry_out(effects);
 
         Ok(())
     }
 
-    fn cash_out(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
-        let sender_balance = match self.balances.get(&params.sender) {
-            Some(balance) => *balance,
+    // Logs every configuration field, in the order they are declared in `Config`.
+    fn query_get_config(&self, _params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        log(&serde_json::to_string(&self.config())?);
+
+        Ok(())
+    }
+
+    fn query_get_tier_queues(&self, _params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let tiers: Vec<serde_json::Value> = self.stake_tiers.iter().enumerate()
+            .map(|(i, (lower, upper))| {
+                json!({
+                    "tier": i,
+                    "lower_bound": lower,
+                    "upper_bound": upper,
+                    "waiting": self.waiting.iter().filter(|m| stake_tier(&self.stake_tiers, m.p1.stake) == Some(i)).count(),
+                })
+            })
+            .collect();
+
+        let result = json!({
+            "tiers": tiers,
+            "untiered": self.waiting.iter().filter(|m| stake_tier(&self.stake_tiers, m.p1.stake).is_none()).count(),
+        });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_get_pot(&self, _params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let result = json!({
+            "pot": self.pot,
+            "threshold": self.threshold,
+            // "waiting" is kept for existing clients
+            "waiting": self.waiting.len(),
+            "waiting_matches": self.waiting.len(),
+        });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_get_stats(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let stats = match self.stats.get(&params.sender) {
+            Some(stats) => stats.clone(),
+            None => PlayerStats::default(),
+        };
+
+        let (loyalty_tier, loyalty_bonus_bps) = loyalty_tier(&self.loyalty_tiers, stats.matches_played);
+
+        let result = json!({
+            "matches_played": stats.matches_played,
+            "wins": stats.wins,
+            "total_won": stats.total_won,
+            "cooperations": stats.cooperations,
+            "defections": stats.defections,
+            "cooperation_rate": stats.cooperation_rate(),
+            "cooperation_streak": stats.cooperation_streak,
+            "cancellations": stats.cancellations,
+            "lifetime_staked": stats.lifetime_staked,
+            "lifetime_earned": stats.lifetime_earned,
+            "roi_pct": stats.roi_pct(),
+            "last_milestone": stats.last_milestone,
+            "reputation": reputation_of(&self.stats, &params.sender),
+            "loyalty_tier": loyalty_tier,
+            "loyalty_bonus_bps": loyalty_bonus_bps,
+        });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_get_reputation(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let address: String = read_param(params, &mut consumed)?;
+
+        let address = from_hex_string(&address)?;
+
+        let reputation = reputation_of(&self.stats, &address);
+
+        let result = json!({
+            "sender": self.log_address(&env, address, params.round_idx),
+            "reputation": reputation,
+            "low_reputation": reputation < self.reputation_floor,
+        });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_get_player_rank(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let earned = self.stats.get(&params.sender).map(|s| s.lifetime_earned).unwrap_or(0);
+
+        let result = json!({
+            "sender": self.log_address(&env, params.sender, params.round_idx),
+            "lifetime_earned": earned,
+            "rank": earnings_rank(&self.earnings_index, earned),
+            "total_players": self.stats.len(),
+        });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_get_achievements(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let earned = match self.achievements.get(&params.sender) {
+            Some(earned) => *earned,
             None => 0,
         };
-        if sender_balance == 0 {
-            return Err("Sender has no PERLS".into());
+
+        let badges: Vec<&str> = BADGE_NAMES.iter()
+            .filter(|(badge, _)| earned & badge != 0)
+            .map(|(_, name)| *name)
+            .collect();
+
+        let result = json!({
+            "achievements": earned,
+            "badges": badges,
+        });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_leaderboard(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let metric: String = read_param(params, &mut consumed)?;
+        let limit: u32 = read_param(params, &mut consumed)?;
+
+        let round = params.round_idx;
+        let mut entries: Vec<(String, u64)> = match metric.as_str() {
+            "balance" => self.balances.iter().map(|(sender, balance)| (self.log_address(&env, *sender, round), *balance)).collect(),
+            "wins" => self.stats.iter().map(|(sender, s)| (self.log_address(&env, *sender, round), s.wins)).collect(),
+            "total_won" => self.stats.iter().map(|(sender, s)| (self.log_address(&env, *sender, round), s.total_won)).collect(),
+            "cooperation_rate" => self.stats.iter().map(|(sender, s)| (self.log_address(&env, *sender, round), s.cooperation_rate())).collect(),
+            _ => {
+                return Err(ContractError::InvalidParameter("Metric must be one of balance, wins, total_won or cooperation_rate.".to_string()).into());
+            }
+        };
+
+        // HashMap iteration order is not deterministic, so sort by value and
+        // break ties by address to give every node the same output.
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        entries.truncate(limit.min(MAX_LEADERBOARD_SIZE) as usize);
+
+        let result: Vec<serde_json::Value> = entries.iter().enumerate().map(|(i, (sender, value))| json!({
+            "rank": i + 1,
+            "sender": sender,
+            "value": value,
+        })).collect();
+
+        log(&json!(result).to_string());
+
+        Ok(())
+    }
+
+    fn query_get_merkle_proof(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let id: String = read_param(params, &mut consumed)?;
+
+        let index = match self.history.iter().position(|m| m.id == id) {
+            Some(index) => index,
+            None => return Err(ContractError::MatchNotFound.into()),
+        };
+
+        let leaves: Vec<[u8; 32]> = self.history.iter().map(merkle_leaf).collect();
+
+        let result = json!({
+            "match_id": id,
+            "index": index,
+            "leaf": to_hex_string(leaves[index]),
+            "proof": compute_proof(&leaves, index).into_iter().map(to_hex_string).collect::<Vec<String>>(),
+            "root": to_hex_string(compute_merkle_root(&leaves)),
+        });
+        log(&result.to_string());
+
+        Ok(())
+    }
+
+    fn query_snapshot(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        log(&serde_json::to_string(self)?);
+
+        Ok(())
+    }
+
+    fn query_export_state(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let state = serde_json::to_string(self)?;
+
+        // Logs may be size limited, so the state is logged in chunks which
+        // have to be joined back together in order.
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < state.len() {
+            let mut end = (start + EXPORT_CHUNK_SIZE).min(state.len());
+            while !state.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            chunks.push(&state[start..end]);
+            start = end;
         }
 
-        Transfer {
-            destination: params.sender,
-            amount: sender_balance,
-            func_name: vec![],
-            func_params: vec![],
-        }.send_transaction();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let result = json!({
+                "chunk": i,
+                "total_chunks": chunks.len(),
+                "data": chunk,
+            });
+            log(&result.to_string());
+        }
+
+        Ok(())
+    }
+
+    fn query_get_balance_of(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut effects = Effects::default();
+        let mut consumed = 0;
+        let address: String = read_param(params, &mut consumed)?;
+
+        let address = from_hex_string(&address)?;
+
+        let balance = match self.balances.get(&address) {
+            Some(balance) => *balance,
+            None => 0,
+        };
+
+        self.emit_query(&mut effects, Event::BalanceQueried(BalanceQueried {
+            schema_version: SCHEMA_VERSION,
+            balance,
+        }));
+
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn query_get_balances(&self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let offset: u32 = read_param(params, &mut consumed)?;
+        let limit: u32 = read_param(params, &mut consumed)?;
+
+        // Addresses sort the same as their hex strings
+        let mut holders: Vec<(&[u8; 32], &u64)> = self.balances.iter().filter(|(_, balance)| **balance > 0).collect();
+        holders.sort_unstable_by_key(|(sender, _)| **sender);
+
+        let balances: Vec<serde_json::Value> = holders.iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_BALANCES_PAGE_SIZE) as usize)
+            .map(|(sender, balance)| {
+                json!({
+                    "sender": self.log_address(&env, **sender, params.round_idx),
+                    "balance": balance,
+                })
+            })
+            .collect();
 
-        self.balances.insert(params.sender, 0);
+        let result = json!({
+            "total_holders": holders.len(),
+            "balances": balances,
+        });
+        log(&result.to_string());
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[smart_contract]
+impl PrisonerDilemma {
+    fn init(params: &mut Parameters) -> Self {
+        // The invariant checks are optional. The constructor can't fail, so
+        // malformed parameters fall back to the defaults.
+        let mut consumed = 0;
+        let mut invariants_enabled = false;
+        if payload_size() > consumed {
+            invariants_enabled = read_param(params, &mut consumed).unwrap_or(false);
+        }
+
+        // So is the threshold mode: 0 (linear), 1 (exponential) or 2 (fixed).
+        // Unknown modes fall back to linear.
+        let mut threshold_decay_mode = ThresholdMode::Linear;
+        if payload_size() > consumed {
+            let mode: u8 = read_param(params, &mut consumed).unwrap_or(0);
+            threshold_decay_mode = ThresholdMode::from_u8(mode).unwrap_or(ThresholdMode::Linear);
+        }
+
+        // And so are the stake tiers, given as their lower and their upper bounds.
+        // Tiers whose bounds are the wrong way around are left out.
+        let mut stake_tiers: Vec<(u64, u64)> = Vec::new();
+        if payload_size() > consumed {
+            let lower: Vec<u64> = read_param(params, &mut consumed).unwrap_or_default();
+            let upper: Vec<u64> = read_param(params, &mut consumed).unwrap_or_default();
+
+            stake_tiers = lower.into_iter().zip(upper).filter(|(lower, upper)| lower <= upper).collect();
+            stake_tiers.sort_unstable();
+        }
+
+        // Last comes the configuration, as a JSON object. Every field that is
+        // missing or out of bounds keeps its default.
+        let mut config = serde_json::Value::Null;
+        if payload_size() > consumed {
+            let json: String = read_param(params, &mut consumed).unwrap_or_default();
+            config = serde_json::from_str(&json).unwrap_or(serde_json::Value::Null);
+        }
+        let setting = |name: &str, valid: fn(u64) -> bool, default: u64| {
+            config.get(name).and_then(|v| v.as_u64()).filter(|v| valid(*v)).unwrap_or(default)
+        };
+
+        let mut state = GameState::new(params.sender);
+        state.invariants_enabled = invariants_enabled;
+        state.threshold_decay_mode = threshold_decay_mode;
+        state.stake_tiers = stake_tiers;
+
+        state.threshold = setting("threshold", |v| v <= 100, state.threshold as u64) as u32;
+        state.min_stake = setting("min_stake", |v| GameState::check_min_stake(v).is_ok(), state.min_stake);
+        state.reward_schedule.cooperate_bps = setting("cooperate_bps", |v| RewardSchedule::check_bps(v, 0).is_ok(), state.reward_schedule.cooperate_bps);
+        state.reward_schedule.defect_bps = setting("defect_bps", |v| RewardSchedule::check_bps(0, v).is_ok(), state.reward_schedule.defect_bps);
+        state.matching_strategy = MatchingStrategy::from_u8(setting("matching_strategy", |v| v <= 2, 0) as u8).unwrap_or(MatchingStrategy::Fifo);
+        state.match_expiry_rounds = setting("match_expiry_rounds", |_| true, state.match_expiry_rounds);
+        state.commitment_expiry_rounds = setting("commitment_expiry_rounds", |v| v > 0, state.commitment_expiry_rounds);
+        state.history_capacity = setting("history_capacity", |v| v > 0, DEFAULT_HISTORY_CAPACITY as u64) as usize;
+
+        state
+    }
+
+    fn play(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let request = read_play_request(params);
+
+        run(env(params), |env, effects| self.handle_play(env, request, effects))
+    }
+
+    // Enters several matches at once, splitting the amount evenly between
+    // them. The payload is the number of matches, at most
+    // MAX_PLAY_MANY_COUNT, followed by the vote as in `play`, without the
+    // referrer and the insurance. A match that can't be entered stops the
+    // batch, the matches entered before it stay entered and the stakes of
+    // the remaining ones go to the sender's balance.
+    fn play_many(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let request = read_play_many_request(params);
+
+        run(env(params), |env, effects| self.handle_play_many(env, request, effects))
+    }
+
+    fn commit_vote(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let commitment = read_param(params, &mut consumed);
+
+        run(env(params), |env, effects| self.handle_commit_vote(env, commitment, effects))
+    }
+
+    fn reveal_vote(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let vote: u8 = read_param(params, &mut consumed)?;
+        let nonce: [u8; 32] = read_param(params, &mut consumed)?;
+
+        run(env(params), |env, effects| self.handle_reveal_vote(env, vote, nonce, effects))
+    }
+
+    fn reveal_blinded_vote(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let id: String = read_param(params, &mut consumed)?;
+        let vote: u8 = read_param(params, &mut consumed)?;
+        let nonce: [u8; 32] = read_param(params, &mut consumed)?;
+
+        run(env(params), |env, effects| self.handle_reveal_blinded_vote(env, &id, vote, nonce, effects))
+    }
+
+    fn get_history(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_history(params)
+    }
+
+    fn history_for(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_history_for(params)
+    }
+
+    fn result(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let id: String = read_param(params, &mut consumed)?;
+
+        run(env(params), |env, effects| self.handle_result(env, id, effects))
+    }
+
+    fn match_status(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_match_status(params)
+    }
+
+    fn result_by_tx(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_result_by_tx(params)
+    }
+
+    fn cancel(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let id: String = read_param(params, &mut consumed)?;
+
+        run(env(params), |env, effects| self.handle_cancel(env, &id, effects))
+    }
+
+    fn set_random_matching(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let enabled: bool = read_param(params, &mut consumed)?;
+
+        run(env(params), |env, effects| self.handle_set_random_matching(env, enabled, effects))
+    }
+
+    // Plays against a challenge-only match, whose id the creator shared.
+    fn challenge(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let challenge = read_match_id_and_u8(params);
+
+        run(env(params), |env, effects| self.handle_challenge(env, challenge, effects))
+    }
+
+    fn top_up_match(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let id = read_param(params, &mut consumed);
+
+        run(env(params), |env, effects| self.handle_top_up(env, id, effects))
+    }
+
+    fn place_side_bet(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let bet = read_match_id_and_u8(params);
+
+        run(env(params), |env, effects| self.handle_side_bet(env, bet, effects))
+    }
+
+    fn transfer_match(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let id: String = read_param(params, &mut consumed)?;
+        let new_owner: [u8; 32] = read_param(params, &mut consumed)?;
+
+        let index = self.find_own_waiting_match(&id, params.sender)?;
+
+        if new_owner == params.sender {
+            return Err(ContractError::InvalidParameter("The match already belongs to the sender.".to_string()).into());
+        }
+
+        // Only the owner changes, the stake and vote stay with the match
+        self.waiting[index].p1.sender = new_owner;
+
+        let event = MatchTransferred {
+            schema_version: SCHEMA_VERSION,
+            match_id: id,
+            from: self.log_address(&env, params.sender, params.round_idx),
+            to: self.log_address(&env, new_owner, params.round_idx),
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::MatchTransferred(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn get_queue_position(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_queue_position(params)
+    }
+
+    fn my_matches(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_my_matches(params)
+    }
+
+    fn get_results_batch(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_results_batch(params)
+    }
+
+    fn get_pair_history(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_pair_history(params)
+    }
+
+    fn get_round_stats(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_round_stats(params)
+    }
+
+    fn set_alias(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let alias = read_string_bytes(params, &mut consumed)?;
+
+        if alias.is_empty() || alias.len() > MAX_ALIAS_LENGTH {
+            return Err(ContractError::InvalidParameter(format!("The alias must be between 1 and {} bytes long.", MAX_ALIAS_LENGTH)).into());
+        }
+
+        let alias = match String::from_utf8(alias) {
+            Ok(alias) => alias,
+            Err(_) => return Err(ContractError::InvalidParameter("The alias must be valid UTF-8.".to_string()).into()),
+        };
+
+        self.aliases.insert(params.sender, alias.clone());
+
+        let event = AliasSet {
+            schema_version: SCHEMA_VERSION,
+            sender: self.log_address(&env, params.sender, params.round_idx),
+            alias,
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::AliasSet(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_auto_cashout(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let enabled: bool = read_param(params, &mut consumed)?;
+
+        if enabled {
+            self.auto_cashout.insert(params.sender);
+        } else {
+            self.auto_cashout.remove(&params.sender);
+        }
+
+        let event = AutoCashoutChanged {
+            schema_version: SCHEMA_VERSION,
+            sender: self.log_address(&env, params.sender, params.round_idx),
+            auto_cashout: enabled,
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::AutoCashoutChanged(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn get_coop_rate(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_coop_rate(params)
+    }
+
+    fn get_strategy_distribution(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_strategy_distribution(params)
+    }
+
+    fn get_global_stats(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_global_stats(params)
+    }
+
+    fn get_balance(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_balance(params)
+    }
+
+    fn get_config(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_config(params)
+    }
+
+    fn get_tier_queues(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_tier_queues(params)
+    }
+
+    fn get_pot(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_pot(params)
+    }
+
+    fn get_stats(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_stats(params)
+    }
+
+    fn get_reputation(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_reputation(params)
+    }
+
+    fn get_player_rank(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_player_rank(params)
+    }
+
+    fn get_achievements(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_achievements(params)
+    }
+
+    fn leaderboard(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_leaderboard(params)
+    }
+
+    fn get_merkle_proof(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_merkle_proof(params)
+    }
+
+    fn snapshot(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_snapshot(params)
+    }
+
+    fn export_state(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_export_state(params)
+    }
+
+    fn pause(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        self.paused = true;
+
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::PauseChanged(PauseChanged {
+            schema_version: SCHEMA_VERSION,
+            paused: self.paused,
+        }));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn unpause(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        if self.shut_down {
+            return Err(ContractError::ShutDown.into());
+        }
+
+        self.paused = false;
+
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::PauseChanged(PauseChanged {
+            schema_version: SCHEMA_VERSION,
+            paused: self.paused,
+        }));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    // Retires the contract: it stays paused for good, and every stake still
+    // in play goes back to its owner's balance, from where it can be cashed
+    // out. The pot is either swept into the owner's balance or burned.
+    // Shutting down again finds nothing left to unwind.
+    fn shutdown(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let sweep_pot: bool = read_param(params, &mut consumed)?;
+
+        run(env(params), |_, effects| self.handle_shutdown(sweep_pot, effects))
+    }
+
+    // Ownership moves in two steps, so a mistyped address can't take it: the
+    // owner proposes the new owner, who then has to accept. An empty address
+    // withdraws the proposal.
+    fn transfer_ownership(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let address: String = read_param(params, &mut consumed)?;
+
+        self.pending_owner = if address.is_empty() {
+            None
+        } else {
+            Some(from_hex_string(&address)?)
+        };
+
+        let event = OwnershipProposed {
+            schema_version: SCHEMA_VERSION,
+            owner: self.log_address(&env, self.owner, params.round_idx),
+            pending_owner: self.pending_owner.map(|a| self.log_address(&env, a, params.round_idx)),
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::OwnershipProposed(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn accept_ownership(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        if self.pending_owner != Some(params.sender) {
+            return Err(ContractError::NotPendingOwner.into());
+        }
+
+        let previous = self.owner;
+        self.owner = params.sender;
+        self.pending_owner = None;
+
+        let event = OwnershipAccepted {
+            schema_version: SCHEMA_VERSION,
+            previous_owner: self.log_address(&env, previous, params.round_idx),
+            owner: self.log_address(&env, self.owner, params.round_idx),
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::OwnershipAccepted(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    // Wipes the stats and badges of the players given as a JSON array of hex
+    // addresses, or of every player if the array is empty. Balances persist.
+    fn reset_player_stats(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut effects = Effects::default();
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let addresses: String = read_param(params, &mut consumed)?;
+        let addresses: Vec<String> = serde_json::from_str(&addresses)?;
+
+        let mut players = Vec::new();
+        for address in &addresses {
+            players.push(from_hex_string(address)?);
+        }
+
+        if players.is_empty() {
+            let mut all: HashSet<[u8; 32]> = self.stats.keys().cloned().collect();
+            all.extend(self.achievements.keys().cloned());
+            players = all.into_iter().collect();
+            players.sort_unstable();
+        }
+
+        for sender in players {
+            let had_stats = match self.stats.remove(&sender) {
+                Some(s) => {
+                    reindex_earnings(&mut self.earnings_index, sender, s.lifetime_earned, 0);
+                    true
+                }
+                None => false,
+            };
+            let had_badges = self.achievements.remove(&sender).is_some();
+
+            if had_stats || had_badges {
+                self.emit(&mut effects, Event::PlayerStatsReset(PlayerStatsReset {
+                    schema_version: SCHEMA_VERSION,
+                    address: to_hex_string(sender),
+                }));
+            }
+        }
+
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn import_state(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        if !self.is_fresh() {
+            return Err(ContractError::InvalidParameter("State can only be imported into a fresh contract.".to_string()).into());
+        }
+
+        let mut consumed = 0;
+        let state: String = read_param(params, &mut consumed)?;
+        let mut imported: PrisonerDilemma = serde_json::from_str(&state)?;
+
+        // The importing contract keeps its own owner.
+        imported.owner = self.owner;
+        imported.pending_owner = self.pending_owner;
+        imported.earnings_index = index_earnings(&imported.stats);
+
+        *self = imported;
+
+        let event = StateImported {
+            schema_version: SCHEMA_VERSION,
+            balances: self.balances.len() as u64,
+            pot: self.pot,
+            waiting: self.waiting.len() as u64,
+            history: self.history.len() as u64,
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::StateImported(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_streak_bonuses(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let milestones: Vec<u64> = read_param(params, &mut consumed)?;
+        let bonus_bps: Vec<u64> = read_param(params, &mut consumed)?;
+
+        if milestones.len() != bonus_bps.len() {
+            return Err(ContractError::InvalidParameter("Every streak milestone needs exactly one bonus.".to_string()).into());
+        }
+
+        if milestones.contains(&0) || bonus_bps.iter().any(|bps| *bps > 10_000) {
+            return Err(ContractError::InvalidParameter("Streak milestones must be positive and bonuses at most 10000 basis points.".to_string()).into());
+        }
+
+        self.streak_bonuses = milestones.into_iter().zip(bonus_bps).collect();
+
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::TiersChanged(TiersChanged {
+            schema_version: SCHEMA_VERSION,
+            name: "streak_bonuses".to_string(),
+            tiers: self.streak_bonuses.clone(),
+        }));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_coop_jackpot(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let jackpot_pct_bps: u64 = read_param(params, &mut consumed)?;
+
+        if jackpot_pct_bps > 10_000 {
+            return Err(ContractError::InvalidParameter("The cooperation jackpot must be at most 10000 basis points.".to_string()).into());
+        }
+
+        let old = std::mem::replace(&mut self.jackpot_pct_bps, jackpot_pct_bps);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "jackpot_pct_bps", old, self.jackpot_pct_bps);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_milestone_bonus(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let bonus_bps: u64 = read_param(params, &mut consumed)?;
+
+        if bonus_bps > 10_000 {
+            return Err(ContractError::InvalidParameter("The milestone bonus must be at most 10000 basis points.".to_string()).into());
+        }
+
+        let old = std::mem::replace(&mut self.milestone_bonus_bps, bonus_bps);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "milestone_bonus_bps", old, self.milestone_bonus_bps);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_loyalty_tiers(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let min_matches: Vec<u64> = read_param(params, &mut consumed)?;
+        let bonus_bps: Vec<u64> = read_param(params, &mut consumed)?;
+
+        if min_matches.len() != bonus_bps.len() {
+            return Err(ContractError::InvalidParameter("Every loyalty tier needs exactly one bonus.".to_string()).into());
+        }
+
+        if min_matches.windows(2).any(|w| w[0] >= w[1]) || bonus_bps.iter().any(|bps| *bps > 10_000) {
+            return Err(ContractError::InvalidParameter("Loyalty tiers must be in ascending order and bonuses at most 10000 basis points.".to_string()).into());
+        }
+
+        self.loyalty_tiers = min_matches.into_iter().zip(bonus_bps).collect();
+
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::TiersChanged(TiersChanged {
+            schema_version: SCHEMA_VERSION,
+            name: "loyalty_tiers".to_string(),
+            tiers: self.loyalty_tiers.clone(),
+        }));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_priority_fee(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let priority_fee = read_param(params, &mut consumed)?;
+        let old = std::mem::replace(&mut self.priority_fee, priority_fee);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "priority_fee", old, self.priority_fee);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_params(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut effects = Effects::default();
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let name: String = read_param(params, &mut consumed)?;
+        let value: u64 = read_param(params, &mut consumed)?;
+
+        let old = self.set_param(&name, value)?;
+
+        self.emit(&mut effects, Event::ConfigChanged(ConfigChanged {
+            schema_version: SCHEMA_VERSION,
+            name,
+            old,
+            new: value,
+        }));
+
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_match_expiry(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let match_expiry_rounds: u64 = read_param(params, &mut consumed)?;
+        let sweep_limit: u64 = read_param(params, &mut consumed)?;
+
+        if sweep_limit == 0 {
+            return Err(ContractError::InvalidParameter("The sweep limit must be at least 1.".to_string()).into());
+        }
+
+        let old_expiry = std::mem::replace(&mut self.match_expiry_rounds, match_expiry_rounds);
+        let old_sweep_limit = std::mem::replace(&mut self.sweep_limit, sweep_limit as usize) as u64;
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "match_expiry_rounds", old_expiry, match_expiry_rounds);
+        self.emit_config(&mut effects, "sweep_limit", old_sweep_limit, sweep_limit);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_matching_strategy(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let strategy: u8 = read_param(params, &mut consumed)?;
+
+        let old = self.matching_strategy;
+        self.matching_strategy = match MatchingStrategy::from_u8(strategy) {
+            Some(strategy) => strategy,
+            None => return Err(ContractError::InvalidParameter("The matching strategy must be either 0 (FIFO), 1 (random) or 2 (stake weighted).".to_string()).into()),
+        };
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "matching_strategy", old as u64, self.matching_strategy as u64);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_max_waiting_pool(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let max_waiting_pool: u64 = read_param(params, &mut consumed)?;
+        let old = std::mem::replace(&mut self.max_waiting_pool, max_waiting_pool as usize) as u64;
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "max_waiting_pool", old, max_waiting_pool);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_anonymous(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let anonymous: bool = read_param(params, &mut consumed)?;
+        let old = std::mem::replace(&mut self.anonymous, anonymous);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "anonymous", old as u64, anonymous as u64);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_reputation_floor(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let floor: u64 = read_param(params, &mut consumed)?;
+
+        if floor > 100 {
+            return Err(ContractError::InvalidParameter("The reputation floor must be at most 100.".to_string()).into());
+        }
+
+        let old = std::mem::replace(&mut self.reputation_floor, floor);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "reputation_floor", old, self.reputation_floor);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_defector_bonus_cap(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let multiplier = read_param(params, &mut consumed)?;
+        let old = std::mem::replace(&mut self.defector_bonus_cap_multiplier, multiplier);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "defector_bonus_cap_multiplier", old, self.defector_bonus_cap_multiplier);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_defection_penalty(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let multiplier: u64 = read_param(params, &mut consumed)?;
+
+        if multiplier < 10_000 {
+            return Err(ContractError::InvalidParameter("The defection penalty multiplier must be at least 10000 basis points.".to_string()).into());
+        }
+
+        let old = std::mem::replace(&mut self.defection_penalty_multiplier, multiplier);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "defection_penalty_multiplier", old, self.defection_penalty_multiplier);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_jackpot(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let trigger: u64 = read_param(params, &mut consumed)?;
+        let share_bps: u64 = read_param(params, &mut consumed)?;
+
+        if share_bps > 10_000 {
+            return Err(ContractError::InvalidParameter("The jackpot share must be at most 10000 basis points.".to_string()).into());
+        }
+
+        let old_trigger = std::mem::replace(&mut self.jackpot_trigger, trigger);
+        let old_share_bps = std::mem::replace(&mut self.jackpot_share_bps, share_bps);
+        let was_armed = self.jackpot_armed;
+
+        if self.jackpot_trigger == 0 {
+            self.jackpot_armed = false;
+        }
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "jackpot_trigger", old_trigger, trigger);
+        self.emit_config(&mut effects, "jackpot_share_bps", old_share_bps, share_bps);
+        if was_armed != self.jackpot_armed {
+            self.emit_config(&mut effects, "jackpot_armed", was_armed as u64, self.jackpot_armed as u64);
+        }
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_reward_schedule(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let schedule = RewardSchedule {
+            low_water: read_param(params, &mut consumed)?,
+            high_water: read_param(params, &mut consumed)?,
+            min_reward: read_param(params, &mut consumed)?,
+            max_reward: read_param(params, &mut consumed)?,
+            cooperate_bps: read_param(params, &mut consumed)?,
+            defect_bps: read_param(params, &mut consumed)?,
+        };
+
+        if schedule.low_water > schedule.high_water {
+            return Err(ContractError::InvalidParameter("The low-water mark must not be above the high-water mark.".to_string()).into());
+        }
+
+        RewardSchedule::check_bps(schedule.cooperate_bps, schedule.defect_bps)?;
+
+        let old = std::mem::replace(&mut self.reward_schedule, schedule);
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "low_water", old.low_water, schedule.low_water);
+        self.emit_config(&mut effects, "high_water", old.high_water, schedule.high_water);
+        self.emit_config(&mut effects, "min_reward", old.min_reward, schedule.min_reward);
+        self.emit_config(&mut effects, "max_reward", old.max_reward, schedule.max_reward);
+        self.emit_config(&mut effects, "cooperate_bps", old.cooperate_bps, schedule.cooperate_bps);
+        self.emit_config(&mut effects, "defect_bps", old.defect_bps, schedule.defect_bps);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_charity(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        // An empty address removes the charity.
+        let mut consumed = 0;
+        let address: String = read_param(params, &mut consumed)?;
+        let share_bps: u64 = read_param(params, &mut consumed)?;
+
+        if share_bps > 10_000 {
+            return Err(ContractError::InvalidParameter("The charity share must be at most 10000 basis points.".to_string()).into());
+        }
+
+        if address.is_empty() {
+            self.charity = None;
+        } else {
+            self.charity = Some(from_hex_string(&address)?);
+        }
+        self.charity_share_bps = share_bps;
+
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::CharityChanged(CharityChanged {
+            schema_version: SCHEMA_VERSION,
+            charity: self.charity.map(to_hex_string),
+            charity_share_bps: self.charity_share_bps,
+        }));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_pot_decay(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let period: u64 = read_param(params, &mut consumed)?;
+        let decay_bps: u64 = read_param(params, &mut consumed)?;
+        let matches: u32 = read_param(params, &mut consumed)?;
+
+        if decay_bps > 10_000 {
+            return Err(ContractError::InvalidParameter("The pot decay must be at most 10000 basis points.".to_string()).into());
+        }
+
+        // The first decay happens in the next period.
+        if let Some(last_decay_period) = params.round_idx.checked_div(period) {
+            self.last_decay_period = last_decay_period;
+        }
+
+        let old_period = std::mem::replace(&mut self.decay_period, period);
+        let old_decay_bps = std::mem::replace(&mut self.decay_bps, decay_bps);
+        let old_matches = std::mem::replace(&mut self.decay_matches, matches as usize) as u64;
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "decay_period", old_period, period);
+        self.emit_config(&mut effects, "decay_bps", old_decay_bps, decay_bps);
+        self.emit_config(&mut effects, "decay_matches", old_matches, matches as u64);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn set_surplus_distribution(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.only_owner(params.sender)?;
+
+        let mut consumed = 0;
+        let threshold: u64 = read_param(params, &mut consumed)?;
+        let matches: u32 = read_param(params, &mut consumed)?;
+
+        if matches == 0 {
+            return Err(ContractError::InvalidParameter("The surplus has to be shared among at least 1 match.".to_string()).into());
+        }
+
+        let old_threshold = std::mem::replace(&mut self.surplus_threshold, threshold);
+        let old_matches = std::mem::replace(&mut self.surplus_matches, matches as usize) as u64;
+
+        let mut effects = Effects::default();
+        self.emit_config(&mut effects, "surplus_threshold", old_threshold, threshold);
+        self.emit_config(&mut effects, "surplus_matches", old_matches, matches as u64);
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn distribute_surplus_pot(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        self.only_owner(params.sender)?;
+
+        if self.pot <= self.surplus_threshold {
+            return Err(ContractError::NoSurplus.into());
+        }
+
+        let recipients = self.recent_players(self.surplus_matches);
+        if recipients.is_empty() {
+            return Err(ContractError::NoRecentPlayers.into());
+        }
+
+        let surplus = self.pot - self.surplus_threshold;
+        let (share, dust) = split_amount(surplus, recipients.len() as u64);
+
+        for recipient in &recipients {
+            update_balance(&mut self.balances, *recipient, share as i128);
+        }
+
+        // The dust goes back into the pot
+        let distributed = surplus - dust;
+        self.pot -= distributed;
+        self.global_stats.pot_outflow += distributed;
+
+        let event = SurplusDistributed {
+            schema_version: SCHEMA_VERSION,
+            recipients: recipients.iter().map(|r| self.log_address(&env, *r, params.round_idx)).collect(),
+            amount: share,
+            pot: self.pot,
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::SurplusDistributed(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn get_balance_of(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_balance_of(params)
+    }
+
+    fn get_balances(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        self.query_get_balances(params)
+    }
+
+    // Cashes out the sender's balance, or that of the player given as an
+    // optional parameter if the sender is their delegate. The balance is
+    // always sent to the player.
+    fn cash_out(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let mut balance_owner = params.sender;
+        if payload_size() > consumed {
+            balance_owner = read_param(params, &mut consumed)?;
+        }
+
+        run(env(params), |env, effects| self.handle_cash_out(env, balance_owner, effects))
+    }
+
+    fn set_cash_out_delegate(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let delegate: [u8; 32] = read_param(params, &mut consumed)?;
+
+        if delegate == params.sender {
+            return Err(ContractError::InvalidParameter("The delegate must be a different address.".to_string()).into());
+        }
+
+        self.cash_out_delegates.insert(params.sender, delegate);
+
+        let event = DelegateSet {
+            schema_version: SCHEMA_VERSION,
+            sender: self.log_address(&env, params.sender, params.round_idx),
+            delegate: self.log_address(&env, delegate, params.round_idx),
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::DelegateSet(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn revoke_cash_out_delegate(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let revoked = self.cash_out_delegates.remove(&params.sender).is_some();
+
+        let event = DelegateRevoked {
+            schema_version: SCHEMA_VERSION,
+            sender: self.log_address(&env, params.sender, params.round_idx),
+            revoked,
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::DelegateRevoked(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn cash_out_multisig_request(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let env = env(params);
+        let mut consumed = 0;
+        let approver: [u8; 32] = read_param(params, &mut consumed)?;
+        let amount: u64 = read_param(params, &mut consumed)?;
+
+        if approver == params.sender {
+            return Err(ContractError::InvalidParameter("The approver must be a different address.".to_string()).into());
+        }
+
+        let sender_balance = match self.balances.get(&params.sender) {
+            Some(balance) => *balance,
+            None => 0,
+        };
+        if amount == 0 || amount > sender_balance {
+            return Err(ContractError::InsufficientBalance.into());
+        }
+
+        self.prune_expired_multisig_requests(params.round_idx);
+
+        let nonce = self.next_multisig_nonce;
+        self.next_multisig_nonce += 1;
+
+        self.multisig_requests.push(MultisigRequest {
+            requester: params.sender,
+            approver,
+            amount,
+            nonce,
+            created_round: params.round_idx,
+        });
+
+        let event = MultisigRequested {
+            schema_version: SCHEMA_VERSION,
+            nonce,
+            approver: self.log_address(&env, approver, params.round_idx),
+            amount,
+            expires_after_round: params.round_idx + self.multisig_expiry_rounds,
+        };
+        let mut effects = Effects::default();
+        self.emit(&mut effects, Event::MultisigRequested(event));
+        carry_out(effects);
+
+        Ok(())
+    }
+
+    fn cash_out_multisig_approve(&mut self, params: &mut Parameters) -> Result<(), Box<dyn Error>> {
+        let mut consumed = 0;
+        let nonce: u64 = read_param(params, &mut consumed)?;
+
+        run(env(params), |env, effects| self.handle_multisig_approve(env, nonce, effects))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use smart_contract::payload::Writeable;
+
+    use super::*;
+    use crate::host::{parameters, LOGS, TRANSFERS};
+
+    // A call from the sender with the amount attached, in the given round.
+    struct Call {
+        sender: [u8; 32],
+        tx_id: [u8; 32],
+        amount: u64,
+        round_idx: u64,
+    }
+
+    fn call(sender: u8, tx: u8, amount: u64, round_idx: u64) -> Call {
+        Call {
+            sender: [sender; 32],
+            tx_id: [tx; 32],
+            amount,
+            round_idx,
+        }
+    }
+
+    fn payload(values: &[&dyn Writeable]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for value in values {
+            value.write_to(&mut bytes);
+        }
+
+        bytes
+    }
+
+    fn params(call: &Call, payload: &[u8]) -> Parameters {
+        parameters(call.sender, call.tx_id, call.amount, call.round_idx, payload)
+    }
+
+    // The contract as deployed with the payload, checking its accounting
+    // after every call.
+    fn deploy(payload: &[u8]) -> PrisonerDilemma {
+        let mut state = PrisonerDilemma::init(&mut params(&call(9, 0, 0, 0), payload));
+        state.invariants_enabled = true;
+
+        state
+    }
+
+    fn logs() -> Vec<String> {
+        LOGS.with(|logs| logs.borrow_mut().drain(..).collect())
+    }
+
+    fn transfers() -> Vec<Transfer> {
+        TRANSFERS.with(|transfers| transfers.borrow_mut().drain(..).collect())
+    }
+
+    // Throws random payloads, amounts and transactions at `play`. Whatever
+    // comes in, it has to return, without breaking the accounting.
+    #[test]
+    fn play_survives_arbitrary_parameters() {
+        let mut rng = rand::rngs::SmallRng::from_seed([66; 16]);
+
+        for seed in 0..20 {
+            let mut state = deploy(&[]);
+            state.min_stake = rng.gen_range(0, 1000);
+
+            for i in 0..500u64 {
+                // Every vote byte is tried, followed by random bytes
+                let mut bytes = vec![((seed * 500 + i) % 256) as u8];
+                let len = rng.gen_range(0, 80);
+                bytes.extend((0..len).map(|_| rng.gen::<u8>()));
+
+                let mut tx_id = [0u8; 32];
+                rng.fill(&mut tx_id);
+                let sender: u8 = rng.gen_range(0, 8);
+                // Amounts stay small enough for the deposits of a whole run to add up
+                let amount = match rng.gen_range(0, 4) {
+                    0 => 0,
+                    1 => rng.gen_range(0, 10_000),
+                    _ => rng.gen_range(0, u64::MAX >> 12),
+                };
+                let round_idx = i / 4 + rng.gen_range(0, 2);
+
+                let call = Call { sender: [sender; 32], tx_id, amount, round_idx };
+                if let Err(err) = state.play(&mut params(&call, &bytes)) {
+                    let message = err.to_string();
+                    assert!(!message.contains("Invariant broken"), "{}", message);
+                }
+
+                assert!(state.verify_conservation().is_ok());
+            }
+
+            logs();
+            transfers();
+        }
+    }
+
+    // The fuzz target on random inputs, so that it keeps building and
+    // passing between fuzzing runs.
+    #[test]
+    fn fuzz_target_survives_random_inputs() {
+        let mut rng = rand::rngs::SmallRng::from_seed([67; 16]);
+
+        for _ in 0..100 {
+            let len = rng.gen_range(0, 4000);
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            crate::fuzz::play(&data);
+        }
+    }
+
+    // All the deposits together can't exceed the supply of PERLs, so the
+    // largest amount is played against a match without a stake.
+    #[test]
+    fn play_survives_the_largest_amount() {
+        for vote in 0..=255u8 {
+            let mut state = deploy(&[]);
+
+            let _ = state.play(&mut params(&call(1, 1, u64::MAX, 1), &payload(&[&vote, &0u8])));
+            assert!(state.verify_conservation().is_ok());
+
+            let _ = state.play(&mut params(&call(2, 2, 0, 2), &payload(&[&2u8, &0u8])));
+            assert!(state.verify_conservation().is_ok());
+
+            logs();
+        }
+    }
+
+    // The events among the logs, by name.
+    fn events(logs: &[String], name: &str) -> Vec<serde_json::Value> {
+        logs.iter()
+            .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+            .filter(|v| v["event"] == name)
+            .collect()
+    }
+
+    fn balance(state: &mut PrisonerDilemma, sender: u8, round_idx: u64) -> u64 {
+        state.get_balance(&mut params(&call(sender, 0, 0, round_idx), &[])).unwrap();
+
+        events(&logs(), "balance_queried")[0]["balance"].as_u64().unwrap()
+    }
+
+    // Every play joins the oldest waiting match.
+    fn deploy_joining() -> PrisonerDilemma {
+        let config = r#"{"threshold":100}"#.to_string();
+        deploy(&payload(&[&true, &2u8, &Vec::<u64>::new(), &Vec::<u64>::new(), &config]))
+    }
+
+    #[test]
+    fn defector_cashes_out_the_winnings() {
+        let mut state = deploy_joining();
+        state.pot = 1_000_000;
+        state.total_deposits = 1_000_000;
+
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&2u8, &0u8]))).unwrap();
+        assert_eq!(events(&logs(), "match_created").len(), 1);
+
+        state.play(&mut params(&call(2, 2, 1000, 2), &payload(&[&1u8, &0u8]))).unwrap();
+        let resolved = events(&logs(), "match_resolved");
+        assert_eq!(resolved.len(), 1);
+
+        let result = &resolved[0];
+        assert_eq!(result["outcome"], crate::game::outcome(2, 1));
+        assert_eq!(result["player_1"]["vote"], 2);
+        assert_eq!(result["player_2"]["payout"], 0);
+
+        let pot_reward = result["defector_bonus"]["capped"].as_u64().unwrap();
+        assert!(pot_reward > 0);
+        assert_eq!(result["player_1"]["payout"], 1000 + 1000 + pot_reward);
+        assert_eq!(balance(&mut state, 1, 3), 1000 + 1000 + pot_reward);
+
+        state.cash_out(&mut params(&call(1, 3, 0, 3), &[])).unwrap();
+
+        let sent = transfers();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].destination, [1; 32]);
+        assert_eq!(sent[0].amount, 1000 + 1000 + pot_reward);
+        assert_eq!(balance(&mut state, 1, 4), 0);
+    }
+
+    #[test]
+    fn init_rejects_rewards_above_the_pot() {
+        let deploy_with = |config: &str| deploy(&payload(&[&false, &0u8, &Vec::<u64>::new(), &Vec::<u64>::new(), &config.to_string()]));
+
+        let state = deploy_with(r#"{"cooperate_bps":5001,"defect_bps":10001}"#);
+        assert_eq!(state.reward_schedule.cooperate_bps, RewardSchedule::default().cooperate_bps);
+        assert_eq!(state.reward_schedule.defect_bps, RewardSchedule::default().defect_bps);
+
+        let state = deploy_with(r#"{"cooperate_bps":5000,"defect_bps":10000}"#);
+        assert_eq!(state.reward_schedule.cooperate_bps, 5_000);
+        assert_eq!(state.reward_schedule.defect_bps, 10_000);
+    }
+
+    fn init(payload: &[u8]) -> PrisonerDilemma {
+        PrisonerDilemma::init(&mut params(&call(9, 0, 0, 0), payload))
+    }
+
+    #[test]
+    fn init_without_a_payload_keeps_the_defaults() {
+        let state = init(&[]);
+        let defaults = GameState::new([9; 32]);
+
+        assert!(!state.invariants_enabled);
+        assert_eq!(state.threshold_decay_mode, ThresholdMode::Linear);
+        assert!(state.stake_tiers.is_empty());
+        assert_eq!(state.threshold, defaults.threshold);
+        assert_eq!(state.min_stake, defaults.min_stake);
+        assert_eq!(state.matching_strategy, MatchingStrategy::Fifo);
+        assert_eq!(state.history_capacity, DEFAULT_HISTORY_CAPACITY);
+    }
+
+    #[test]
+    fn init_reads_a_partial_payload() {
+        let state = init(&payload(&[&true, &1u8]));
+
+        assert!(state.invariants_enabled);
+        assert_eq!(state.threshold_decay_mode, ThresholdMode::Exponential);
+        assert!(state.stake_tiers.is_empty());
+        assert_eq!(state.min_stake, GameState::new([9; 32]).min_stake);
+    }
+
+    #[test]
+    fn init_reads_a_full_payload() {
+        let config = json!({
+            "threshold": 80,
+            "min_stake": 10,
+            "cooperate_bps": 400,
+            "defect_bps": 900,
+            "matching_strategy": 1,
+            "match_expiry_rounds": 30,
+            "commitment_expiry_rounds": 7,
+            "history_capacity": 20,
+        }).to_string();
+        // The second tier has its bounds the wrong way around
+        let state = init(&payload(&[&false, &2u8, &vec![1001u64, 0, 5000], &vec![2000u64, 1000, 4000], &config]));
+
+        assert!(!state.invariants_enabled);
+        assert_eq!(state.threshold_decay_mode, ThresholdMode::Fixed);
+        assert_eq!(state.stake_tiers, vec![(0, 1000), (1001, 2000)]);
+        assert_eq!(state.threshold, 80);
+        assert_eq!(state.min_stake, 10);
+        assert_eq!(state.reward_schedule.cooperate_bps, 400);
+        assert_eq!(state.reward_schedule.defect_bps, 900);
+        assert_eq!(state.matching_strategy, MatchingStrategy::from_u8(1).unwrap());
+        assert_eq!(state.match_expiry_rounds, 30);
+        assert_eq!(state.commitment_expiry_rounds, 7);
+        assert_eq!(state.history_capacity, 20);
+    }
+
+    // Init takes the same values as set_params, and keeps the default
+    // for any other.
+    #[test]
+    fn init_rejects_what_set_params_rejects() {
+        let config = r#"{"threshold":101,"min_stake":0,"commitment_expiry_rounds":0,"history_capacity":0}"#.to_string();
+        let state = init(&payload(&[&false, &0u8, &Vec::<u64>::new(), &Vec::<u64>::new(), &config]));
+        let mut defaults = GameState::new([9; 32]);
+
+        assert_eq!(state.threshold, defaults.threshold);
+        assert_eq!(state.min_stake, defaults.min_stake);
+        assert_eq!(state.commitment_expiry_rounds, defaults.commitment_expiry_rounds);
+        assert_eq!(state.history_capacity, DEFAULT_HISTORY_CAPACITY);
+
+        for name in ["threshold", "min_stake", "commitment_expiry_rounds", "history_capacity"] {
+            let value = serde_json::from_str::<serde_json::Value>(&config).unwrap()[name].as_u64().unwrap();
+            assert!(defaults.set_param(name, value).is_err(), "{}", name);
+        }
+    }
+
+    // Every call that changes the state logs only events with a seq of
+    // their own, one past the last. Queries report the latest seq without
+    // taking one.
+    #[test]
+    fn seq_increases_across_every_kind_of_call() {
+        let mut state = deploy_joining();
+        let mut last = 0;
+
+        // Checks the logs of the call just made
+        let mut check = |query: bool| {
+            let logs = logs();
+            assert!(!logs.is_empty());
+
+            for l in logs {
+                let seq = serde_json::from_str::<serde_json::Value>(&l).unwrap()["seq"].as_u64().unwrap_or_else(|| panic!("no seq in {}", l));
+                if query {
+                    assert_eq!(seq, last, "{}", l);
+                } else {
+                    assert!(seq > last, "{}", l);
+                    last = seq;
+                }
+            }
+        };
+
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        check(false);
+        state.play(&mut params(&call(2, 2, 1000, 1), &payload(&[&2u8, &0u8]))).unwrap();
+        check(false);
+        state.get_balance(&mut params(&call(1, 0, 0, 2), &[])).unwrap();
+        check(true);
+        state.play(&mut params(&call(3, 3, 1000, 2), &payload(&[&1u8, &0u8]))).unwrap();
+        check(false);
+        let id = state.waiting[0].id.clone();
+        state.cancel(&mut params(&call(3, 4, 0, 2), &payload(&[&id]))).unwrap();
+        check(false);
+        state.pause(&mut params(&call(9, 5, 0, 3), &[])).unwrap();
+        check(false);
+        state.unpause(&mut params(&call(9, 6, 0, 3), &[])).unwrap();
+        check(false);
+        state.set_params(&mut params(&call(9, 7, 0, 3), &payload(&[&"min_stake".to_string(), &5u64]))).unwrap();
+        check(false);
+        state.set_max_waiting_pool(&mut params(&call(9, 8, 0, 3), &payload(&[&10u64]))).unwrap();
+        check(false);
+        state.set_random_matching(&mut params(&call(1, 9, 0, 3), &payload(&[&false]))).unwrap();
+        check(false);
+        state.set_alias(&mut params(&call(1, 10, 0, 3), b"alice\0")).unwrap();
+        check(false);
+        state.get_global_stats(&mut params(&call(1, 0, 0, 4), &[])).unwrap();
+        check(true);
+        state.play(&mut params(&call(4, 11, 1000, 4), &payload(&[&1u8, &0u8]))).unwrap();
+        check(false);
+    }
+
+    // Clients decode the logs into envelopes, so every event has to come
+    // back out of its JSON without losing a field.
+    #[test]
+    fn events_deserialize_back_into_envelopes() {
+        let mut state = deploy_joining();
+        state.pot = 1_000_000;
+        state.total_deposits = 1_000_000;
+        let mut logged = Vec::new();
+
+        state.set_alias(&mut params(&call(1, 1, 0, 1), b"alice\0")).unwrap();
+        state.play(&mut params(&call(1, 2, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        let id = state.waiting[0].id.clone();
+        state.place_side_bet(&mut params(&call(5, 3, 100, 1), &payload(&[&id, &3u8]))).unwrap();
+        state.top_up_match(&mut params(&call(1, 4, 500, 1), &payload(&[&id]))).unwrap();
+        state.play(&mut params(&call(2, 5, 1500, 2), &payload(&[&2u8, &0u8]))).unwrap();
+        logged.extend(logs());
+
+        state.play_many(&mut params(&call(3, 6, 2000, 2), &payload(&[&2u8, &1u8, &0u8]))).unwrap();
+        state.commit_vote(&mut params(&call(4, 7, 1000, 2), &payload(&[&[7u8; 32]]))).unwrap();
+        state.pause(&mut params(&call(9, 8, 0, 3), &[])).unwrap();
+        state.unpause(&mut params(&call(9, 9, 0, 3), &[])).unwrap();
+        state.set_params(&mut params(&call(9, 10, 0, 3), &payload(&[&"min_stake".to_string(), &5u64]))).unwrap();
+        state.result(&mut params(&call(1, 0, 0, 3), &payload(&[&id]))).unwrap();
+        state.get_balance(&mut params(&call(1, 0, 0, 3), &[])).unwrap();
+        state.get_global_stats(&mut params(&call(1, 0, 0, 3), &[])).unwrap();
+        state.shutdown(&mut params(&call(9, 11, 0, 4), &payload(&[&true]))).unwrap();
+        logged.extend(logs());
+
+        let mut names = HashSet::new();
+        for l in &logged {
+            let value: serde_json::Value = serde_json::from_str(l).unwrap();
+            if value.get("event").is_none() {
+                continue;
+            }
+
+            let envelope: crate::events::Envelope = serde_json::from_str(l).unwrap_or_else(|err| panic!("{}: {}", err, l));
+            assert_eq!(serde_json::to_value(&envelope).unwrap(), value);
+            names.insert(value["event"].as_str().unwrap().to_string());
+        }
+
+        for name in ["match_created", "match_resolved", "side_bet_placed", "match_topped_up", "batch_played", "vote_committed", "pause_changed", "config_changed", "contract_shutdown"] {
+            assert!(names.contains(name), "{} was not logged", name);
+        }
+
+        // The settled match kept its typed details
+        let resolved = &events(&logged, "match_resolved")[0];
+        assert_eq!(resolved["player_1"]["alias"], "alice");
+        assert!(resolved["defector_bonus"]["capped"].as_u64().unwrap() > 0);
+        assert_eq!(resolved["side_bets"][0]["predicted_outcome"], 3);
+    }
+
+    #[test]
+    fn leaderboard_is_sorted_and_repeatable() {
+        let mut state = deploy(&[]);
+        for (sender, balance) in [(3u8, 50u64), (1, 50), (2, 70), (4, 10)] {
+            state.balances.insert([sender; 32], balance);
+        }
+
+        let mut leaderboard = |limit: u32| {
+            state.leaderboard(&mut params(&call(1, 1, 0, 1), &payload(&[&"balance".to_string(), &limit]))).unwrap();
+            logs().pop().unwrap()
+        };
+
+        let first = leaderboard(3);
+        assert_eq!(first, leaderboard(3));
+
+        // Equal balances are ordered by address
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&first).unwrap();
+        let senders: Vec<String> = entries.iter().map(|e| e["sender"].as_str().unwrap().to_string()).collect();
+        assert_eq!(senders, vec![to_hex_string([2; 32]), to_hex_string([1; 32]), to_hex_string([3; 32])]);
+        assert_eq!(entries[0]["rank"], 1);
+        assert_eq!(entries[0]["value"], 70);
+    }
+
+    #[test]
+    fn leaderboard_rejects_unknown_metrics() {
+        let mut state = deploy(&[]);
+
+        let result = state.leaderboard(&mut params(&call(1, 1, 0, 1), &payload(&[&"losses".to_string(), &10u32])));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn exported_state_imports_into_a_fresh_contract() {
+        let mut state = deploy_joining();
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        state.play(&mut params(&call(2, 2, 1000, 1), &payload(&[&2u8, &0u8]))).unwrap();
+        logs();
+
+        assert!(state.export_state(&mut params(&call(1, 3, 0, 2), &[])).is_err());
+
+        state.export_state(&mut params(&call(9, 3, 0, 2), &[])).unwrap();
+        let exported: String = logs().iter()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["data"].as_str().unwrap().to_string())
+            .collect();
+
+        let mut imported = deploy(&[]);
+        imported.import_state(&mut params(&call(9, 4, 0, 3), &payload(&[&exported]))).unwrap();
+        logs();
+
+        assert_eq!(imported.balances, state.balances);
+        assert_eq!(imported.pot, state.pot);
+        let ids = |s: &PrisonerDilemma| s.history.iter().map(|m| m.id.clone()).collect::<Vec<String>>();
+        assert_eq!(ids(&imported), ids(&state));
+    }
+
+    #[test]
+    fn results_batch_marks_missing_and_pending_matches() {
+        let mut state = deploy_joining();
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        state.play(&mut params(&call(2, 2, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        state.play(&mut params(&call(3, 3, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        logs();
+
+        let ids = vec![state.history[0].id.clone(), state.waiting[0].id.clone(), "missing".to_string()];
+        let mut batch = |ids: &[String]| {
+            let ids = serde_json::to_string(ids).unwrap();
+            state.get_results_batch(&mut params(&call(4, 4, 0, 2), &payload(&[&ids]))).map(|_| logs().pop().unwrap())
+        };
+
+        let results: Vec<serde_json::Value> = serde_json::from_str(&batch(&ids).unwrap()).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["match_id"], json!(ids[0]));
+        assert_eq!(results[0]["outcome"], "cc");
+        assert_eq!(results[1]["error"], "pending");
+        assert_eq!(results[2]["error"], "not_found");
+
+        assert_eq!(batch(&[]).unwrap(), "[]");
+        assert!(batch(&vec![ids[0].clone(); MAX_RESULTS_BATCH_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn aliases_are_validated_and_shown_in_results() {
+        let mut state = deploy_joining();
+        let set_alias = |state: &mut PrisonerDilemma, alias: &[u8]| {
+            let bytes: Vec<u8> = alias.iter().copied().chain(std::iter::once(0)).collect();
+            state.set_alias(&mut params(&call(1, 0, 0, 1), &bytes))
+        };
+
+        set_alias(&mut state, b"alice").unwrap();
+        set_alias(&mut state, b"bob").unwrap();
+        assert!(set_alias(&mut state, &[b'a'; MAX_ALIAS_LENGTH + 1]).is_err());
+        assert!(set_alias(&mut state, &[0xff, 0xfe]).is_err());
+        assert_eq!(state.aliases.get(&[1; 32]).map(String::as_str), Some("bob"));
+
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        state.play(&mut params(&call(2, 2, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        let resolved = events(&logs(), "match_resolved");
+        assert_eq!(resolved[0]["player_1"]["alias"], "bob");
+        assert!(resolved[0]["player_2"].get("alias").is_none());
+    }
+
+    // Three matches waiting in the pool, one per stake tier so that they
+    // can't be matched with each other. Player n staked n * 1000.
+    fn deploy_waiting() -> PrisonerDilemma {
+        let mut state = deploy_joining();
+        state.stake_tiers = vec![(0, 1000), (1001, 2000), (2001, 3000)];
+
+        for sender in 1..=3u8 {
+            let stake = sender as u64 * 1000;
+            state.play(&mut params(&call(sender, sender, stake, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        }
+        logs();
+
+        state
+    }
+
+    #[test]
+    fn queue_position_counts_the_matches_ahead() {
+        let mut state = deploy_waiting();
+        let ids: Vec<String> = state.waiting.iter().map(|m| m.id.clone()).collect();
+
+        for (position, id) in ids.iter().enumerate() {
+            state.get_queue_position(&mut params(&call(4, 4, 0, 2), &payload(&[id]))).unwrap();
+            let result: serde_json::Value = serde_json::from_str(&logs()[0]).unwrap();
+            assert_eq!(result["position"], position);
+            assert_eq!(result["queue_length"], 3);
+        }
+
+        // A resolved match isn't queued anymore
+        state.play(&mut params(&call(4, 4, 1000, 2), &payload(&[&1u8, &0u8]))).unwrap();
+        logs();
+        assert!(state.get_queue_position(&mut params(&call(4, 5, 0, 2), &payload(&[&ids[0]]))).is_err());
+    }
+
+    #[test]
+    fn transferred_match_belongs_to_the_new_owner() {
+        let mut state = deploy_waiting();
+        let id = state.waiting[0].id.clone();
+        let transfer = |state: &mut PrisonerDilemma, sender: u8, id: &String| {
+            state.transfer_match(&mut params(&call(sender, 10, 0, 2), &payload(&[id, &[5u8; 32]])))
+        };
+
+        assert!(transfer(&mut state, 2, &id).is_err());
+        transfer(&mut state, 1, &id).unwrap();
+        assert_eq!(state.waiting[0].p1.sender, [5; 32]);
+        assert_eq!(state.waiting[0].p1.stake, 1000);
+
+        let cancel = |state: &mut PrisonerDilemma, sender: u8| state.cancel(&mut params(&call(sender, 11, 0, 2), &payload(&[&id])));
+        assert!(cancel(&mut state, 1).is_err());
+        cancel(&mut state, 5).unwrap();
+        assert_eq!(balance(&mut state, 5, 2), 1000);
+
+        // Player 4 resolves the match of player 2
+        let resolved = state.waiting[0].id.clone();
+        state.play(&mut params(&call(4, 12, 2000, 2), &payload(&[&1u8, &0u8]))).unwrap();
+        assert!(transfer(&mut state, 2, &resolved).is_err());
+
+        assert!(transfer(&mut state, 5, &"missing".to_string()).is_err());
+        logs();
+    }
+
+    #[test]
+    fn surplus_pot_is_shared_by_the_recent_players() {
+        let mut state = deploy_joining();
+        for (sender, tx) in [(1, 1), (2, 2), (3, 3), (1, 4)] {
+            state.play(&mut params(&call(sender, tx, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        }
+        logs();
+
+        let distribute = |state: &mut PrisonerDilemma, sender: u8| state.distribute_surplus_pot(&mut params(&call(sender, 5, 0, 2), &[]));
+
+        // The pot is topped up to 100 PERLs above the threshold
+        state.surplus_threshold = state.pot;
+        assert!(distribute(&mut state, 9).is_err());
+        state.pot += 100;
+        state.total_deposits += 100;
+
+        assert!(distribute(&mut state, 1).is_err());
+
+        let before: Vec<u64> = (1..=3).map(|sender| state.balances[&[sender; 32]]).collect();
+        distribute(&mut state, 9).unwrap();
+        logs();
+
+        for (sender, before) in (1..=3).zip(before) {
+            assert_eq!(state.balances[&[sender; 32]], before + 33);
+        }
+        assert_eq!(state.pot, state.surplus_threshold + 1);
+        state.verify_conservation().unwrap();
+    }
+
+    #[test]
+    fn conditional_vote_depends_on_the_opponent_stake() {
+        for &(needed, opponent_stake, resolved) in &[(500u64, 1000u64, 1u8), (500, 3000, 1), (2000, 1000, 2), (2000, 3000, 1)] {
+            let mut state = deploy_joining();
+            state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&4u8, &0u8, &needed]))).unwrap();
+            state.play(&mut params(&call(2, 2, opponent_stake, 1), &payload(&[&1u8, &0u8]))).unwrap();
+
+            let resolved_event = &events(&logs(), "match_resolved")[0];
+            assert_eq!(resolved_event["player_1"]["vote"], resolved, "{} {}", needed, opponent_stake);
+            assert_eq!(resolved_event["player_1"]["original_vote"], 4);
+            assert!(resolved_event["player_2"].get("original_vote").is_none());
+        }
+    }
+
+    #[test]
+    fn multisig_cash_out_needs_the_approver() {
+        let mut state = deploy(&[]);
+        state.balances.insert([1; 32], 1000);
+        state.total_deposits = 1000;
+
+        let request = |state: &mut PrisonerDilemma, amount: u64, round: u64| {
+            state.cash_out_multisig_request(&mut params(&call(1, 1, 0, round), &payload(&[&[2u8; 32], &amount]))).unwrap();
+            state.next_multisig_nonce - 1
+        };
+        let approve = |state: &mut PrisonerDilemma, sender: u8, nonce: u64, round: u64| {
+            state.cash_out_multisig_approve(&mut params(&call(sender, 2, 0, round), &payload(&[&nonce])))
+        };
+
+        let nonce = request(&mut state, 400, 1);
+        assert!(approve(&mut state, 3, nonce, 1).is_err());
+        assert!(transfers().is_empty());
+
+        approve(&mut state, 2, nonce, 2).unwrap();
+        let sent = transfers();
+        assert_eq!((sent.len(), sent[0].destination, sent[0].amount), (1, [1; 32], 400));
+        assert!(approve(&mut state, 2, nonce, 2).is_err());
+
+        let expired = request(&mut state, 100, 3);
+        let expiry = state.multisig_expiry_rounds;
+        assert!(approve(&mut state, 2, expired, 3 + expiry + 1).is_err());
+        assert!(transfers().is_empty());
+
+        // The requester can still cash out alone
+        state.cash_out(&mut params(&call(1, 3, 0, 200), &[])).unwrap();
+        assert_eq!(transfers()[0].amount, 600);
+        logs();
+    }
+
+    #[test]
+    fn delegate_cashes_out_to_the_balance_owner() {
+        let mut state = deploy(&[]);
+        state.balances.insert([1; 32], 1000);
+        state.total_deposits = 1000;
+        let cash_out_for = |state: &mut PrisonerDilemma, sender: u8, round: u64| {
+            state.cash_out(&mut params(&call(sender, round as u8, 0, round), &payload(&[&[1u8; 32]])))
+        };
+
+        assert!(state.set_cash_out_delegate(&mut params(&call(1, 1, 0, 1), &payload(&[&[1u8; 32]]))).is_err());
+        state.set_cash_out_delegate(&mut params(&call(1, 1, 0, 1), &payload(&[&[2u8; 32]]))).unwrap();
+
+        assert_eq!(code(cash_out_for(&mut state, 3, 2).unwrap_err()), "NOT_DELEGATE");
+        assert!(transfers().is_empty());
+        assert_eq!(balance(&mut state, 1, 2), 1000);
+
+        // The PERLs go to the player, the delegate only sends the transaction
+        cash_out_for(&mut state, 2, 3).unwrap();
+        let sent = transfers();
+        assert_eq!((sent.len(), sent[0].destination, sent[0].amount), (1, [1; 32], 1000));
+        assert_eq!((balance(&mut state, 1, 3), balance(&mut state, 2, 3)), (0, 0));
+
+        state.balances.insert([1; 32], 500);
+        state.total_deposits += 500;
+        state.revoke_cash_out_delegate(&mut params(&call(1, 4, 0, 4), &[])).unwrap();
+        assert_eq!(code(cash_out_for(&mut state, 2, 5).unwrap_err()), "NOT_DELEGATE");
+        assert!(transfers().is_empty());
+        logs();
+    }
+
+    // (vote of player 1, vote of player 2, round created, round joined),
+    // each match between two new players.
+    const SCRIPT: [(u8, u8, u64, u64); 5] = [(1, 1, 1, 1), (1, 2, 2, 4), (2, 1, 4, 5), (2, 2, 5, 5), (1, 1, 6, 9)];
+
+    // Plays the script, and returns the match_resolved events it logged.
+    fn play_script(state: &mut PrisonerDilemma) -> Vec<serde_json::Value> {
+        let mut resolved = Vec::new();
+        for (i, (vote1, vote2, created, joined)) in SCRIPT.iter().enumerate() {
+            let (p1, p2) = (2 * i as u8 + 1, 2 * i as u8 + 2);
+            state.play(&mut params(&call(p1, p1, 1000, *created), &payload(&[vote1, &0u8]))).unwrap();
+            state.play(&mut params(&call(p2, p2, 1000, *joined), &payload(&[vote2, &0u8]))).unwrap();
+            resolved.extend(events(&logs(), "match_resolved"));
+        }
+
+        resolved
+    }
+
+    // Every counter is checked against what the matches of a scripted
+    // session add up to.
+    #[test]
+    fn counters_add_up_over_a_scripted_session() {
+        let mut state = deploy_joining();
+        state.pot = 1_000_000;
+        state.total_deposits = 1_000_000;
+
+        let resolved = play_script(&mut state);
+        assert_eq!(resolved.len(), SCRIPT.len());
+
+        // The votes are counted in the round the match resolved in
+        let mut rounds: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        for (vote1, vote2, _, joined) in SCRIPT {
+            let round = rounds.entry(joined).or_default();
+            for vote in [vote1, vote2] {
+                if vote == 1 { round.0 += 1 } else { round.1 += 1 }
+            }
+        }
+        let expected: Vec<serde_json::Value> = rounds.iter()
+            .map(|(round, (cooperations, defections))| serde_json::json!({
+                "round": round,
+                "cooperations": cooperations,
+                "defections": defections,
+                "coop_rate_pct": cooperations * 100 / (cooperations + defections),
+            }))
+            .collect();
+
+        state.get_round_stats(&mut params(&call(1, 0, 0, 10), &payload(&[&0u64, &10u64]))).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&logs()[0]).unwrap(), serde_json::json!(expected));
+
+        // The outcomes, the pot changes of the matches and their waits
+        let mut outcomes = BTreeMap::new();
+        let mut avg_wait_rounds = 0.0;
+        for (i, (vote1, vote2, created, joined)) in SCRIPT.iter().enumerate() {
+            let outcome = format!("{}{}_count", if *vote1 == 1 { "c" } else { "d" }, if *vote2 == 1 { "c" } else { "d" });
+            *outcomes.entry(outcome).or_insert(0) += 1;
+
+            let wait = (joined - created) as f64;
+            avg_wait_rounds = if i == 0 { wait } else { avg_wait_rounds + (wait - avg_wait_rounds) * crate::game::WAIT_ROUNDS_EMA_ALPHA };
+        }
+        let pot_changes: Vec<i64> = resolved.iter().map(|m| m["pot_change"].as_i64().unwrap()).collect();
+        let pot_inflow: i64 = pot_changes.iter().filter(|change| **change > 0).sum();
+        let pot_outflow: i64 = -pot_changes.iter().filter(|change| **change < 0).sum::<i64>();
+        assert!(pot_inflow > 0 && pot_outflow > 0);
+
+        state.get_global_stats(&mut params(&call(1, 0, 0, 10), &[])).unwrap();
+        let stats = events(&logs(), "global_stats_queried").remove(0);
+        assert_eq!(stats["matches_resolved"], SCRIPT.len());
+        for (outcome, count) in &outcomes {
+            assert_eq!(stats[outcome], *count, "{}", outcome);
+        }
+        assert_eq!(outcomes.len(), 4);
+        assert_eq!((stats["pot_inflow"].as_i64(), stats["pot_outflow"].as_i64()), (Some(pot_inflow), Some(pot_outflow)));
+        assert_eq!(state.pot as i64, 1_000_000 + pot_inflow - pot_outflow);
+        assert_eq!(stats["avg_wait_rounds"].as_f64(), Some(avg_wait_rounds));
+
+        // A stake left waiting, and everyone cashing out what they won
+        state.play(&mut params(&call(11, 11, 1000, 10), &payload(&[&1u8, &0u8]))).unwrap();
+        let payouts: u64 = resolved.iter()
+            .map(|m| m["player_1"]["payout"].as_u64().unwrap() + m["player_2"]["payout"].as_u64().unwrap())
+            .sum();
+        for sender in 1..=10 {
+            if balance(&mut state, sender, 11) > 0 {
+                state.cash_out(&mut params(&call(sender, sender, 0, 11), &[])).unwrap();
+            }
+        }
+        let cashed_out: u64 = transfers().iter().map(|transfer| transfer.amount).sum();
+        assert_eq!(cashed_out, payouts);
+        logs();
+
+        let cooperations = SCRIPT.iter().map(|(vote1, vote2, _, _)| (*vote1 == 1) as u64 + (*vote2 == 1) as u64).sum::<u64>();
+        state.get_global_stats(&mut params(&call(1, 0, 0, 12), &[])).unwrap();
+        let stats = events(&logs(), "global_stats_queried").remove(0);
+        assert_eq!(stats["matches_resolved"], SCRIPT.len());
+        assert_eq!(stats["total_staked"], 1000 * (2 * SCRIPT.len() as u64 + 1));
+        assert_eq!(stats["total_cashed_out"], cashed_out);
+        assert_eq!(stats["coop_rate_pct"], cooperations * 100 / (2 * SCRIPT.len() as u64));
+        assert_eq!(stats["waiting"], 1);
+        assert_eq!(stats["threshold"], 100);
+        assert_eq!(stats["pot"], 1_000_000 + pot_inflow as u64 - pot_outflow as u64);
+    }
+
+    #[test]
+    fn stats_show_the_return_on_the_stakes() {
+        let mut state = deploy_joining();
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&2u8, &0u8]))).unwrap();
+        state.play(&mut params(&call(2, 2, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        logs();
+
+        let mut stats = |sender: u8| {
+            state.get_stats(&mut params(&call(sender, 0, 0, 2), &[])).unwrap();
+            serde_json::from_str::<serde_json::Value>(&logs()[0]).unwrap()
+        };
+
+        // The defector took both stakes, the pot being empty
+        let defector = stats(1);
+        assert_eq!((defector["lifetime_staked"].as_u64(), defector["lifetime_earned"].as_u64()), (Some(1000), Some(2000)));
+        assert_eq!(defector["roi_pct"], 100);
+
+        let cooperator = stats(2);
+        assert_eq!((cooperator["lifetime_staked"].as_u64(), cooperator["lifetime_earned"].as_u64()), (Some(1000), Some(0)));
+        assert_eq!(cooperator["roi_pct"], -100);
+
+        let newcomer = stats(3);
+        assert_eq!((newcomer["lifetime_staked"].as_u64(), newcomer["roi_pct"].as_i64()), (Some(0), Some(0)));
+    }
+
+    type Query = fn(&mut PrisonerDilemma, &mut Parameters) -> Result<(), Box<dyn Error>>;
+
+    // Queries run against a state with waiting and resolved matches, and
+    // have to leave every byte of it as it was.
+    #[test]
+    fn queries_do_not_change_the_state() {
+        let mut state = deploy_waiting();
+        state.play(&mut params(&call(4, 4, 1000, 2), &payload(&[&1u8, &0u8]))).unwrap();
+        logs();
+
+        let resolved = state.history[0].id.clone();
+        let waiting = state.waiting[0].id.clone();
+        let (p1, p4) = (to_hex_string([1; 32]), to_hex_string([4; 32]));
+        let batch = serde_json::to_string(&[&resolved, &waiting]).unwrap();
+
+        let queries: Vec<(&str, Query, Vec<u8>)> = vec![
+            ("result", PrisonerDilemma::result, payload(&[&resolved])),
+            ("get_history", PrisonerDilemma::get_history, payload(&[&0u32, &10u32])),
+            ("history_for", PrisonerDilemma::history_for, payload(&[&p1, &10u32])),
+            ("match_status", PrisonerDilemma::match_status, payload(&[&waiting])),
+            ("result_by_tx", PrisonerDilemma::result_by_tx, payload(&[&p4])),
+            ("get_queue_position", PrisonerDilemma::get_queue_position, payload(&[&waiting])),
+            ("my_matches", PrisonerDilemma::my_matches, vec![]),
+            ("get_results_batch", PrisonerDilemma::get_results_batch, payload(&[&batch])),
+            ("get_pair_history", PrisonerDilemma::get_pair_history, payload(&[&p1, &p4])),
+            ("get_round_stats", PrisonerDilemma::get_round_stats, payload(&[&0u64, &10u64])),
+            ("get_coop_rate", PrisonerDilemma::get_coop_rate, vec![]),
+            ("get_strategy_distribution", PrisonerDilemma::get_strategy_distribution, vec![]),
+            ("get_global_stats", PrisonerDilemma::get_global_stats, vec![]),
+            ("get_balance", PrisonerDilemma::get_balance, vec![]),
+            ("get_config", PrisonerDilemma::get_config, vec![]),
+            ("get_tier_queues", PrisonerDilemma::get_tier_queues, vec![]),
+            ("get_pot", PrisonerDilemma::get_pot, vec![]),
+            ("get_stats", PrisonerDilemma::get_stats, vec![]),
+            ("get_reputation", PrisonerDilemma::get_reputation, payload(&[&p1])),
+            ("get_player_rank", PrisonerDilemma::get_player_rank, vec![]),
+            ("get_achievements", PrisonerDilemma::get_achievements, vec![]),
+            ("leaderboard", PrisonerDilemma::leaderboard, payload(&[&"balance".to_string(), &10u32])),
+            ("get_merkle_proof", PrisonerDilemma::get_merkle_proof, payload(&[&resolved])),
+            ("snapshot", PrisonerDilemma::snapshot, vec![]),
+            ("export_state", PrisonerDilemma::export_state, vec![]),
+            ("get_balance_of", PrisonerDilemma::get_balance_of, payload(&[&p1])),
+            ("get_balances", PrisonerDilemma::get_balances, payload(&[&0u32, &10u32])),
+        ];
+
+        for (name, query, payload) in queries {
+            let before = serde_json::to_vec(&state).unwrap();
+            let result = query(&mut state, &mut params(&call(9, 20, 0, 3), &payload));
+            assert!(result.is_ok(), "{}: {:?}", name, result.err());
+            assert_eq!(serde_json::to_vec(&state).unwrap(), before, "{}", name);
+            assert!(!logs().is_empty(), "{}", name);
+        }
+    }
+
+    // Folds the proof of the leaf at the index into the root it proves.
+    fn proven_root(leaf: [u8; 32], mut index: usize, proof: &[[u8; 32]]) -> [u8; 32] {
+        let mut node = leaf;
+        for sibling in proof {
+            let pair = if index.is_multiple_of(2) { [node, *sibling] } else { [*sibling, node] };
+            node = merkle_level(&pair)[0];
+            index /= 2;
+        }
+
+        node
+    }
+
+    #[test]
+    fn merkle_proofs_verify_against_the_root() {
+        let leaves: Vec<[u8; 32]> = (0..5u8).map(|i| sha256(&[i])).collect();
+        let root = compute_merkle_root(&leaves);
+
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = compute_proof(&leaves, index);
+            assert_eq!(proven_root(*leaf, index, &proof), root, "leaf {}", index);
+
+            // Another leaf doesn't verify with the same proof
+            assert_ne!(proven_root(sha256(b"other"), index, &proof), root, "leaf {}", index);
+        }
+
+        assert_eq!(compute_merkle_root(&leaves[..1]), leaves[0]);
+        assert!(compute_proof(&leaves[..1], 0).is_empty());
+    }
+
+    #[test]
+    fn merkle_proof_query_proves_the_match() {
+        let mut state = deploy_joining();
+        for tx in 1..=6u8 {
+            state.play(&mut params(&call(tx % 2 + 1, tx, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        }
+        logs();
+
+        let id = state.history[1].id.clone();
+        state.get_merkle_proof(&mut params(&call(3, 0, 0, 2), &payload(&[&id]))).unwrap();
+        let result: serde_json::Value = serde_json::from_str(&logs()[0]).unwrap();
+
+        let hex = |v: &serde_json::Value| from_hex_string(v.as_str().unwrap()).unwrap();
+        let proof: Vec<[u8; 32]> = result["proof"].as_array().unwrap().iter().map(hex).collect();
+        assert_eq!(hex(&result["leaf"]), merkle_leaf(&state.history[1]));
+        assert_eq!(proven_root(hex(&result["leaf"]), 1, &proof), hex(&result["root"]));
+    }
+
+    #[test]
+    fn stakes_are_matched_within_their_tier() {
+        let mut state = deploy_joining();
+        state.stake_tiers = vec![(1, 1000), (1000, 2000)];
+
+        // 1000 is on the boundary of both tiers and goes to the lower one
+        for (sender, stake) in [(1u8, 500u64), (2, 1500), (3, 1000)] {
+            state.play(&mut params(&call(sender, sender, stake, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        }
+        assert_eq!(state.history.len(), 1);
+        assert_eq!((state.history[0].p1.stake, state.history[0].p2.as_ref().unwrap().stake), (500, 1000));
+        logs();
+
+        state.play(&mut params(&call(4, 4, 5000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        logs();
+        state.get_tier_queues(&mut params(&call(4, 0, 0, 1), &[])).unwrap();
+        let queues: serde_json::Value = serde_json::from_str(&logs()[0]).unwrap();
+        assert_eq!(queues["tiers"][0]["waiting"], 0);
+        assert_eq!(queues["tiers"][1]["waiting"], 1);
+        assert_eq!(queues["untiered"], 1);
+    }
+
+    #[test]
+    fn players_are_ranked_by_lifetime_earnings() {
+        let rank = |state: &mut PrisonerDilemma, sender: u8| {
+            logs();
+            state.get_player_rank(&mut params(&call(sender, 0, 0, 1), &[])).unwrap();
+            let result: serde_json::Value = serde_json::from_str(&logs()[0]).unwrap();
+            (result["rank"].as_u64().unwrap(), result["total_players"].as_u64().unwrap())
+        };
+
+        let mut state = deploy_joining();
+        state.stats.insert([1; 32], PlayerStats { lifetime_earned: 500, ..PlayerStats::default() });
+        state.earnings_index = index_earnings(&state.stats);
+        assert_eq!(rank(&mut state, 1), (1, 1));
+
+        for (sender, earned) in [(2u8, 300u64), (3, 300), (4, 100)] {
+            state.stats.insert([sender; 32], PlayerStats { lifetime_earned: earned, ..PlayerStats::default() });
+        }
+        state.earnings_index = index_earnings(&state.stats);
+
+        assert_eq!(rank(&mut state, 1), (1, 4));
+        // Tied players share the better rank.
+        assert_eq!(rank(&mut state, 2), (2, 4));
+        assert_eq!(rank(&mut state, 3), (2, 4));
+        assert_eq!(rank(&mut state, 4), (4, 4));
+    }
+
+    #[test]
+    fn reset_wipes_the_stats_but_not_the_balances() {
+        let mut state = deploy_joining();
+        for sender in 1..=3u8 {
+            state.stats.insert([sender; 32], PlayerStats { matches_played: 5, lifetime_earned: 100 * sender as u64, ..PlayerStats::default() });
+            state.achievements.insert([sender; 32], BADGE_NAMES[0].0);
+            state.balances.insert([sender; 32], 1_000);
+        }
+        state.earnings_index = index_earnings(&state.stats);
+        logs();
+
+        let reset = |addresses: &[u8]| {
+            let addresses: Vec<String> = addresses.iter().map(|a| to_hex_string([*a; 32])).collect();
+            payload(&[&serde_json::to_string(&addresses).unwrap()])
+        };
+
+        assert!(state.reset_player_stats(&mut params(&call(1, 0, 0, 1), &reset(&[1]))).is_err());
+        assert!(state.stats.contains_key(&[1; 32]));
+
+        state.reset_player_stats(&mut params(&call(9, 0, 0, 1), &reset(&[1]))).unwrap();
+        assert_eq!(events(&logs(), "player_stats_reset").len(), 1);
+        assert!(!state.stats.contains_key(&[1; 32]));
+        assert!(!state.achievements.contains_key(&[1; 32]));
+        assert_eq!(state.stats.len(), 2);
+        assert_eq!(state.earnings_index, index_earnings(&state.stats));
+
+        state.reset_player_stats(&mut params(&call(9, 0, 0, 1), &reset(&[]))).unwrap();
+        assert_eq!(events(&logs(), "player_stats_reset").len(), 2);
+        assert!(state.stats.is_empty());
+        assert!(state.achievements.is_empty());
+        assert!(state.earnings_index.is_empty());
+
+        for sender in 1..=3u8 {
+            assert_eq!(state.balances[&[sender; 32]], 1_000);
+        }
+    }
+
+    fn code(err: Box<dyn Error>) -> String {
+        let error: serde_json::Value = serde_json::from_str(&err.to_string()).unwrap();
+        error["code"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn unpausing_a_shut_down_contract_is_rejected() {
+        let mut state = deploy_joining();
+        state.shut_down = true;
+
+        assert_eq!(code(state.unpause(&mut params(&call(9, 0, 0, 1), &[])).unwrap_err()), "SHUT_DOWN");
+    }
+
+    type EntryPoint = fn(&mut PrisonerDilemma, &mut Parameters) -> Result<(), Box<dyn Error>>;
+
+    // Every call that stakes something is refused while paused, with the
+    // stake kept in the sender's balance, and goes through once unpaused. The
+    // owner can configure the contract and players can leave it either way.
+    #[test]
+    fn pause_only_blocks_new_stakes() {
+        let mut state = deploy_joining();
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        state.set_random_matching(&mut params(&call(3, 2, 0, 1), &payload(&[&false]))).unwrap();
+        state.play(&mut params(&call(3, 3, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        let (waiting, challenged) = (state.waiting[0].id.clone(), state.challenge_only_waiting[0].id.clone());
+
+        let stakes: Vec<(&str, EntryPoint, u8, u64, Vec<u8>)> = vec![
+            ("place_side_bet", PrisonerDilemma::place_side_bet, 5, 100, payload(&[&waiting, &3u8])),
+            ("top_up_match", PrisonerDilemma::top_up_match, 1, 500, payload(&[&waiting])),
+            ("challenge", PrisonerDilemma::challenge, 2, 1000, payload(&[&challenged, &1u8])),
+            ("commit_vote", PrisonerDilemma::commit_vote, 4, 1000, payload(&[&[7u8; 32]])),
+            ("play_many", PrisonerDilemma::play_many, 6, 2000, payload(&[&2u8, &1u8, &0u8])),
+            ("play", PrisonerDilemma::play, 7, 1000, payload(&[&1u8, &0u8])),
+        ];
+        let owner_calls: Vec<(&str, EntryPoint, Vec<u8>)> = vec![
+            ("set_params", PrisonerDilemma::set_params, payload(&[&"min_stake".to_string(), &1u64])),
+            ("set_coop_jackpot", PrisonerDilemma::set_coop_jackpot, payload(&[&0u64])),
+            ("set_milestone_bonus", PrisonerDilemma::set_milestone_bonus, payload(&[&0u64])),
+            ("set_jackpot", PrisonerDilemma::set_jackpot, payload(&[&0u64, &5_000u64])),
+            ("set_match_expiry", PrisonerDilemma::set_match_expiry, payload(&[&0u64, &5u64])),
+            ("set_max_waiting_pool", PrisonerDilemma::set_max_waiting_pool, payload(&[&0u64])),
+            ("set_anonymous", PrisonerDilemma::set_anonymous, payload(&[&false])),
+            ("set_reputation_floor", PrisonerDilemma::set_reputation_floor, payload(&[&0u64])),
+        ];
+
+        state.pause(&mut params(&call(9, 10, 0, 2), &[])).unwrap();
+        for (i, (name, entry, sender, amount, payload)) in stakes.iter().enumerate() {
+            let before = balance(&mut state, *sender, 2);
+            let err = entry(&mut state, &mut params(&call(*sender, 20 + i as u8, *amount, 2), payload)).unwrap_err();
+
+            assert_eq!(code(err), "PAUSED", "{}", name);
+            assert_eq!(balance(&mut state, *sender, 2), before + amount, "{}", name);
+        }
+        for (name, entry, payload) in &owner_calls {
+            let result = entry(&mut state, &mut params(&call(9, 0, 0, 2), payload));
+            assert!(result.is_ok(), "{} while paused: {:?}", name, result.err());
+        }
+
+        state.unpause(&mut params(&call(9, 11, 0, 3), &[])).unwrap();
+        for (i, (name, entry, sender, amount, payload)) in stakes.iter().enumerate() {
+            let result = entry(&mut state, &mut params(&call(*sender, 30 + i as u8, *amount, 3), payload));
+            assert!(result.is_ok(), "{}: {:?}", name, result.err());
+        }
+        for (name, entry, payload) in &owner_calls {
+            let result = entry(&mut state, &mut params(&call(9, 0, 0, 3), payload));
+            assert!(result.is_ok(), "{}: {:?}", name, result.err());
+        }
+
+        state.play(&mut params(&call(8, 40, 1000, 3), &payload(&[&1u8, &0u8]))).unwrap();
+        let id = state.waiting[0].id.clone();
+        state.pause(&mut params(&call(9, 12, 0, 4), &[])).unwrap();
+        logs();
+
+        state.cancel(&mut params(&call(8, 41, 0, 4), &payload(&[&id]))).unwrap();
+        assert_eq!(balance(&mut state, 8, 4), 1000);
+        state.cash_out(&mut params(&call(8, 42, 0, 4), &[])).unwrap();
+        assert_eq!(transfers().iter().map(|t| (t.destination, t.amount)).collect::<Vec<_>>(), vec![([8; 32], 1000)]);
+    }
+
+    // Nobody but the owner gets past only_owner, not even the proposed next
+    // owner, and a rejected call leaves the state as it was.
+    #[test]
+    fn owner_calls_reject_everyone_else() {
+        let mut state = deploy_waiting();
+        state.pending_owner = Some([1; 32]);
+
+        let owner_calls: Vec<(&str, EntryPoint)> = vec![
+            ("snapshot", PrisonerDilemma::snapshot),
+            ("export_state", PrisonerDilemma::export_state),
+            ("pause", PrisonerDilemma::pause),
+            ("unpause", PrisonerDilemma::unpause),
+            ("shutdown", PrisonerDilemma::shutdown),
+            ("transfer_ownership", PrisonerDilemma::transfer_ownership),
+            ("reset_player_stats", PrisonerDilemma::reset_player_stats),
+            ("import_state", PrisonerDilemma::import_state),
+            ("set_streak_bonuses", PrisonerDilemma::set_streak_bonuses),
+            ("set_coop_jackpot", PrisonerDilemma::set_coop_jackpot),
+            ("set_milestone_bonus", PrisonerDilemma::set_milestone_bonus),
+            ("set_loyalty_tiers", PrisonerDilemma::set_loyalty_tiers),
+            ("set_priority_fee", PrisonerDilemma::set_priority_fee),
+            ("set_params", PrisonerDilemma::set_params),
+            ("set_match_expiry", PrisonerDilemma::set_match_expiry),
+            ("set_matching_strategy", PrisonerDilemma::set_matching_strategy),
+            ("set_max_waiting_pool", PrisonerDilemma::set_max_waiting_pool),
+            ("set_anonymous", PrisonerDilemma::set_anonymous),
+            ("set_reputation_floor", PrisonerDilemma::set_reputation_floor),
+            ("set_defector_bonus_cap", PrisonerDilemma::set_defector_bonus_cap),
+            ("set_defection_penalty", PrisonerDilemma::set_defection_penalty),
+            ("set_jackpot", PrisonerDilemma::set_jackpot),
+            ("set_reward_schedule", PrisonerDilemma::set_reward_schedule),
+            ("set_charity", PrisonerDilemma::set_charity),
+            ("set_pot_decay", PrisonerDilemma::set_pot_decay),
+            ("set_surplus_distribution", PrisonerDilemma::set_surplus_distribution),
+            ("distribute_surplus_pot", PrisonerDilemma::distribute_surplus_pot),
+        ];
+
+        for (name, entry) in owner_calls {
+            for sender in [1, 4] {
+                let before = serde_json::to_vec(&state).unwrap();
+                let err = entry(&mut state, &mut params(&call(sender, 0, 0, 2), &[])).unwrap_err();
+
+                assert_eq!(code(err), "UNAUTHORIZED", "{} from {}", name, sender);
+                assert_eq!(serde_json::to_vec(&state).unwrap(), before, "{} from {}", name, sender);
+            }
+        }
+        assert!(logs().is_empty());
+    }
+
+    #[test]
+    fn accepting_an_unproposed_ownership_is_rejected() {
+        let mut state = deploy_joining();
+        state.pending_owner = Some([2; 32]);
+
+        assert_eq!(code(state.accept_ownership(&mut params(&call(3, 0, 0, 1), &[])).unwrap_err()), "NOT_PENDING_OWNER");
+    }
+
+    #[test]
+    fn distributing_a_pot_without_surplus_is_rejected() {
+        let mut state = deploy_joining();
+        state.surplus_threshold = 1_000;
+        state.pot = 1_000;
+
+        assert_eq!(code(state.distribute_surplus_pot(&mut params(&call(9, 0, 0, 1), &[])).unwrap_err()), "NO_SURPLUS");
+    }
+
+    #[test]
+    fn distributing_a_surplus_without_players_is_rejected() {
+        let mut state = deploy_joining();
+        state.surplus_threshold = 1_000;
+        state.pot = 2_000;
+
+        assert_eq!(code(state.distribute_surplus_pot(&mut params(&call(9, 0, 0, 1), &[])).unwrap_err()), "NO_RECENT_PLAYERS");
+    }
+
+
+    // The whole output, so that a renamed or dropped field shows up here.
+    #[test]
+    fn get_config_logs_every_field() {
+        let mut state = deploy(&[]);
+        state.pending_owner = Some([3; 32]);
+        state.stake_tiers = vec![(0, 1000), (1001, 2000)];
+
+        state.get_config(&mut params(&call(1, 1, 0, 1), &[])).unwrap();
+
+        let expected = concat!(
+            r#"{"version":""#, env!("CARGO_PKG_VERSION"), r#"","schema_version":2,"#,
+            r#""owner":"0909090909090909090909090909090909090909090909090909090909090909","#,
+            r#""pending_owner":"0303030303030303030303030303030303030303030303030303030303030303","#,
+            r#""paused":false,"shut_down":false,"threshold":50,"threshold_decay_mode":"Linear","matching_strategy":"FIFO","#,
+            r#""reward_schedule":{"low_water":100,"high_water":100000000,"min_reward":1,"max_reward":1500000,"cooperate_bps":100,"defect_bps":150},"#,
+            r#""abstain_fee_bps":100,"streak_bonuses":[[3,50],[5,100],[10,200]],"#,
+            r#""jackpot_trigger":0,"jackpot_share_bps":5000,"milestone_bonus_bps":0,"min_pot_for_rewards":0,"jackpot_pct_bps":0,"#,
+            r#""charity":null,"charity_share_bps":0,"decay_period":0,"decay_bps":0,"decay_matches":10,"#,
+            r#""referral_bps":50,"max_referral_reward":1000000,"loyalty_tiers":[[10,10],[50,25]],"#,
+            r#""min_stake":0,"history_capacity":100,"priority_fee":0,"reputation_floor":0,"#,
+            r#""defector_bonus_cap_multiplier":10,"defection_penalty_multiplier":10000,"surplus_threshold":1000000000,"surplus_matches":10,"#,
+            r#""pair_retention_rounds":10000,"multisig_expiry_rounds":100,"invariants_enabled":true,"max_waiting_pool":0,"anonymous":false,"#,
+            r#""commitment_expiry_rounds":100,"match_expiry_rounds":0,"sweep_limit":5,"stake_tiers":[[0,1000],[1001,2000]]}"#,
+        );
+        assert_eq!(logs(), vec![expected.to_string()]);
+    }
+
+    // The open matches of the sender in both queues, and nothing of anyone else.
+    #[test]
+    fn my_matches_lists_only_the_open_matches_of_the_sender() {
+        let mut state = deploy_joining();
+        let my_matches = |state: &mut PrisonerDilemma, sender: u8| {
+            state.my_matches(&mut params(&call(sender, 0, 0, 5), &[])).unwrap();
+            serde_json::from_str::<serde_json::Value>(&logs().pop().unwrap()).unwrap()
+        };
+        assert_eq!(my_matches(&mut state, 1), serde_json::json!([]));
+
+        state.play(&mut params(&call(1, 1, 1000, 1), &payload(&[&1u8, &0u8]))).unwrap();
+        state.set_random_matching(&mut params(&call(1, 2, 0, 2), &payload(&[&false]))).unwrap();
+        state.play(&mut params(&call(1, 3, 2000, 2), &payload(&[&2u8, &0u8]))).unwrap();
+        state.set_random_matching(&mut params(&call(2, 4, 0, 3), &payload(&[&false]))).unwrap();
+        state.play(&mut params(&call(2, 5, 1000, 3), &payload(&[&1u8, &0u8]))).unwrap();
+        logs();
+        let (random, challenge) = (state.waiting[0].id.clone(), state.challenge_only_waiting[0].id.clone());
+
+        assert_eq!(my_matches(&mut state, 1), serde_json::json!([
+            {"match_id": random, "stake": 1000, "created_round": 1, "kind": "random"},
+            {"match_id": challenge, "stake": 2000, "created_round": 2, "kind": "challenge"},
+        ]));
+        assert_eq!(my_matches(&mut state, 2)[0]["kind"], "challenge");
+        assert_eq!(my_matches(&mut state, 3), serde_json::json!([]));
+
+        // A match that was joined is no longer open
+        state.challenge(&mut params(&call(3, 6, 2000, 4), &payload(&[&challenge, &1u8]))).unwrap();
+        logs();
+        assert_eq!(my_matches(&mut state, 1).as_array().unwrap().len(), 1);
+    }
+
+    // Plays as many matches between new players, one per round.
+    fn play_matches(state: &mut PrisonerDilemma, count: u8) {
+        for i in 0..count {
+            let (p1, p2) = (2 * i + 1, 2 * i + 2);
+            state.play(&mut params(&call(p1, p1, 1000, i as u64 + 1), &payload(&[&1u8, &0u8]))).unwrap();
+            state.play(&mut params(&call(p2, p2, 1000, i as u64 + 1), &payload(&[&2u8, &0u8]))).unwrap();
+        }
+        logs();
+    }
+
+    // Pages run newest first, hold at most 25 matches, and are empty past the end.
+    #[test]
+    fn history_pages_stop_at_the_end_and_the_page_size() {
+        let mut state = deploy_joining();
+        play_matches(&mut state, 30);
+        let newest_first: Vec<String> = state.history.iter().rev().map(|m| m.id.clone()).collect();
+
+        let mut page = |offset: u32, limit: u32| {
+            state.get_history(&mut params(&call(1, 0, 0, 40), &payload(&[&offset, &limit]))).unwrap();
+            let result = serde_json::from_str::<serde_json::Value>(&logs()[0]).unwrap();
+            assert_eq!(result["total_available"], 30);
+            result["matches"].as_array().unwrap().iter()
+                .map(|m| m["match_id"].as_str().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(page(0, 10), newest_first[..10]);
+        assert_eq!(page(25, 10), newest_first[25..]);
+        assert_eq!(page(0, 100), newest_first[..25]);
+        assert!(page(0, 0).is_empty());
+        assert!(page(30, 10).is_empty());
+        assert!(page(u32::MAX, u32::MAX).is_empty());
+    }
+
+    // At most 25 matches of the address, newest first, and none for a limit
+    // of 0 or an address that never played.
+    #[test]
+    fn history_for_stops_at_the_limit_and_the_page_size() {
+        let mut state = deploy_joining();
+        for opponent in 2..32u8 {
+            state.play(&mut params(&call(1, opponent, 1000, opponent as u64), &payload(&[&1u8, &0u8]))).unwrap();
+            state.play(&mut params(&call(opponent, opponent + 100, 1000, opponent as u64), &payload(&[&2u8, &0u8]))).unwrap();
+        }
+        logs();
+        let newest_first: Vec<String> = state.history.iter().rev().map(|m| m.id.clone()).collect();
+
+        let mut history_for = |sender: u8, address: String, limit: u32| {
+            state.history_for(&mut params(&call(sender, 0, 0, 40), &payload(&[&address, &limit]))).unwrap();
+            serde_json::from_str::<serde_json::Value>(&logs()[0]).unwrap().as_array().unwrap().clone()
+        };
+        let ids = |matches: &[serde_json::Value]| matches.iter().map(|m| m["match_id"].as_str().unwrap().to_string()).collect::<Vec<String>>();
+        let p1 = to_hex_string([1; 32]);
+
+        assert_eq!(ids(&history_for(5, p1.clone(), 3)), newest_first[..3]);
+        assert_eq!(ids(&history_for(5, p1.clone(), 100)), newest_first[..25]);
+        assert!(history_for(5, p1.clone(), 0).is_empty());
+        assert!(history_for(5, to_hex_string([99; 32]), 10).is_empty());
+
+        // The sender by default, with the opponent called out
+        let own = history_for(31, String::new(), 10);
+        assert_eq!(own.len(), 1);
+        assert_eq!((own[0]["match_id"].as_str(), own[0]["you"]["vote"].as_u64()), (Some(newest_first[0].as_str()), Some(2)));
+        assert_eq!(own[0]["opponent"]["sender"], p1);
+    }
+
+    // Filtered pages count and page through only the matches of the outcome.
+    #[test]
+    fn filtered_history_pages_only_hold_the_outcome() {
+        let mut state = deploy_joining();
+        let votes = [(1u8, 1u8), (1, 2), (2, 1), (2, 2)];
+        for i in 0..12u8 {
+            let (vote1, vote2) = votes[i as usize % 4];
+            let (p1, p2) = (2 * i + 1, 2 * i + 2);
+            state.play(&mut params(&call(p1, p1, 1000, i as u64 + 1), &payload(&[&vote1, &0u8]))).unwrap();
+            state.play(&mut params(&call(p2, p2, 1000, i as u64 + 1), &payload(&[&vote2, &0u8]))).unwrap();
+        }
+        logs();
+        let defections: Vec<String> = state.history.iter().rev().filter(|m| m.outcome() == "dd").map(|m| m.id.clone()).collect();
+        assert_eq!(defections.len(), 3);
+
+        let mut page = |filter: &str, offset: u32, limit: u32| {
+            let filter = filter.to_string();
+            state.get_history(&mut params(&call(1, 0, 0, 20), &payload(&[&offset, &limit, &filter]))).map(|_| {
+                let result = serde_json::from_str::<serde_json::Value>(&logs()[0]).unwrap();
+                let matches = result["matches"].as_array().unwrap();
+                assert!(matches.iter().all(|m| filter == "any" || m["outcome"] == filter.as_str()));
+                (result["total_available"].as_u64().unwrap(), matches.iter().map(|m| m["match_id"].as_str().unwrap().to_string()).collect::<Vec<String>>())
+            })
+        };
+
+        assert_eq!(page("dd", 0, 2).unwrap(), (3, defections[..2].to_vec()));
+        assert_eq!(page("dd", 2, 2).unwrap(), (3, defections[2..].to_vec()));
+        assert_eq!(page("dd", 3, 2).unwrap(), (3, vec![]));
+        assert_eq!(page("dd", 0, 0).unwrap(), (3, vec![]));
+        assert_eq!(page("cd", 0, 25).unwrap().1.len(), 3);
+        assert_eq!(page("any", 10, 25).unwrap().1.len(), 2);
+        assert_eq!(code(page("abstain", 0, 25).unwrap_err()), "INVALID_PARAMETER");
+    }
+
+    // Holders in address order, at most 50 a page, with empty balances skipped.
+    #[test]
+    fn balance_pages_stop_at_the_end_and_the_page_size() {
+        let mut state = deploy_joining();
+        for sender in (1..=60u8).rev() {
+            state.balances.insert([sender; 32], sender as u64 * 10);
+            state.total_deposits += sender as u64 * 10;
+        }
+        state.balances.insert([30; 32], 0);
+        state.total_deposits -= 300;
+
+        let mut page = |offset: u32, limit: u32| {
+            state.get_balances(&mut params(&call(1, 0, 0, 1), &payload(&[&offset, &limit]))).unwrap();
+            let result = serde_json::from_str::<serde_json::Value>(&logs()[0]).unwrap();
+            assert_eq!(result["total_holders"], 59);
+            result["balances"].as_array().unwrap().iter()
+                .map(|b| (b["sender"].as_str().unwrap().to_string(), b["balance"].as_u64().unwrap()))
+                .collect::<Vec<(String, u64)>>()
+        };
+        let holders: Vec<(String, u64)> = (1..=60u8).filter(|sender| *sender != 30)
+            .map(|sender| (to_hex_string([sender; 32]), sender as u64 * 10))
+            .collect();
+
+        assert_eq!(page(0, 10), holders[..10]);
+        assert_eq!(page(50, 50), holders[50..]);
+        assert_eq!(page(0, 100), holders[..50]);
+        assert!(page(0, 0).is_empty());
+        assert!(page(59, 10).is_empty());
+        assert!(page(u32::MAX, u32::MAX).is_empty());
+    }
+}